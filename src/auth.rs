@@ -0,0 +1,76 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+use crate::secret::{constant_time_eq, Secret};
+
+const BASIC_PREFIX: &str = "Basic ";
+
+/// Credentials that clients must present via HTTP Basic auth.
+#[derive(Debug, Clone)]
+pub struct BasicAuth {
+    pub username: String,
+    pub password: Secret,
+}
+
+impl BasicAuth {
+    /// Checks the value of a request's `Authorization` header against the
+    /// configured credentials. A missing or malformed header never verifies.
+    pub fn verify(&self, header: Option<&str>) -> bool {
+        let encoded = match header.and_then(|h| h.strip_prefix(BASIC_PREFIX)) {
+            Some(encoded) => encoded.trim(),
+            None => return false,
+        };
+
+        let decoded = match STANDARD.decode(encoded) {
+            Ok(decoded) => decoded,
+            Err(_) => return false,
+        };
+
+        let (username, password) = match decoded.iter().position(|&b| b == b':') {
+            Some(idx) => (&decoded[..idx], &decoded[idx + 1..]),
+            None => return false,
+        };
+
+        // check both halves regardless of the first result so that a wrong
+        // username takes as long to reject as a wrong password
+        let username_ok = constant_time_eq(username, self.username.as_bytes());
+        let password_ok = self.password.matches(password);
+
+        username_ok & password_ok
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials() -> BasicAuth {
+        BasicAuth {
+            username: String::from("admin"),
+            password: Secret::new("secret"),
+        }
+    }
+
+    fn header(userpass: &str) -> String {
+        format!("{}{}", BASIC_PREFIX, STANDARD.encode(userpass))
+    }
+
+    #[test]
+    fn only_the_right_credentials_verify() {
+        let auth = credentials();
+
+        assert!(auth.verify(Some(&header("admin:secret"))));
+        assert!(!auth.verify(Some(&header("admin:wrong"))));
+        assert!(!auth.verify(Some(&header("someone:secret"))));
+        assert!(!auth.verify(None));
+    }
+
+    #[test]
+    fn a_malformed_header_never_verifies() {
+        let auth = credentials();
+
+        assert!(!auth.verify(Some(&header("adminsecret"))));
+        assert!(!auth.verify(Some("Basic not base64!")));
+        assert!(!auth.verify(Some("Bearer YWRtaW46c2VjcmV0")));
+    }
+}
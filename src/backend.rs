@@ -0,0 +1,186 @@
+//! Where the store is persisted. The server only ever loads the store once at
+//! startup and saves whole snapshots of it after that, so a backend is free
+//! to keep them in whatever shape suits it.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+
+use log::{error, info, warn};
+
+use crate::error::ServerError;
+use crate::{snapshot, Record, Storage, PERSIST};
+
+/// Something the store can be loaded from and saved to.
+pub trait PersistenceBackend: Send + Sync {
+    /// Reads back the most recently saved records. A backend that has never
+    /// been saved to holds an empty store.
+    fn load(&self) -> Result<HashMap<String, Record>, ServerError>;
+
+    /// Replaces whatever was saved before with `data`.
+    fn save(&self, data: &HashMap<String, Record>) -> Result<(), ServerError>;
+}
+
+/// Keeps the store as a single JSON object in a file.
+#[derive(Debug, Clone)]
+pub struct JsonFileBackend {
+    /// The persistence file.
+    pub path: PathBuf,
+    /// Mirror the file into this directory after every save, if set.
+    pub backup_dir: Option<PathBuf>,
+    /// Refuse to save an empty store over a file that still has data.
+    pub no_flush_if_empty: bool,
+    /// How many threads the store is serialized on.
+    pub threads: usize,
+}
+
+impl JsonFileBackend {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        JsonFileBackend {
+            path: path.into(),
+            backup_dir: None,
+            no_flush_if_empty: false,
+            threads: 1,
+        }
+    }
+}
+
+impl PersistenceBackend for JsonFileBackend {
+    fn load(&self) -> Result<HashMap<String, Record>, ServerError> {
+        // on a first run there's nothing persisted yet, which is an empty store
+        match fs::read_to_string(&self.path) {
+            Ok(persisted) => Ok(Storage::load(&persisted)?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn save(&self, data: &HashMap<String, Record>) -> Result<(), ServerError> {
+        let path = self.path.as_path();
+
+        if self.no_flush_if_empty && data.is_empty() && has_data(path) {
+            warn!("Refusing to overwrite a non-empty persistence file with an empty store");
+            return Ok(());
+        }
+
+        let json = snapshot::serialize(data, self.threads)?;
+
+        // written out to the side and renamed over the file in one step, so
+        // a crash mid-write leaves the previous snapshot intact
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut file = File::create(&tmp)?;
+
+        file.write_all(json.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+
+        info!("Successfully flushed data to disk");
+
+        if let Some(dir) = &self.backup_dir {
+            // a failed backup shouldn't take the primary flush down with it
+            match backup(path, dir) {
+                Ok(()) => info!("Backed up data to {}", dir.display()),
+                Err(err) => error!("Failed to back up data to {}: {}", dir.display(), err),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether the snapshot at `path` holds at least one key. A file that can't
+/// be read or parsed counts as holding data, so the guard errs on the side of
+/// keeping it.
+fn has_data(path: &Path) -> bool {
+    match fs::read_to_string(path) {
+        Ok(persisted) => Storage::load(&persisted).map_or(true, |data| !data.is_empty()),
+        Err(err) => err.kind() != io::ErrorKind::NotFound,
+    }
+}
+
+/// Copies the snapshot at `path` into `dir`, going through a temporary file
+/// so that the backup is never observed half-written.
+fn backup(path: &Path, dir: &Path) -> io::Result<()> {
+    let file_name = path.file_name().unwrap_or_else(|| PERSIST.as_ref());
+    let target = dir.join(file_name);
+    let tmp = target.with_extension("json.tmp");
+
+    fs::copy(path, &tmp)?;
+    fs::rename(&tmp, &target)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::tests::scratch_dir;
+
+    fn one_key() -> HashMap<String, Record> {
+        HashMap::from([(String::from("a"), Record::new(Value::from(1)))])
+    }
+
+    #[test]
+    fn a_save_is_mirrored_into_the_backup_dir() {
+        let dir = scratch_dir("a_save_is_mirrored_into_the_backup_dir");
+        let backups = dir.join("backups");
+        fs::create_dir(&backups).unwrap();
+
+        let mut backend = JsonFileBackend::new(dir.join("persist.json"));
+        backend.backup_dir = Some(backups.clone());
+        backend.save(&one_key()).unwrap();
+
+        let saved = fs::read_to_string(dir.join("persist.json")).unwrap();
+        assert_eq!(fs::read_to_string(backups.join("persist.json")).unwrap(), saved);
+        assert!(!dir.join("persist.json.tmp").exists());
+        assert!(!backups.join("persist.json.tmp").exists());
+    }
+
+    #[test]
+    fn a_failed_backup_still_saves() {
+        let dir = scratch_dir("a_failed_backup_still_saves");
+
+        let mut backend = JsonFileBackend::new(dir.join("persist.json"));
+        backend.backup_dir = Some(dir.join("missing"));
+
+        assert!(backend.save(&one_key()).is_ok());
+        assert!(backend.load().unwrap().contains_key("a"));
+    }
+
+    #[test]
+    fn an_empty_store_only_clobbers_the_file_when_allowed() {
+        let dir = scratch_dir("an_empty_store_only_clobbers_the_file_when_allowed");
+
+        let mut backend = JsonFileBackend::new(dir.join("persist.json"));
+        backend.no_flush_if_empty = true;
+        backend.save(&one_key()).unwrap();
+
+        // with the guard on, clearing the store leaves the file as it was
+        backend.save(&HashMap::new()).unwrap();
+        assert!(backend.load().unwrap().contains_key("a"));
+
+        backend.no_flush_if_empty = false;
+        backend.save(&HashMap::new()).unwrap();
+        assert!(backend.load().unwrap().is_empty());
+    }
+
+    #[test]
+    fn only_a_snapshot_known_to_be_empty_may_be_clobbered() {
+        let dir = scratch_dir("only_a_snapshot_known_to_be_empty_may_be_clobbered");
+        let path = dir.join(PERSIST);
+
+        assert!(!has_data(&path));
+
+        fs::write(&path, "{}").unwrap();
+        assert!(!has_data(&path));
+
+        fs::write(&path, r#"{"a": "1"}"#).unwrap();
+        assert!(has_data(&path));
+
+        // a file that can't be read as a snapshot might still hold something
+        fs::write(&path, "not json").unwrap();
+        assert!(has_data(&path));
+    }
+}
@@ -0,0 +1,92 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{Record, Storage};
+
+/// The most operations a single benchmark may run, to keep it from running
+/// for an unbounded amount of time.
+pub const MAX_OPS: usize = 1_000_000;
+/// The most distinct keys a single benchmark may touch.
+pub const MAX_KEYS: usize = 100_000;
+
+/// The results of one benchmark run.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub ops: usize,
+    pub keys: usize,
+    pub elapsed_ms: f64,
+    pub ops_per_sec: f64,
+    pub latency_us: Percentiles,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Percentiles {
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+/// Runs `ops` operations against `keys` temporary keys in `storage`,
+/// alternating sets and gets, and removes every temporary key again before
+/// returning. The keys live in a `__benchmark` namespace under a per-run
+/// nonce so that they can't clobber real data.
+pub(crate) fn run(storage: &mut Storage, ops: usize, keys: usize) -> Report {
+    let nonce = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos())
+        .unwrap_or(0);
+    let keys: Vec<String> = (0..keys)
+        .map(|i| format!("__benchmark:{:x}:{}", nonce, i))
+        .collect();
+
+    let mut latencies = Vec::with_capacity(ops);
+    let started = Instant::now();
+
+    for i in 0..ops {
+        let key = &keys[i % keys.len()];
+        let op_started = Instant::now();
+
+        if i % 2 == 0 {
+            let value = storage.intern(Value::from(i));
+            storage.insert(key.clone(), Record::new(value));
+        } else {
+            std::hint::black_box(storage.data.get(key));
+        }
+
+        latencies.push(op_started.elapsed());
+    }
+
+    let elapsed = started.elapsed();
+
+    for key in &keys {
+        storage.remove(key);
+    }
+
+    latencies.sort_unstable();
+
+    Report {
+        ops,
+        keys: keys.len(),
+        elapsed_ms: elapsed.as_secs_f64() * 1_000.0,
+        ops_per_sec: ops as f64 / elapsed.as_secs_f64().max(f64::MIN_POSITIVE),
+        latency_us: Percentiles {
+            p50: percentile(&latencies, 0.50),
+            p90: percentile(&latencies, 0.90),
+            p99: percentile(&latencies, 0.99),
+            max: percentile(&latencies, 1.0),
+        },
+    }
+}
+
+/// Picks the `p`th percentile from sorted latencies, in microseconds.
+fn percentile(sorted: &[Duration], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+
+    let idx = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[idx].as_secs_f64() * 1_000_000.0
+}
@@ -1,9 +1,13 @@
 use std::process;
 
-use db_server::server_init;
+use db_server::{server_init, ServerConfig};
 
 fn main() {
-    if let Err(err) = server_init() {
+    // everything from info up unless RUST_LOG says otherwise; per-request
+    // lines are logged at debug
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    if let Err(err) = server_init(ServerConfig::from_env()) {
         eprintln!("Error: {:?}", err);
         process::exit(1);
     }
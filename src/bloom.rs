@@ -0,0 +1,79 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Bits per expected key, which with `HASHES` hashes gives roughly a 1%
+/// false positive rate at capacity.
+const BITS_PER_KEY: usize = 10;
+const HASHES: u64 = 7;
+
+/// A bloom filter over the keyspace, answering "definitely absent" for most
+/// keys that were never stored without touching the store itself. Bits are
+/// set atomically, so it can be read without holding the store's lock.
+///
+/// Keys can't be taken back out, so removed keys and growth past the
+/// expected count only raise the false positive rate; a key that was added
+/// is never reported absent.
+pub struct BloomFilter {
+    bits: Vec<AtomicU64>,
+}
+
+impl BloomFilter {
+    pub fn new(expected_keys: usize) -> Self {
+        let words = (expected_keys.max(1) * BITS_PER_KEY).div_ceil(64);
+
+        BloomFilter {
+            bits: (0..words).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    pub fn insert(&self, key: &str) {
+        for bit in self.bit_indices(key) {
+            self.bits[bit / 64].fetch_or(1 << (bit % 64), Ordering::Release);
+        }
+    }
+
+    /// Whether `key` might have been added. `false` means it never was.
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.bit_indices(key)
+            .all(|bit| self.bits[bit / 64].load(Ordering::Acquire) & (1 << (bit % 64)) != 0)
+    }
+
+    /// The bits a key maps to, derived from two hashes by double hashing.
+    fn bit_indices(&self, key: &str) -> impl Iterator<Item = usize> {
+        let len = (self.bits.len() * 64) as u64;
+        let first = hash(key, 0);
+        // an odd step can't get stuck cycling through a few bits
+        let step = hash(key, 1) | 1;
+
+        (0..HASHES).map(move |i| (first.wrapping_add(i.wrapping_mul(step)) % len) as usize)
+    }
+}
+
+fn hash(key: &str, seed: u64) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    seed.hash(&mut hasher);
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn added_keys_are_never_missed_and_most_others_are() {
+        let filter = BloomFilter::new(1_000);
+
+        for i in 0..1_000 {
+            filter.insert(&format!("key{}", i));
+        }
+
+        assert!((0..1_000).all(|i| filter.may_contain(&format!("key{}", i))));
+
+        // about 1% at capacity; well under 5% leaves room for chance
+        let false_positives =
+            (0..10_000).filter(|i| filter.may_contain(&format!("other{}", i))).count();
+        assert!(false_positives < 500, "{} false positives", false_positives);
+    }
+}
@@ -0,0 +1,160 @@
+use std::io::{self, BufRead, Write};
+use std::sync::Weak;
+
+use log::error;
+
+use crate::{flush_shared, lock, now, typed_value, Record, Server};
+
+const HELP: &str = "commands: keys | get <key> | set <key> <value> | flush | stats | help";
+
+/// Reads admin commands from stdin, one per line, until stdin closes or the
+/// server shuts down. The server is held weakly so that this thread, which
+/// spends its life blocked on stdin, never keeps the store from flushing.
+pub fn run(server: Weak<Server>) {
+    println!("Admin commands enabled on stdin; {}", HELP);
+
+    serve(server, io::stdin().lock(), io::stdout());
+}
+
+/// Runs each command read from `input`, writing what it prints to `output`.
+fn serve(server: Weak<Server>, input: impl BufRead, mut output: impl Write) {
+    for line in input.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) => {
+                error!("Failed to read admin command: {}", err);
+                break;
+            }
+        };
+
+        let server = match server.upgrade() {
+            Some(server) => server,
+            None => break,
+        };
+
+        if let Err(err) = execute(&server, line.trim(), &mut output) {
+            error!("Failed to answer admin command: {}", err);
+            break;
+        }
+    }
+}
+
+fn execute(server: &Server, line: &str, output: &mut impl Write) -> io::Result<()> {
+    let (command, args) = line.split_once(' ').unwrap_or((line, ""));
+    let args = args.trim();
+
+    match (command, args) {
+        ("", _) => {}
+        ("keys", "") => {
+            let storage = lock(&server.storage);
+            let now = now();
+            let mut keys: Vec<&String> = storage
+                .data
+                .iter()
+                .filter(|(_, record)| !record.is_expired(now))
+                .map(|(key, _)| key)
+                .collect();
+
+            keys.sort_unstable();
+
+            for key in &keys {
+                writeln!(output, "{}", key)?;
+            }
+
+            writeln!(output, "({} keys)", keys.len())?;
+        }
+        ("get", key) if !key.is_empty() && !key.contains(' ') => {
+            let storage = lock(&server.storage);
+
+            match storage.data.get(key).filter(|record| !record.is_expired(now())) {
+                Some(record) => writeln!(output, "{}", record.value)?,
+                None => writeln!(output, "(not found)")?,
+            }
+        }
+        ("set", args) => match args.split_once(' ') {
+            // the value is the rest of the line, spaces and all
+            Some((key, val)) if !key.is_empty() => {
+                let mut storage = lock(&server.storage);
+                let val = storage.intern(typed_value(val));
+                let record = Record::with_ttl(val, server.config.ttl_for(key));
+
+                storage.insert(String::from(key), record);
+                writeln!(output, "OK")?;
+            }
+            _ => writeln!(output, "usage: set <key> <value>")?,
+        },
+        ("flush", "") => {
+            if let Err(err) = flush_shared(&server.storage) {
+                writeln!(output, "flush failed: {}", err)?;
+            }
+        }
+        ("stats", "") => {
+            let now = now();
+            let keys = lock(&server.storage)
+                .data
+                .values()
+                .filter(|record| !record.is_expired(now))
+                .count();
+            let metrics = server.metrics.snapshot();
+
+            writeln!(output, "keys: {}", keys)?;
+            writeln!(output, "{:?}", metrics)?;
+        }
+        ("help", "") => writeln!(output, "{}", HELP)?,
+        _ => writeln!(output, "unknown command {:?}; {}", line, HELP)?,
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::tests::{expire, server};
+    use crate::ServerConfig;
+
+    #[test]
+    fn commands_from_a_pipe_answer_in_turn() {
+        let admin = Arc::new(server(ServerConfig::default()));
+        let input = Cursor::new("set b hello there\nset a 1\nget b\nget c\nkeys\n\nset a\nbogus\n");
+        let mut output = Vec::new();
+
+        serve(Arc::downgrade(&admin), input, &mut output);
+
+        let expected = [
+            "OK",
+            "OK",
+            "\"hello there\"",
+            "(not found)",
+            "a",
+            "b",
+            "(2 keys)",
+            "usage: set <key> <value>",
+            &format!("unknown command \"bogus\"; {}", HELP),
+        ];
+        assert_eq!(String::from_utf8(output).unwrap().lines().collect::<Vec<_>>(), expected);
+
+        let output = {
+            let mut output = Vec::new();
+            serve(Arc::downgrade(&admin), Cursor::new("stats\n"), &mut output);
+            String::from_utf8(output).unwrap()
+        };
+        assert!(output.starts_with("keys: 2\n"), "{}", output);
+    }
+
+    #[test]
+    fn the_stats_command_leaves_out_expired_keys() {
+        let admin = Arc::new(server(ServerConfig::default()));
+        serve(Arc::downgrade(&admin), Cursor::new("set a 1\nset b 2\n"), io::sink());
+        expire(&admin, "b");
+
+        let mut output = Vec::new();
+        serve(Arc::downgrade(&admin), Cursor::new("stats\n"), &mut output);
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("keys: 1\n"), "{}", output);
+    }
+}
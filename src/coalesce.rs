@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Increments held back from the store, so that a burst of them on one
+/// counter takes the store's lock once per window rather than once each.
+pub struct Coalescer {
+    window: Duration,
+    pending: Mutex<HashMap<String, Pending>>,
+}
+
+/// A counter with increments held back for it.
+struct Pending {
+    /// The value the counter has with them applied, which is what they're
+    /// answered with.
+    value: f64,
+    /// What they add up to, which is what gets written to the store.
+    delta: f64,
+    /// When the counter stops being served, in seconds since the UNIX epoch.
+    expires: Option<u64>,
+    /// When the store last had the counter's value.
+    since: Instant,
+}
+
+impl Coalescer {
+    pub fn new(window: Duration) -> Self {
+        Coalescer {
+            window,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Adds `by` to a counter held here, giving its new value, or `None` if
+    /// the increment has to go to the store instead: the counter isn't held,
+    /// its window is up, it has expired, or the result wouldn't be finite.
+    pub fn incr(&self, key: &str, by: f64, now: u64) -> Option<f64> {
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        let held = pending.get_mut(key)?;
+
+        if held.since.elapsed() >= self.window || held.expires.is_some_and(|at| at <= now) {
+            return None;
+        }
+
+        let value = held.value + by;
+        if !value.is_finite() {
+            return None;
+        }
+
+        held.value = value;
+        held.delta += by;
+
+        Some(value)
+    }
+
+    /// Starts holding increments to `key`, which the store has just left at
+    /// `value`.
+    pub fn hold(&self, key: String, value: f64, expires: Option<u64>) {
+        let held = Pending {
+            value,
+            delta: 0.0,
+            expires,
+            since: Instant::now(),
+        };
+
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        pending.insert(key, held);
+    }
+
+    /// Takes what the increments held for each counter add up to, leaving
+    /// none held, so they can be written to the store.
+    pub fn drain(&self) -> Vec<(String, f64)> {
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+
+        pending
+            .drain()
+            .filter(|(_, held)| held.delta != 0.0)
+            .map(|(key, held)| (key, held.delta))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn increments_are_held_until_the_window_is_up() {
+        let coalescer = Coalescer::new(Duration::from_millis(100));
+        assert_eq!(coalescer.incr("hits", 1.0, 0), None);
+
+        coalescer.hold(String::from("hits"), 1.0, None);
+        assert_eq!(coalescer.incr("hits", 1.0, 0), Some(2.0));
+        assert_eq!(coalescer.incr("hits", 2.0, 0), Some(4.0));
+
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(coalescer.incr("hits", 1.0, 0), None);
+        assert_eq!(coalescer.drain(), vec![(String::from("hits"), 3.0)]);
+        assert!(coalescer.drain().is_empty());
+    }
+}
@@ -0,0 +1,581 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::auth::BasicAuth;
+use crate::encoding::ValueFormat;
+use crate::error::ServerError;
+use crate::secret::Secret;
+use crate::upstream::{Upstream, WriteThrough};
+
+const ADDRESS_VAR: &str = "DB_ADDRESS";
+const PERSIST_VAR: &str = "DB_PERSIST";
+const BACKUP_DIR_VAR: &str = "DB_BACKUP_DIR";
+const SLOW_START_VAR: &str = "DB_SLOW_START_SECS";
+const COALESCE_WINDOW_VAR: &str = "DB_COALESCE_WINDOW_MS";
+const MAX_RESPONSE_BYTES_VAR: &str = "DB_MAX_RESPONSE_BYTES";
+const BASIC_AUTH_USER_VAR: &str = "DB_BASIC_AUTH_USER";
+const BASIC_AUTH_PASSWORD_VAR: &str = "DB_BASIC_AUTH_PASSWORD";
+const DEFAULT_TTL_VAR: &str = "DB_DEFAULT_TTL_SECS";
+const NAMESPACE_TTLS_VAR: &str = "DB_NAMESPACE_TTLS";
+const UPSTREAM_VAR: &str = "DB_UPSTREAM_URL";
+const UPSTREAM_BAD_GATEWAY_VAR: &str = "DB_UPSTREAM_BAD_GATEWAY";
+const WRITE_THROUGH_VAR: &str = "DB_WRITE_THROUGH";
+const SNAPSHOT_DIR_VAR: &str = "DB_SNAPSHOT_DIR";
+const NO_FLUSH_IF_EMPTY_VAR: &str = "DB_NO_FLUSH_IF_EMPTY";
+const BENCHMARK_VAR: &str = "DB_BENCHMARK_ENABLED";
+const TRACE_VAR: &str = "DB_TRACE_ENABLED";
+const CONTENT_HASH_VAR: &str = "DB_CONTENT_HASH";
+const ERROR_VERBOSITY_VAR: &str = "DB_ERROR_VERBOSITY";
+const ENABLED_OPERATIONS_VAR: &str = "DB_ENABLED_OPERATIONS";
+const DISABLED_OPERATIONS_VAR: &str = "DB_DISABLED_OPERATIONS";
+const UNIX_SOCKET_VAR: &str = "DB_UNIX_SOCKET";
+const NO_TCP_VAR: &str = "DB_NO_TCP";
+const DEDUP_VALUES_VAR: &str = "DB_DEDUP_VALUES";
+const LISTEN_FD_VAR: &str = "DB_LISTEN_FD";
+const MAX_KEY_BYTES_VAR: &str = "DB_MAX_KEY_BYTES";
+const CONFLICT_RETURNS_VALUE_VAR: &str = "DB_CONFLICT_RETURNS_VALUE";
+const VALUE_FORMAT_VAR: &str = "DB_VALUE_FORMAT";
+const ADMIN_CLI_VAR: &str = "DB_ADMIN_CLI";
+const WRITE_BUFFER_BYTES_VAR: &str = "DB_WRITE_BUFFER_BYTES";
+const BLOOM_FILTER_KEYS_VAR: &str = "DB_BLOOM_FILTER_KEYS";
+const THREADS_VAR: &str = "DB_THREADS";
+const SNAPSHOT_THREADS_VAR: &str = "DB_SNAPSHOT_THREADS";
+const PERSIST_INTERVAL_VAR: &str = "DB_PERSIST_INTERVAL_SECS";
+const MAX_VALUE_BYTES_VAR: &str = "DB_MAX_VALUE_BYTES";
+const OVERSIZED_VALUES_VAR: &str = "DB_OVERSIZED_VALUES";
+const REQUEST_MEMORY_VAR: &str = "DB_REQUEST_MEMORY_BYTES";
+const DUPLICATE_PARAMS_VAR: &str = "DB_DUPLICATE_PARAMS";
+const KEEPALIVE_MAX_REQUESTS_VAR: &str = "DB_KEEPALIVE_MAX_REQUESTS";
+const WAL_VAR: &str = "DB_WAL";
+const WAL_FSYNC_VAR: &str = "DB_WAL_FSYNC";
+const LOG_REJECTED_VAR: &str = "DB_LOG_REJECTED";
+const MAX_ENTRIES_VAR: &str = "DB_MAX_ENTRIES";
+const STORE_LIMIT_KEYS_VAR: &str = "DB_STORE_LIMIT_KEYS";
+const STORE_LIMIT_BYTES_VAR: &str = "DB_STORE_LIMIT_BYTES";
+const SLOW_REQUEST_MS_VAR: &str = "DB_SLOW_REQUEST_MS";
+const SLOW_REQUEST_LOG_VAR: &str = "DB_SLOW_REQUEST_LOG";
+const SYSTEMD_LISTEN_FDS_VAR: &str = "LISTEN_FDS";
+const SYSTEMD_LISTEN_PID_VAR: &str = "LISTEN_PID";
+/// The first descriptor systemd passes with socket activation.
+const SYSTEMD_LISTEN_FDS_START: RawFd = 3;
+
+/// Settings that control how the server runs.
+#[derive(Debug, Clone, Default)]
+pub struct ServerConfig {
+    /// The address to listen for TCP connections on, if not the default
+    /// `127.0.0.1:4000`.
+    pub address: Option<String>,
+    /// The file the store is loaded from and flushed to, if not the default
+    /// `persist.json` in the working directory.
+    pub persist_path: Option<PathBuf>,
+    /// A secondary directory that every successful snapshot is mirrored into.
+    pub backup_dir: Option<PathBuf>,
+    /// How long to ramp up the connection accept rate for after startup, if
+    /// at all.
+    pub slow_start: Option<Duration>,
+    /// How long increments to a counter are held back for before they're
+    /// written to the store together, if at all.
+    pub coalesce_window: Option<Duration>,
+    /// The largest response body the server will send; anything bigger is
+    /// replaced with a 413.
+    pub max_response_bytes: Option<usize>,
+    /// Credentials every request must carry, if set.
+    pub basic_auth: Option<BasicAuth>,
+    /// How long keys live when they're set without an explicit expiry.
+    pub default_ttl_secs: Option<u64>,
+    /// Settings for individual namespaces, keyed by namespace name.
+    pub namespaces: HashMap<String, NamespaceConfig>,
+    /// A backend to read missing keys through from.
+    pub upstream: Option<Upstream>,
+    /// Answer with a 502 rather than a 404 when the upstream fails.
+    pub upstream_bad_gateway: bool,
+    /// Forward every set to the upstream as well, if set.
+    pub write_through: Option<WriteThrough>,
+    /// The only directory that admin endpoints may read snapshot files from.
+    pub snapshot_dir: Option<PathBuf>,
+    /// Skip the shutdown flush when the store is empty but the persistence
+    /// file isn't, rather than clobbering the file.
+    pub no_flush_if_empty: bool,
+    /// Allow the `/benchmark` self-test to run.
+    pub benchmark_enabled: bool,
+    /// Allow `/trace` to echo requests back, headers and all. It's off by
+    /// default since the echo includes whatever credentials came with it.
+    pub trace_enabled: bool,
+    /// Send an `X-Content-Hash` header with the SHA-256 of every get body.
+    pub content_hash: bool,
+    /// How much of an error's underlying cause is sent back to clients. The
+    /// server's own logs always get all of it.
+    pub error_verbosity: ErrorVerbosity,
+    /// The only operations the server will run, if set; anything else is
+    /// refused with a 403.
+    pub enabled_operations: Option<HashSet<String>>,
+    /// Operations the server refuses with a 403, even if they're enabled.
+    pub disabled_operations: HashSet<String>,
+    /// A unix socket to serve requests on, if set, alongside TCP.
+    pub unix_socket: Option<PathBuf>,
+    /// Serve only the unix socket, without listening on TCP at all.
+    pub no_tcp: bool,
+    /// Store one shared copy of each distinct value set, however many keys
+    /// hold it, at the cost of hashing every value on set.
+    pub dedup_values: bool,
+    /// An already-bound TCP listener to accept on instead of binding afresh,
+    /// so a new instance can take over the port without dropping it.
+    pub listen_fd: Option<RawFd>,
+    /// The longest key, in bytes, any request may name; longer ones are
+    /// refused with a 400.
+    pub max_key_bytes: Option<usize>,
+    /// Answer a failed conditional write with the current value as JSON,
+    /// so the client can retry against it without another read.
+    pub conflict_returns_value: bool,
+    /// How fetched values are laid out when they're sent as JSON text.
+    pub value_format: ValueFormat,
+    /// Read admin commands such as `keys` and `flush` from stdin.
+    pub admin_cli: bool,
+    /// Buffer each response in memory up to this many bytes before writing
+    /// it to the stream, rather than writing every piece as it's ready.
+    pub write_buffer_bytes: Option<usize>,
+    /// Keep a bloom filter sized for this many keys, so that gets for keys
+    /// that were never stored are answered without taking the store's lock.
+    pub bloom_filter_keys: Option<usize>,
+    /// How many connections are served at once, each on its own worker
+    /// thread.
+    pub threads: Option<usize>,
+    /// How many threads the persistence snapshot is serialized on; one
+    /// unless set.
+    pub snapshot_threads: Option<usize>,
+    /// How often the store is flushed in the background, on top of the
+    /// flush at shutdown; every 30 seconds unless set.
+    pub persist_interval_secs: Option<u64>,
+    /// The largest value, in bytes, a set may store; 1 MiB unless set.
+    pub max_value_bytes: Option<usize>,
+    /// What a set does with a value over `max_value_bytes`.
+    pub oversized_values: OversizedValues,
+    /// Roughly how much memory a scan or listing may gather its results
+    /// into before it's aborted with a 507.
+    pub request_memory_bytes: Option<usize>,
+    /// What to do with a query parameter that's given more than once. The
+    /// default is to use the last one.
+    pub duplicate_params: DuplicateParams,
+    /// Keep connections open for more requests, closing each one once it's
+    /// served this many so clients reconnect and get rebalanced. Without it
+    /// every connection is closed after its first request.
+    pub keepalive_max_requests: Option<usize>,
+    /// Append every change to a write-ahead log at this path, such as
+    /// `wal.log`, and replay it over the snapshot at startup.
+    pub wal_path: Option<PathBuf>,
+    /// Sync the write-ahead log to disk after every change, rather than
+    /// leaving it to the OS.
+    pub wal_fsync: bool,
+    /// Log every rejected request as a warning naming the client's address
+    /// and why it was turned away, for security monitoring to pick up.
+    pub log_rejected: bool,
+    /// Run as a bounded cache holding at most this many keys, evicting the
+    /// least recently used to make room for new ones.
+    pub max_entries: Option<usize>,
+    /// Refuse writes that would take the store past this many keys.
+    pub store_limit_keys: Option<usize>,
+    /// Refuse writes that would take the store's keys and values past
+    /// roughly this many bytes in total.
+    pub store_limit_bytes: Option<usize>,
+    /// How many milliseconds handling a request may take before it's logged
+    /// as slow and kept for `/trace-slow`; 100 unless set.
+    pub slow_request_ms: Option<u64>,
+    /// How many of the most recent slow requests `/trace-slow` keeps; 100
+    /// unless set.
+    pub slow_request_log: Option<usize>,
+    /// Environment variables that were set but couldn't be parsed, kept so
+    /// the startup self-check can report them with everything else.
+    rejected_vars: Vec<String>,
+}
+
+/// How much detail error responses carry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorVerbosity {
+    /// Only the error code, so nothing internal leaks.
+    #[default]
+    Terse,
+    /// The error code along with the underlying cause.
+    Detailed,
+}
+
+impl FromStr for ErrorVerbosity {
+    type Err = ServerError;
+
+    fn from_str(verbosity: &str) -> Result<Self, Self::Err> {
+        match verbosity {
+            "terse" => Ok(ErrorVerbosity::Terse),
+            "detailed" => Ok(ErrorVerbosity::Detailed),
+            _ => Err(ServerError::InvalidConfig {
+                problems: vec![format!("unknown error verbosity: {}", verbosity)],
+            }),
+        }
+    }
+}
+
+/// Which of a repeated query parameter's values a request goes by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicateParams {
+    First,
+    #[default]
+    Last,
+    /// Refuse the request with a 400.
+    Reject,
+}
+
+impl FromStr for DuplicateParams {
+    type Err = ServerError;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "first" => Ok(DuplicateParams::First),
+            "last" => Ok(DuplicateParams::Last),
+            "reject" => Ok(DuplicateParams::Reject),
+            _ => Err(ServerError::InvalidConfig {
+                problems: vec![format!("unknown duplicate parameter policy: {}", policy)],
+            }),
+        }
+    }
+}
+
+/// What happens to a value that's too big to store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OversizedValues {
+    /// Refuse the set with a 413.
+    #[default]
+    Reject,
+    /// Store as much of the value as fits, and say so in a `Warning` header.
+    Truncate,
+}
+
+impl FromStr for OversizedValues {
+    type Err = ServerError;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "reject" => Ok(OversizedValues::Reject),
+            "truncate" => Ok(OversizedValues::Truncate),
+            _ => Err(ServerError::InvalidConfig {
+                problems: vec![format!("unknown oversized value policy: {}", policy)],
+            }),
+        }
+    }
+}
+
+/// Settings that apply only to keys in one namespace.
+#[derive(Debug, Clone, Default)]
+pub struct NamespaceConfig {
+    /// Overrides the server-wide `default_ttl_secs` for this namespace.
+    pub default_ttl_secs: Option<u64>,
+}
+
+impl ServerConfig {
+    /// Builds a config from the `DB_*` environment variables, leaving
+    /// anything that isn't set at its default.
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        let rejected = &mut config.rejected_vars;
+
+        config.address = env::var(ADDRESS_VAR).ok();
+        config.persist_path = env::var_os(PERSIST_VAR).map(PathBuf::from);
+
+        if let Some(dir) = env::var_os(BACKUP_DIR_VAR) {
+            config.backup_dir = Some(PathBuf::from(dir));
+        }
+
+        config.slow_start = parse_var(SLOW_START_VAR, rejected).map(Duration::from_secs);
+        config.coalesce_window =
+            parse_var(COALESCE_WINDOW_VAR, rejected).map(Duration::from_millis);
+        config.max_response_bytes = parse_var(MAX_RESPONSE_BYTES_VAR, rejected);
+
+        if let (Ok(username), Ok(password)) =
+            (env::var(BASIC_AUTH_USER_VAR), env::var(BASIC_AUTH_PASSWORD_VAR))
+        {
+            config.basic_auth = Some(BasicAuth {
+                username,
+                password: Secret::new(password),
+            });
+        }
+
+        config.default_ttl_secs = parse_var(DEFAULT_TTL_VAR, rejected);
+
+        // namespace TTLs are given as a list like `users=60,sessions=3600`
+        if let Ok(ttls) = env::var(NAMESPACE_TTLS_VAR) {
+            for ttl in ttls.split(',').filter(|ttl| !ttl.trim().is_empty()) {
+                match ttl.split_once('=').map(|(ns, secs)| (ns.trim(), secs.trim().parse())) {
+                    Some((namespace, Ok(secs))) => {
+                        config
+                            .namespaces
+                            .entry(String::from(namespace))
+                            .or_default()
+                            .default_ttl_secs = Some(secs);
+                    }
+                    _ => rejected.push(format!("{}: could not parse {:?}", NAMESPACE_TTLS_VAR, ttl)),
+                }
+            }
+        }
+
+        config.upstream = parse_var(UPSTREAM_VAR, rejected);
+        config.upstream_bad_gateway = parse_var(UPSTREAM_BAD_GATEWAY_VAR, rejected).unwrap_or(false);
+        config.write_through = parse_var(WRITE_THROUGH_VAR, rejected);
+        config.snapshot_dir = env::var_os(SNAPSHOT_DIR_VAR).map(PathBuf::from);
+        config.no_flush_if_empty = parse_var(NO_FLUSH_IF_EMPTY_VAR, rejected).unwrap_or(false);
+        config.benchmark_enabled = parse_var(BENCHMARK_VAR, rejected).unwrap_or(false);
+        config.trace_enabled = parse_var(TRACE_VAR, rejected).unwrap_or(false);
+        config.content_hash = parse_var(CONTENT_HASH_VAR, rejected).unwrap_or(false);
+        config.error_verbosity = parse_var(ERROR_VERBOSITY_VAR, rejected).unwrap_or_default();
+
+        // operations are given as a list like `getdel,flush_namespace`
+        config.enabled_operations = env::var(ENABLED_OPERATIONS_VAR).ok().map(|ops| operation_list(&ops));
+        config.disabled_operations = env::var(DISABLED_OPERATIONS_VAR)
+            .map(|ops| operation_list(&ops))
+            .unwrap_or_default();
+
+        config.unix_socket = env::var_os(UNIX_SOCKET_VAR).map(PathBuf::from);
+        config.no_tcp = parse_var(NO_TCP_VAR, rejected).unwrap_or(false);
+        config.dedup_values = parse_var(DEDUP_VALUES_VAR, rejected).unwrap_or(false);
+        config.max_key_bytes = parse_var(MAX_KEY_BYTES_VAR, rejected);
+        config.conflict_returns_value = parse_var(CONFLICT_RETURNS_VALUE_VAR, rejected).unwrap_or(false);
+        config.value_format = parse_var(VALUE_FORMAT_VAR, rejected).unwrap_or_default();
+        config.admin_cli = parse_var(ADMIN_CLI_VAR, rejected).unwrap_or(false);
+        config.write_buffer_bytes = parse_var(WRITE_BUFFER_BYTES_VAR, rejected);
+        config.bloom_filter_keys = parse_var(BLOOM_FILTER_KEYS_VAR, rejected);
+        config.threads = parse_var(THREADS_VAR, rejected);
+        config.snapshot_threads = parse_var(SNAPSHOT_THREADS_VAR, rejected);
+        config.persist_interval_secs = parse_var(PERSIST_INTERVAL_VAR, rejected);
+        config.max_value_bytes = parse_var(MAX_VALUE_BYTES_VAR, rejected);
+        config.oversized_values = parse_var(OVERSIZED_VALUES_VAR, rejected).unwrap_or_default();
+        config.request_memory_bytes = parse_var(REQUEST_MEMORY_VAR, rejected);
+        config.duplicate_params = parse_var(DUPLICATE_PARAMS_VAR, rejected).unwrap_or_default();
+        config.keepalive_max_requests = parse_var(KEEPALIVE_MAX_REQUESTS_VAR, rejected);
+        config.wal_path = env::var_os(WAL_VAR).map(PathBuf::from);
+        config.wal_fsync = parse_var(WAL_FSYNC_VAR, rejected).unwrap_or(false);
+        config.log_rejected = parse_var(LOG_REJECTED_VAR, rejected).unwrap_or(false);
+        config.max_entries = parse_var(MAX_ENTRIES_VAR, rejected);
+        config.store_limit_keys = parse_var(STORE_LIMIT_KEYS_VAR, rejected);
+        config.store_limit_bytes = parse_var(STORE_LIMIT_BYTES_VAR, rejected);
+        config.slow_request_ms = parse_var(SLOW_REQUEST_MS_VAR, rejected);
+        config.slow_request_log = parse_var(SLOW_REQUEST_LOG_VAR, rejected);
+
+        // an explicit descriptor wins over one passed by systemd socket
+        // activation. The variables saying so are cleared once they're read,
+        // so that a child process doesn't take the socket for its own
+        let pid = env::var(SYSTEMD_LISTEN_PID_VAR).ok();
+        let fds = env::var(SYSTEMD_LISTEN_FDS_VAR).ok();
+        env::remove_var(SYSTEMD_LISTEN_PID_VAR);
+        env::remove_var(SYSTEMD_LISTEN_FDS_VAR);
+
+        let activated = systemd_listen_fd(pid.as_deref(), fds.as_deref(), process::id());
+        config.listen_fd = parse_var(LISTEN_FD_VAR, rejected).or(activated);
+
+        config
+    }
+
+    /// Checks every invariant the server relies on, returning a description
+    /// of each one that doesn't hold so they can all be reported at once.
+    pub(crate) fn problems(&self) -> Vec<String> {
+        let mut problems = self.rejected_vars.clone();
+
+        for (name, dir) in [("backup_dir", &self.backup_dir), ("snapshot_dir", &self.snapshot_dir)] {
+            if let Some(dir) = dir {
+                if !dir.is_dir() {
+                    problems.push(format!("{} {} is not a directory", name, dir.display()));
+                }
+            }
+        }
+
+        if self.slow_start == Some(Duration::ZERO) {
+            problems.push(String::from("slow_start must be longer than zero"));
+        }
+
+        if self.coalesce_window == Some(Duration::ZERO) {
+            problems.push(String::from("coalesce_window must be longer than zero"));
+        }
+
+        if self.max_response_bytes == Some(0) {
+            problems.push(String::from("max_response_bytes must be greater than zero"));
+        }
+
+        if let Some(auth) = &self.basic_auth {
+            if auth.username.is_empty() || auth.username.contains(':') {
+                problems.push(String::from("basic_auth username must be non-empty and contain no ':'"));
+            }
+
+            if auth.password.is_empty() {
+                problems.push(String::from("basic_auth password must be non-empty"));
+            }
+        }
+
+        if self.default_ttl_secs == Some(0) {
+            problems.push(String::from("default_ttl_secs must be greater than zero"));
+        }
+
+        for (name, namespace) in &self.namespaces {
+            if name.is_empty() || name.contains(crate::NAMESPACE_DELIMITER) {
+                problems.push(format!("namespace {:?} must be non-empty and contain no ':'", name));
+            }
+
+            if namespace.default_ttl_secs == Some(0) {
+                problems.push(format!("namespace {:?} default_ttl_secs must be greater than zero", name));
+            }
+        }
+
+        if self.upstream.is_none() {
+            if self.write_through.is_some() {
+                problems.push(String::from("write_through requires an upstream"));
+            }
+
+            if self.upstream_bad_gateway {
+                problems.push(String::from("upstream_bad_gateway requires an upstream"));
+            }
+        }
+
+        if self.threads == Some(0) {
+            problems.push(String::from("threads must be greater than zero"));
+        }
+
+        if self.persist_interval_secs == Some(0) {
+            problems.push(String::from("persist_interval_secs must be greater than zero"));
+        }
+
+        if self.snapshot_threads == Some(0) {
+            problems.push(String::from("snapshot_threads must be greater than zero"));
+        }
+
+        if self.bloom_filter_keys == Some(0) {
+            problems.push(String::from("bloom_filter_keys must be greater than zero"));
+        }
+
+        if self.write_buffer_bytes == Some(0) {
+            problems.push(String::from("write_buffer_bytes must be greater than zero"));
+        }
+
+        if self.keepalive_max_requests == Some(0) {
+            problems.push(String::from("keepalive_max_requests must be greater than zero"));
+        }
+
+        if self.request_memory_bytes == Some(0) {
+            problems.push(String::from("request_memory_bytes must be greater than zero"));
+        }
+
+        if self.max_value_bytes == Some(0) {
+            problems.push(String::from("max_value_bytes must be greater than zero"));
+        }
+
+        if self.max_entries == Some(0) {
+            problems.push(String::from("max_entries must be greater than zero"));
+        }
+
+        if self.store_limit_keys == Some(0) {
+            problems.push(String::from("store_limit_keys must be greater than zero"));
+        }
+
+        if self.store_limit_bytes == Some(0) {
+            problems.push(String::from("store_limit_bytes must be greater than zero"));
+        }
+
+        if self.slow_request_log == Some(0) {
+            problems.push(String::from("slow_request_log must be greater than zero"));
+        }
+
+        if self.max_key_bytes == Some(0) {
+            problems.push(String::from("max_key_bytes must be greater than zero"));
+        }
+
+        if self.listen_fd.is_some_and(|fd| fd < 0) {
+            problems.push(String::from("listen_fd must not be negative"));
+        }
+
+        if self.no_tcp && self.unix_socket.is_none() {
+            problems.push(String::from("no_tcp requires a unix_socket"));
+        }
+
+        if self.wal_fsync && self.wal_path.is_none() {
+            problems.push(String::from("wal_fsync requires a wal_path"));
+        }
+
+        let enabled = self.enabled_operations.iter().flatten();
+
+        for operation in enabled.chain(&self.disabled_operations) {
+            if !crate::OPERATIONS.contains(&operation.as_str()) {
+                problems.push(format!("unknown operation {:?}", operation));
+            }
+        }
+
+        problems
+    }
+
+    /// Whether the operation named `operation` may run at all.
+    pub(crate) fn allows(&self, operation: &str) -> bool {
+        let enabled = match &self.enabled_operations {
+            Some(enabled) => enabled.contains(operation),
+            None => true,
+        };
+
+        enabled && !self.disabled_operations.contains(operation)
+    }
+
+    /// The expiry applied to `key` when it's set without an explicit one:
+    /// its namespace's default if that namespace has one, otherwise the
+    /// server-wide default.
+    pub(crate) fn ttl_for(&self, key: &str) -> Option<u64> {
+        key.split_once(crate::NAMESPACE_DELIMITER)
+            .and_then(|(namespace, _)| self.namespaces.get(namespace))
+            .and_then(|namespace| namespace.default_ttl_secs)
+            .or(self.default_ttl_secs)
+    }
+}
+
+fn operation_list(ops: &str) -> HashSet<String> {
+    ops.split(',')
+        .map(str::trim)
+        .filter(|op| !op.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// The descriptor passed by systemd socket activation, given the values of
+/// `LISTEN_PID` and `LISTEN_FDS`, if one was meant for the process `own_pid`.
+fn systemd_listen_fd(pid: Option<&str>, fds: Option<&str>, own_pid: u32) -> Option<RawFd> {
+    let pid: u32 = pid?.parse().ok()?;
+    let fds: u32 = fds?.parse().ok()?;
+
+    (pid == own_pid && fds >= 1).then_some(SYSTEMD_LISTEN_FDS_START)
+}
+
+/// Reads and parses an environment variable. An unset variable is absent;
+/// one that doesn't parse is absent too, and is noted in `rejected`.
+fn parse_var<T: FromStr>(name: &str, rejected: &mut Vec<String>) -> Option<T> {
+    let val = env::var(name).ok()?;
+
+    match val.parse() {
+        Ok(parsed) => Some(parsed),
+        Err(_) => {
+            rejected.push(format!("{}: could not parse {:?}", name, val));
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn socket_activation_only_counts_for_the_process_it_names() {
+        let activated = |pid, fds| systemd_listen_fd(pid, fds, 42);
+
+        assert_eq!(activated(Some("42"), Some("1")), Some(SYSTEMD_LISTEN_FDS_START));
+        assert_eq!(activated(Some("42"), Some("2")), Some(SYSTEMD_LISTEN_FDS_START));
+
+        assert_eq!(activated(Some("43"), Some("1")), None);
+        assert_eq!(activated(Some("42"), Some("0")), None);
+        assert_eq!(activated(Some("42"), None), None);
+        assert_eq!(activated(None, Some("1")), None);
+        assert_eq!(activated(Some("not a pid"), Some("1")), None);
+    }
+}
@@ -0,0 +1,145 @@
+use std::str::FromStr;
+
+use serde_json::Value;
+
+use crate::error::ServerError;
+
+/// The formats a fetched value can be sent back in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    #[default]
+    Json,
+    Text,
+    MessagePack,
+}
+
+/// How values are laid out wherever they're written as JSON text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ValueFormat {
+    /// Everything on one line, with no extra whitespace.
+    #[default]
+    Compact,
+    /// Indented over multiple lines, for reading by eye.
+    Pretty,
+}
+
+impl ValueFormat {
+    /// Writes a value out as JSON text in this format.
+    pub fn render(self, value: &Value) -> String {
+        match self {
+            ValueFormat::Compact => value.to_string(),
+            // a Value always serializes, so this can't fall back in practice
+            ValueFormat::Pretty => {
+                serde_json::to_string_pretty(value).unwrap_or_else(|_| value.to_string())
+            }
+        }
+    }
+}
+
+impl FromStr for ValueFormat {
+    type Err = ServerError;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "compact" => Ok(ValueFormat::Compact),
+            "pretty" => Ok(ValueFormat::Pretty),
+            _ => Err(ServerError::InvalidConfig {
+                problems: vec![format!("unknown value format: {}", format)],
+            }),
+        }
+    }
+}
+
+impl Encoding {
+    /// Picks an encoding from the value of an `Accept` header, honoring
+    /// quality values and preferring earlier entries on ties. Wildcards, and
+    /// clients that don't send the header at all, get JSON. Returns `None`
+    /// when nothing the client accepts is supported.
+    pub fn negotiate(accept: Option<&str>) -> Option<Encoding> {
+        let accept = match accept {
+            Some(accept) => accept,
+            None => return Some(Encoding::Json),
+        };
+
+        let mut candidates: Vec<(f32, Encoding)> = accept
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';');
+                let media_type = parts.next()?.trim();
+                let quality = parts
+                    .filter_map(|param| param.trim().strip_prefix("q="))
+                    .find_map(|q| q.parse().ok())
+                    .unwrap_or(1.0);
+
+                let encoding = match media_type {
+                    "application/json" | "application/*" | "*/*" => Encoding::Json,
+                    "text/plain" | "text/*" => Encoding::Text,
+                    "application/msgpack" | "application/x-msgpack" => Encoding::MessagePack,
+                    _ => return None,
+                };
+
+                Some((quality, encoding))
+            })
+            .filter(|(quality, _)| *quality > 0.0)
+            .collect();
+
+        // a stable sort keeps the client's own ordering among equal qualities
+        candidates.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+        candidates.first().map(|(_, encoding)| *encoding)
+    }
+
+    /// Serializes a value, returning its content type along with the bytes.
+    /// Any JSON text is laid out according to `format`.
+    pub fn encode(
+        self,
+        value: &Value,
+        format: ValueFormat,
+    ) -> Result<(&'static str, Vec<u8>), ServerError> {
+        match self {
+            Encoding::Json => Ok(("application/json", format.render(value).into_bytes())),
+            Encoding::Text => {
+                // strings go out bare; anything else is written as JSON text
+                let text = match value {
+                    Value::String(s) => s.clone(),
+                    other => format.render(other),
+                };
+
+                Ok(("text/plain; charset=utf-8", text.into_bytes()))
+            }
+            Encoding::MessagePack => Ok(("application/msgpack", rmp_serde::to_vec(value)?)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiation_honors_quality_and_order() {
+        assert_eq!(Encoding::negotiate(None), Some(Encoding::Json));
+        assert_eq!(Encoding::negotiate(Some("*/*")), Some(Encoding::Json));
+        assert_eq!(Encoding::negotiate(Some("text/plain")), Some(Encoding::Text));
+        assert_eq!(
+            Encoding::negotiate(Some("application/json;q=0.5, application/msgpack")),
+            Some(Encoding::MessagePack)
+        );
+        assert_eq!(
+            Encoding::negotiate(Some("text/plain, application/json")),
+            Some(Encoding::Text)
+        );
+        assert_eq!(Encoding::negotiate(Some("image/png")), None);
+        assert_eq!(Encoding::negotiate(Some("application/json;q=0")), None);
+    }
+
+    #[test]
+    fn nested_values_come_back_as_valid_json_in_either_format() {
+        let nested = serde_json::json!({ "a": [1, { "b": null }], "c": "line\nbreak" });
+
+        for format in [ValueFormat::Compact, ValueFormat::Pretty] {
+            let (_, json) = Encoding::Json.encode(&nested, format).unwrap();
+            assert_eq!(serde_json::from_slice::<Value>(&json).unwrap(), nested, "{:?}", format);
+            assert_eq!(json.contains(&b'\n'), format == ValueFormat::Pretty);
+        }
+    }
+}
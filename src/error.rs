@@ -6,22 +6,89 @@ pub enum ServerError {
     ParseError { reason: String },
     #[error("Failed to bind to address")]
     ConnectionError,
-    #[error("Got an invalid request")]
+    #[error("Invalid configuration:\n  {}", .problems.join("\n  "))]
+    InvalidConfig { problems: Vec<String> },
+    #[error("No endpoint matches the request")]
     InvalidRequest,
+    #[error("Request is larger than {max:?} bytes")]
+    RequestTooLarge { max: usize },
+    #[error("Query parameter {name:?} was given more than once")]
+    DuplicateParam { name: String },
+    #[error("Request body isn't valid UTF-8")]
+    InvalidBody,
     #[error("Received no request from client")]
     NoRequestFound,
-    #[error("Failed to load response")]
-    NoResponseFound,
+    #[error("Upstream request failed: {reason}")]
+    UpstreamError { reason: String },
     #[error(transparent)]
     IoError(#[from] std::io::Error),
     #[error(transparent)]
     SerdeError(#[from] serde_json::Error),
+    #[error(transparent)]
+    MessagePackError(#[from] rmp_serde::encode::Error),
 }
 
 #[derive(Error, Debug)]
 pub enum ParseError {
-    #[error("Request was improperly formatted: {code:?}")]
+    #[error("Request was improperly formatted ({code:?}): {}", describe(*code))]
     InvalidRequest { code: u32 },
     #[error("No key found in request")]
     MissingKey,
+    #[error("Key is longer than {max:?} bytes")]
+    KeyTooLong { max: usize },
+}
+
+/// What each `ParseError::InvalidRequest` code says was wrong with the
+/// request.
+fn describe(code: u32) -> &'static str {
+    match code {
+        1 => "the key must be given exactly once",
+        3 => "a set has to start with a key=value pair",
+        4 => "the request has no query string",
+        5 => "a flag must be true or false",
+        6 => "unknown or misplaced query parameter",
+        7 => "the request line has no path",
+        8 => "no namespace was given to flush",
+        9 => "since must be given as a whole number",
+        10 => "no path was given",
+        11 => "no expression was given",
+        12 => "ops and keys must be whole numbers within the benchmark's limits",
+        13 => "the scan cursor isn't valid",
+        14 => "count is out of range",
+        15 => "the amount must be a finite number",
+        16 => "no field was given",
+        17 => "no value was given",
+        18 => "exactly one of a prefix or a JSON list of keys must be given",
+        19 => "unknown if_type",
+        20 => "a query parameter isn't valid percent-encoded UTF-8",
+        21 => "ttl must be a positive whole number of seconds",
+        22 => "sample is out of range",
+        23 => "both namespaces must be given, non-empty and without a ':'",
+        24 => "no default was given",
+        25 => "the delimiter must not be empty",
+        26 => "no expected value was given",
+        _ => "the request couldn't be parsed",
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ExprError {
+    #[error("Expression is longer than {max:?} characters")]
+    TooLong { max: usize },
+    #[error("Expression nests deeper than {max:?} levels")]
+    TooDeep { max: usize },
+    #[error("Unexpected input at position {pos:?}")]
+    Unexpected { pos: usize },
+    #[error("Division by zero")]
+    DivisionByZero,
+    #[error("Expression result is not a finite number")]
+    NotFinite,
+}
+
+#[derive(Error, Debug)]
+pub enum TemplateError {
+    #[error("No value given for placeholder {name:?}")]
+    MissingVar { name: String },
+    #[error("Placeholder opened at position {pos:?} is never closed")]
+    Unclosed { pos: usize },
 }
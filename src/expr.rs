@@ -0,0 +1,176 @@
+//! A deliberately tiny arithmetic language for `/eval`. An expression is
+//! built from numbers, the name `value` (the key's current number), the
+//! operators `+ - * /`, unary minus and parentheses. There are no other
+//! names, functions or side effects, and both the length of an expression
+//! and how deeply it nests are capped.
+
+use crate::error::ExprError;
+
+const MAX_EXPR_LEN: usize = 256;
+const MAX_DEPTH: usize = 16;
+
+/// Evaluates `expr` with `value` bound to the given number.
+pub fn eval(expr: &str, value: f64) -> Result<f64, ExprError> {
+    if expr.len() > MAX_EXPR_LEN {
+        return Err(ExprError::TooLong { max: MAX_EXPR_LEN });
+    }
+
+    let mut parser = Parser {
+        input: expr.as_bytes(),
+        pos: 0,
+        value,
+    };
+
+    let result = parser.expr(0)?;
+    parser.skip_whitespace();
+
+    if parser.pos != parser.input.len() {
+        return Err(ExprError::Unexpected { pos: parser.pos });
+    }
+
+    if result.is_finite() {
+        Ok(result)
+    } else {
+        Err(ExprError::NotFinite)
+    }
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+    value: f64,
+}
+
+impl Parser<'_> {
+    // expr := term (('+' | '-') term)*
+    fn expr(&mut self, depth: usize) -> Result<f64, ExprError> {
+        let mut acc = self.term(depth)?;
+
+        loop {
+            match self.peek() {
+                Some(b'+') => {
+                    self.pos += 1;
+                    acc += self.term(depth)?;
+                }
+                Some(b'-') => {
+                    self.pos += 1;
+                    acc -= self.term(depth)?;
+                }
+                _ => return Ok(acc),
+            }
+        }
+    }
+
+    // term := factor (('*' | '/') factor)*
+    fn term(&mut self, depth: usize) -> Result<f64, ExprError> {
+        let mut acc = self.factor(depth)?;
+
+        loop {
+            match self.peek() {
+                Some(b'*') => {
+                    self.pos += 1;
+                    acc *= self.factor(depth)?;
+                }
+                Some(b'/') => {
+                    self.pos += 1;
+                    let divisor = self.factor(depth)?;
+
+                    if divisor == 0.0 {
+                        return Err(ExprError::DivisionByZero);
+                    }
+
+                    acc /= divisor;
+                }
+                _ => return Ok(acc),
+            }
+        }
+    }
+
+    // factor := '-' factor | '(' expr ')' | 'value' | number
+    fn factor(&mut self, depth: usize) -> Result<f64, ExprError> {
+        if depth > MAX_DEPTH {
+            return Err(ExprError::TooDeep { max: MAX_DEPTH });
+        }
+
+        match self.peek() {
+            Some(b'-') => {
+                self.pos += 1;
+                Ok(-self.factor(depth + 1)?)
+            }
+            Some(b'(') => {
+                self.pos += 1;
+                let inner = self.expr(depth + 1)?;
+
+                if self.peek() != Some(b')') {
+                    return Err(ExprError::Unexpected { pos: self.pos });
+                }
+
+                self.pos += 1;
+                Ok(inner)
+            }
+            Some(b'v') if self.input[self.pos..].starts_with(b"value") => {
+                self.pos += "value".len();
+                Ok(self.value)
+            }
+            Some(c) if c.is_ascii_digit() || c == b'.' => self.number(),
+            _ => Err(ExprError::Unexpected { pos: self.pos }),
+        }
+    }
+
+    fn number(&mut self) -> Result<f64, ExprError> {
+        let start = self.pos;
+
+        while self
+            .input
+            .get(self.pos)
+            .is_some_and(|c| c.is_ascii_digit() || *c == b'.')
+        {
+            self.pos += 1;
+        }
+
+        // the input is valid UTF-8 and we only consumed ASCII
+        let literal = std::str::from_utf8(&self.input[start..self.pos]).unwrap_or_default();
+        literal
+            .parse()
+            .map_err(|_| ExprError::Unexpected { pos: start })
+    }
+
+    /// Skips whitespace and returns the next byte without consuming it.
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.input.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.input.get(self.pos).is_some_and(u8::is_ascii_whitespace) {
+            self.pos += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operators_bind_the_usual_way() {
+        assert_eq!(eval("value * 3", 4.0).unwrap(), 12.0);
+        assert_eq!(eval("1 + 2 * 3", 0.0).unwrap(), 7.0);
+        assert_eq!(eval("(1 + 2) * 3", 0.0).unwrap(), 9.0);
+        assert_eq!(eval("-value - -2", 5.0).unwrap(), -3.0);
+        assert_eq!(eval("10 / 4", 0.0).unwrap(), 2.5);
+    }
+
+    #[test]
+    fn bad_expressions_are_refused() {
+        assert!(matches!(eval("value *", 1.0), Err(ExprError::Unexpected { .. })));
+        assert!(matches!(eval("value 2", 1.0), Err(ExprError::Unexpected { pos: 6 })));
+        assert!(matches!(eval("other", 1.0), Err(ExprError::Unexpected { .. })));
+        assert!(matches!(eval("1 / 0", 1.0), Err(ExprError::DivisionByZero)));
+        assert!(matches!(eval(&"1+".repeat(200), 1.0), Err(ExprError::TooLong { .. })));
+        assert!(matches!(
+            eval(&format!("{}1{}", "(".repeat(20), ")".repeat(20)), 1.0),
+            Err(ExprError::TooDeep { .. })
+        ));
+    }
+}
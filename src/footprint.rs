@@ -0,0 +1,50 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use crate::estimated_size;
+
+/// A running total of how many bytes the store's keys and values take up,
+/// kept by noting each key's size whenever it changes so that it never has
+/// to be summed over the whole store.
+#[derive(Default)]
+pub struct Footprint {
+    bytes: usize,
+    /// What each key was counted as the last time it changed.
+    sizes: HashMap<String, usize>,
+}
+
+impl Footprint {
+    /// The total across every key.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// What `key` is counted as now, or zero if it isn't stored.
+    pub fn size_of(&self, key: &str) -> usize {
+        self.sizes.get(key).copied().unwrap_or(0)
+    }
+
+    /// Recounts `key` as holding `value`, or as gone if there isn't one.
+    pub fn update(&mut self, key: &str, value: Option<&Value>) {
+        let new = value.map(|value| entry_size(key, value));
+        let old = match new {
+            Some(size) => self.sizes.insert(String::from(key), size),
+            None => self.sizes.remove(key),
+        };
+
+        self.bytes = self.bytes - old.unwrap_or(0) + new.unwrap_or(0);
+    }
+}
+
+/// How many bytes storing `value` under `key` is counted as.
+pub fn entry_size(key: &str, value: &Value) -> usize {
+    let (key_bytes, value_bytes) = entry_sizes(key, value);
+    key_bytes + value_bytes
+}
+
+/// The bytes `entry_size` counts, split into the key's and the value's, for
+/// totals that count a value shared between keys only once.
+pub fn entry_sizes(key: &str, value: &Value) -> (usize, usize) {
+    (key.len(), estimated_size(value))
+}
@@ -1,212 +1,4198 @@
-#![feature(map_entry_replace)]
-
+mod auth;
+mod backend;
+mod benchmark;
+mod bloom;
+mod cli;
+mod coalesce;
+mod config;
+mod encoding;
 mod error;
+mod expr;
+mod footprint;
+mod lru;
+mod metrics;
+mod pool;
+mod schema;
+mod secret;
+mod slow_log;
+mod slow_start;
+mod snapshot;
+mod template;
+mod upstream;
+mod wal;
+
+#[cfg(test)]
+mod tests;
 
-use std::collections::hash_map::{Entry, HashMap};
-use std::fs::{self, File};
-use std::io::prelude::*;
-use std::net::{TcpListener, TcpStream};
-use std::path::Path;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::fs;
+use std::borrow::Cow;
+use std::io::{self, prelude::*, BufWriter};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::{anyhow, Result};
-use error::{ServerError, ParseError};
+use bloom::BloomFilter;
+use coalesce::Coalescer;
+use encoding::Encoding;
+use error::{ExprError, ServerError, ParseError, TemplateError};
+use footprint::Footprint;
+use log::{debug, error, info, warn};
+use lru::Lru;
+use metrics::{Metrics, MetricsSnapshot, Stats};
+use pool::ThreadPool;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use slow_log::{SlowLog, SlowRequest};
+use slow_start::SlowStart;
+use wal::Wal;
+
+pub use auth::BasicAuth;
+pub use backend::{JsonFileBackend, PersistenceBackend};
+pub use config::{DuplicateParams, ErrorVerbosity, NamespaceConfig, OversizedValues, ServerConfig};
+pub use encoding::ValueFormat;
+pub use secret::Secret;
+pub use upstream::{Upstream, WriteThrough};
+
+/// Every operation, by the name config lists use to switch it on or off.
+pub(crate) const OPERATIONS: [&str; 35] = [
+    "get",
+    "set",
+    "getdel",
+    "delete",
+    "metrics_reset",
+    "flush_namespace",
+    "changed",
+    "scan",
+    "diff",
+    "eval",
+    "decr",
+    "benchmark",
+    "explain",
+    "setmeta",
+    "getmeta",
+    "snapshot_subset",
+    "debug_expired",
+    "render",
+    "trace",
+    "keys",
+    "exists",
+    "incr",
+    "infer_schema",
+    "move",
+    "favicon",
+    "getorcreate",
+    "count_by_prefix",
+    "mget",
+    "persist",
+    "ready",
+    "cas",
+    "stats",
+    "trace_slow",
+    "export",
+    "import",
+];
 
 const BUFFER_SIZE: usize = 1024;
+/// The room a request is given for its headers and the rest of its request
+/// line, on top of the largest value it could carry.
+const REQUEST_HEAD_BYTES: usize = 64 * 1024;
+/// How many connections are served at once when the config doesn't say.
+const DEFAULT_THREADS: usize = 4;
+const DEFAULT_PERSIST_INTERVAL_SECS: u64 = 30;
+/// The largest value a set may store when the config doesn't say, so that
+/// no single client can run the server out of memory with one value.
+const DEFAULT_MAX_VALUE_BYTES: usize = 1024 * 1024;
+/// How many dead entries the dedup table may hold beyond twice the number of
+/// keys before it's swept.
+const INTERN_SWEEP_SLACK: usize = 1024;
+/// How many removed keys the eviction order may still track beyond twice
+/// the number of keys before it's swept.
+const LRU_SWEEP_SLACK: usize = 1024;
+/// Where the server listens unless it's configured otherwise.
 const ADDRESS: &str = "127.0.0.1:4000";
 const SET_HEADER: &str = "GET /set?";
+const POST_SET_HEADER: &str = "POST /set?";
 const GET_HEADER: &str = "GET /get?key=";
-const SUCCESS_STATUS: &str = "HTTP/1.1 200 OK\r\n\r\n";
-const NOT_FOUND_STATUS: &str = "HTTP/1.1 404 NOT FOUND\r\n\r\n";
+const GETDEL_HEADER: &str = "GET /getdel?key=";
+const DELETE_HEADER: &str = "GET /delete?key=";
+const METRICS_RESET_HEADER: &str = "POST /metrics/reset";
+const STATS_HEADER: &str = "GET /stats ";
+const TRACE_SLOW_HEADER: &str = "GET /trace-slow ";
+const NAMESPACE_HEADER: &str = "POST /ns/";
+const CHANGED_HEADER: &str = "GET /changed?";
+const DIFF_HEADER: &str = "POST /diff?";
+const EVAL_HEADER: &str = "GET /eval?";
+const BENCHMARK_HEADER: &str = "POST /benchmark";
+const DECR_HEADER: &str = "GET /decr?";
+const INCR_HEADER: &str = "GET /incr?";
+const INFER_SCHEMA_HEADER: &str = "GET /infer-schema";
+const MOVE_HEADER: &str = "GET /move?";
+const FAVICON_HEADER: &str = "GET /favicon.ico ";
+const READY_HEADER: &str = "GET /ready ";
+const EXPLAIN_HEADER: &str = "POST /explain";
+const SETMETA_HEADER: &str = "GET /setmeta?";
+const GETMETA_HEADER: &str = "GET /getmeta-field?";
+const SNAPSHOT_SUBSET_HEADER: &str = "POST /snapshot/subset?";
+const EXPIRED_HEADER: &str = "GET /debug/expired";
+const SCAN_HEADER: &str = "GET /scan";
+const RENDER_HEADER: &str = "GET /render?";
+const TRACE_HEADER: &str = "GET /trace";
+const KEYS_HEADER: &str = "GET /keys";
+const COUNT_BY_PREFIX_HEADER: &str = "GET /count-by-prefix";
+const EXISTS_HEADER: &str = "GET /exists?";
+const GETORCREATE_HEADER: &str = "GET /getorcreate?";
+const CAS_HEADER: &str = "GET /cas?";
+const MGET_HEADER: &str = "GET /mget?";
+const PERSIST_HEADER: &str = "GET /persist?";
+const EXPORT_HEADER: &str = "GET /export";
+const IMPORT_HEADER: &str = "POST /import";
+const DEFAULT_SCAN_COUNT: usize = 100;
+const MAX_SCAN_COUNT: usize = 10_000;
+const NO_CONTENT_STATUS: &str = "HTTP/1.1 204 NO CONTENT\r\n\r\n";
+const SUCCESS_STATUS: &str = "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n";
+const TRACE_SUCCESS_STATUS: &str = "HTTP/1.1 200 OK\r\nContent-Type: message/http\r\n\r\n";
+const BAD_REQUEST_STATUS: &str = "HTTP/1.1 400 BAD REQUEST\r\nContent-Type: application/json\r\n\r\n";
+const UNAUTHORIZED_STATUS: &str = "HTTP/1.1 401 UNAUTHORIZED\r\nContent-Type: application/json\r\n\
+    WWW-Authenticate: Basic realm=\"db-server\"\r\n\r\n";
+const FORBIDDEN_STATUS: &str = "HTTP/1.1 403 FORBIDDEN\r\nContent-Type: application/json\r\n\r\n";
+const NOT_FOUND_STATUS: &str = "HTTP/1.1 404 NOT FOUND\r\nContent-Type: application/json\r\n\r\n";
+const NOT_ACCEPTABLE_STATUS: &str =
+    "HTTP/1.1 406 NOT ACCEPTABLE\r\nContent-Type: application/json\r\n\r\n";
+const TOO_LARGE_STATUS: &str =
+    "HTTP/1.1 413 PAYLOAD TOO LARGE\r\nContent-Type: application/json\r\n\r\n";
+const CONFLICT_STATUS: &str = "HTTP/1.1 409 CONFLICT\r\nContent-Type: application/json\r\n\r\n";
+const BAD_GATEWAY_STATUS: &str = "HTTP/1.1 502 BAD GATEWAY\r\nContent-Type: application/json\r\n\r\n";
+const INSUFFICIENT_STORAGE_STATUS: &str =
+    "HTTP/1.1 507 INSUFFICIENT STORAGE\r\nContent-Type: application/json\r\n\r\n";
+const SERVICE_UNAVAILABLE_STATUS: &str =
+    "HTTP/1.1 503 SERVICE UNAVAILABLE\r\nContent-Type: application/json\r\n\r\n";
+const TIMEOUT_STATUS: &str =
+    "HTTP/1.1 504 GATEWAY TIMEOUT\r\nContent-Type: application/json\r\n\r\n";
+const DEADLINE_HEADER: &str = "x-deadline-ms";
+const AUTHORIZATION_HEADER: &str = "authorization";
+const ACCEPT_HEADER: &str = "accept";
+const CONTENT_HASH_HEADER: &str = "X-Content-Hash";
+const KEY_PREFIX_HEADER: &str = "x-key-prefix";
+const CONTENT_LENGTH_HEADER: &str = "content-length";
+const CONNECTION_HEADER: &str = "connection";
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a kept-alive connection may wait for its next request.
+const KEEPALIVE_IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a client that connects while the store loads gets to send its
+/// request before it's answered anyway.
+const LOADING_READ_TIMEOUT: Duration = Duration::from_secs(1);
+/// Where the store is persisted unless it's configured otherwise.
 const PERSIST: &str = "persist.json";
+/// Separates a key's namespace from the rest of it, as in `tenant:key`.
+pub(crate) const NAMESPACE_DELIMITER: char = ':';
 
 enum Request {
-    Get(String),
-    Set(String, String),
+    Get(String, GetOptions),
+    Set(String, String, SetOptions),
+    GetDel(String),
+    Delete(String),
+    ResetMetrics,
+    FlushNamespace(String),
+    Changed { since: u64, values: bool, scope: String },
+    Scan(ScanOptions),
+    Diff(String),
+    Eval { key: String, expr: String },
+    Decr { key: String, by: f64, floor: Option<f64> },
+    Incr { key: String, by: f64 },
+    Benchmark { ops: usize, keys: usize },
+    Explain(String),
+    SetMeta { key: String, field: String, value: String },
+    GetMeta { key: String, field: String },
+    SnapshotSubset { path: String, selection: Selection },
+    Expired { scope: String },
+    Render { key: String, vars: HashMap<String, String> },
+    /// Echo the request line and headers, exactly as they were received.
+    Trace(String),
+    /// Every live key starting with `prefix`, within the connection's
+    /// `scope`.
+    List { prefix: String, scope: String },
+    /// How many live keys there are with each prefix up to the first
+    /// `delimiter`, within the connection's `scope`.
+    CountByPrefix { delimiter: String, scope: String },
+    Exists(String),
+    /// Infer a schema from up to `sample` live values under `prefix`,
+    /// within the connection's `scope`.
+    InferSchema { prefix: String, sample: usize, scope: String },
+    /// Move `key` from one namespace to another; with `nx`, only if the
+    /// target doesn't hold it already.
+    Move { key: String, from_ns: String, to_ns: String, nx: bool },
+    /// A browser asking for the site icon, which there isn't one of.
+    Favicon,
+    /// Fetch `key`, first setting it to `default` if it isn't there.
+    GetOrCreate { key: String, default: String },
+    /// Take away the key's expiry, so it's kept until it's deleted.
+    Persist(String),
+    /// Fetch each of `keys`, within the connection's `scope`.
+    MultiGet { keys: Vec<String>, scope: String },
+    /// Whether the server has finished starting up and is taking traffic.
+    Ready,
+    /// Set `key` to `new`, but only if it currently holds `expected`; with
+    /// no `expected`, only if it's absent.
+    Cas { key: String, expected: Option<String>, new: String },
+    /// Report the size of the store and the operation counters so far.
+    Stats,
+    /// List the most recent requests that were slow to handle.
+    TraceSlow,
+    /// Dump the whole store, in the format it's persisted in.
+    Export,
+    /// Load a dump into the store, over what's there; with `replace`, the
+    /// keys it doesn't hold are removed.
+    Import { body: String, replace: bool },
+}
+
+/// Which keys a partial snapshot takes.
+enum Selection {
+    Keys(Vec<String>),
+    Prefix(String),
+}
+
+impl Request {
+    /// Whether a successful response carries a stored value, and so has to
+    /// be encoded in a format the client accepts.
+    fn returns_value(&self) -> bool {
+        matches!(
+            self,
+            Request::Get(..)
+                | Request::GetDel(_)
+                | Request::Eval { .. }
+                | Request::GetMeta { .. }
+                | Request::Render { .. }
+                | Request::Incr { .. }
+                | Request::GetOrCreate { .. }
+        )
+    }
+
+    /// Moves the request into the key space under `prefix`, so that it reads
+    /// and writes `<prefix><key>` wherever it names `<key>`. Admin requests
+    /// operate on the whole store and are left alone.
+    fn with_key_prefix(self, prefix: &str) -> Request {
+        let prefixed = |key: String| format!("{}{}", prefix, key);
+
+        match self {
+            Request::Get(key, options) => Request::Get(prefixed(key), options),
+            Request::Set(key, val, options) => Request::Set(prefixed(key), val, options),
+            Request::GetDel(key) => Request::GetDel(prefixed(key)),
+            Request::Delete(key) => Request::Delete(prefixed(key)),
+            Request::Exists(key) => Request::Exists(prefixed(key)),
+            Request::Persist(key) => Request::Persist(prefixed(key)),
+            Request::FlushNamespace(namespace) => Request::FlushNamespace(prefixed(namespace)),
+            Request::Changed { since, values, .. } => Request::Changed {
+                since,
+                values,
+                scope: String::from(prefix),
+            },
+            Request::Expired { .. } => Request::Expired {
+                scope: String::from(prefix),
+            },
+            Request::List { prefix: listed, .. } => Request::List {
+                prefix: listed,
+                scope: String::from(prefix),
+            },
+            Request::MultiGet { keys, .. } => Request::MultiGet {
+                keys,
+                scope: String::from(prefix),
+            },
+            Request::CountByPrefix { delimiter, .. } => Request::CountByPrefix {
+                delimiter,
+                scope: String::from(prefix),
+            },
+            Request::InferSchema { prefix: sampled, sample, .. } => Request::InferSchema {
+                prefix: sampled,
+                sample,
+                scope: String::from(prefix),
+            },
+            Request::Scan(options) => Request::Scan(ScanOptions {
+                scope: String::from(prefix),
+                ..options
+            }),
+            Request::Eval { key, expr } => Request::Eval {
+                key: prefixed(key),
+                expr,
+            },
+            Request::Decr { key, by, floor } => Request::Decr {
+                key: prefixed(key),
+                by,
+                floor,
+            },
+            Request::Incr { key, by } => Request::Incr {
+                key: prefixed(key),
+                by,
+            },
+            Request::SetMeta { key, field, value } => Request::SetMeta {
+                key: prefixed(key),
+                field,
+                value,
+            },
+            Request::GetMeta { key, field } => Request::GetMeta {
+                key: prefixed(key),
+                field,
+            },
+            Request::Render { key, vars } => Request::Render {
+                key: prefixed(key),
+                vars,
+            },
+            Request::GetOrCreate { key, default } => Request::GetOrCreate {
+                key: prefixed(key),
+                default,
+            },
+            Request::Cas { key, expected, new } => Request::Cas {
+                key: prefixed(key),
+                expected,
+                new,
+            },
+            // namespaces lead the key, so that's where the prefix goes
+            Request::Move { key, from_ns, to_ns, nx } => Request::Move {
+                key,
+                from_ns: prefixed(from_ns),
+                to_ns: prefixed(to_ns),
+                nx,
+            },
+            other => other,
+        }
+    }
+
+    /// The key the request reads or writes, if it names a single one.
+    fn key(&self) -> Option<&str> {
+        match self {
+            Request::Get(key, _)
+            | Request::Set(key, ..)
+            | Request::GetDel(key)
+            | Request::Delete(key)
+            | Request::Exists(key)
+            | Request::Persist(key)
+            | Request::Eval { key, .. }
+            | Request::Decr { key, .. }
+            | Request::Incr { key, .. }
+            | Request::SetMeta { key, .. }
+            | Request::GetMeta { key, .. }
+            | Request::Render { key, .. }
+            | Request::GetOrCreate { key, .. }
+            | Request::Cas { key, .. } => Some(key),
+            _ => None,
+        }
+    }
+
+    /// Every key the request names, as they'd be stored: its single key, or
+    /// each of the keys it reads, writes or clears in bulk.
+    fn keys(&self) -> Vec<Cow<'_, str>> {
+        if let Some(key) = self.key() {
+            return vec![Cow::Borrowed(key)];
+        }
+
+        match self {
+            Request::MultiGet { keys, .. }
+            | Request::SnapshotSubset { selection: Selection::Keys(keys), .. } => {
+                keys.iter().map(|key| Cow::Borrowed(key.as_str())).collect()
+            }
+            Request::SnapshotSubset { selection: Selection::Prefix(prefix), .. } => {
+                vec![Cow::Borrowed(prefix.as_str())]
+            }
+            Request::FlushNamespace(namespace) => vec![Cow::Borrowed(namespace.as_str())],
+            Request::Move { key, from_ns, to_ns, .. } => vec![
+                Cow::Owned(format!("{}{}{}", from_ns, NAMESPACE_DELIMITER, key)),
+                Cow::Owned(format!("{}{}{}", to_ns, NAMESPACE_DELIMITER, key)),
+            ],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Whether this is an administrative operation. These are refused
+    /// outright unless the server has been configured with credentials.
+    fn is_admin(&self) -> bool {
+        matches!(
+            self,
+            Request::Diff(_)
+                | Request::Benchmark { .. }
+                | Request::SnapshotSubset { .. }
+                | Request::Export
+                | Request::Import { .. }
+        )
+    }
+
+    /// The name this kind of request goes by in the enabled and disabled
+    /// operation lists; one of `OPERATIONS`.
+    fn operation(&self) -> &'static str {
+        match self {
+            Request::Get(..) => "get",
+            Request::Set(..) => "set",
+            Request::GetDel(_) => "getdel",
+            Request::Delete(_) => "delete",
+            Request::ResetMetrics => "metrics_reset",
+            Request::FlushNamespace(_) => "flush_namespace",
+            Request::Changed { .. } => "changed",
+            Request::Scan(_) => "scan",
+            Request::Diff(_) => "diff",
+            Request::Eval { .. } => "eval",
+            Request::Decr { .. } => "decr",
+            Request::Benchmark { .. } => "benchmark",
+            Request::Explain(_) => "explain",
+            Request::SetMeta { .. } => "setmeta",
+            Request::GetMeta { .. } => "getmeta",
+            Request::SnapshotSubset { .. } => "snapshot_subset",
+            Request::Expired { .. } => "debug_expired",
+            Request::Render { .. } => "render",
+            Request::Trace(_) => "trace",
+            Request::List { .. } => "keys",
+            Request::CountByPrefix { .. } => "count_by_prefix",
+            Request::MultiGet { .. } => "mget",
+            Request::Persist(_) => "persist",
+            Request::Exists(_) => "exists",
+            Request::Incr { .. } => "incr",
+            Request::InferSchema { .. } => "infer_schema",
+            Request::Move { .. } => "move",
+            Request::Favicon => "favicon",
+            Request::GetOrCreate { .. } => "getorcreate",
+            Request::Ready => "ready",
+            Request::Cas { .. } => "cas",
+            Request::Stats => "stats",
+            Request::TraceSlow => "trace_slow",
+            Request::Export => "export",
+            Request::Import { .. } => "import",
+        }
+    }
+}
+
+/// Optional `&`-separated flags that may follow the key of a get.
+#[derive(Default)]
+struct GetOptions {
+    /// Serve an expired value while it's refreshed from the upstream in the
+    /// background, rather than missing.
+    swr: bool,
+}
+
+/// Where a `/scan` batch picks up and how much it returns.
+struct ScanOptions {
+    /// Only keys after this one are returned; `None` starts from the top.
+    after: Option<String>,
+    /// The most entries to return in this batch.
+    count: usize,
+    /// Only keys starting with this are returned.
+    prefix: String,
+    /// The connection's key prefix, which is hidden from the keys returned.
+    scope: String,
+}
+
+/// Optional `&`-separated flags that may follow the key/value pair of a set.
+#[derive(Default)]
+struct SetOptions {
+    /// Strip surrounding whitespace from the value before storing it.
+    trim: bool,
+    /// Only write if the current value's content hash, in hex, is this.
+    if_hash: Option<String>,
+    /// Only write if the key is absent or its current value is of this type.
+    if_type: Option<JsonType>,
+    /// How many seconds the value lives for, overriding the default TTL.
+    ttl_secs: Option<u64>,
+}
+
+/// The kinds of JSON value a conditional set can insist on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum JsonType {
+    Null,
+    Bool,
+    /// A number with no fractional part.
+    Int,
+    /// A number stored with a fractional part.
+    Float,
+    /// Any number at all.
+    Number,
+    String,
+    Array,
+    Object,
+}
+
+impl JsonType {
+    fn parse(name: &str) -> Option<JsonType> {
+        match name {
+            "null" => Some(JsonType::Null),
+            "bool" => Some(JsonType::Bool),
+            "int" => Some(JsonType::Int),
+            "float" => Some(JsonType::Float),
+            "number" => Some(JsonType::Number),
+            "string" => Some(JsonType::String),
+            "array" => Some(JsonType::Array),
+            "object" => Some(JsonType::Object),
+            _ => None,
+        }
+    }
+
+    fn matches(self, value: &Value) -> bool {
+        match (self, value) {
+            (JsonType::Null, Value::Null)
+            | (JsonType::Bool, Value::Bool(_))
+            | (JsonType::Number, Value::Number(_))
+            | (JsonType::String, Value::String(_))
+            | (JsonType::Array, Value::Array(_))
+            | (JsonType::Object, Value::Object(_)) => true,
+            (JsonType::Int, Value::Number(n)) => !n.is_f64(),
+            (JsonType::Float, Value::Number(n)) => n.is_f64(),
+            _ => false,
+        }
+    }
 }
 
 enum Response {
-    GetSuccess(String),
+    GetSuccess(Arc<Value>),
     SetSuccess,
+    NoContent,
+    /// The set went through with the value cut down to this many bytes.
+    SetTruncated(usize),
+    DeleteSuccess,
+    Metrics(MetricsSnapshot),
+    Stats(Stats),
+    /// The whole store, already serialized.
+    Export(String),
+    NamespaceFlushed(usize),
+    Json(Value),
+    Trace(String),
     NotFound,
+    NotAcceptable,
+    TooLarge,
+    /// A conditional write lost out; carries the value it lost to, if any.
+    Conflict(Option<Arc<Value>>),
+    BadRequest(Failure),
+    /// The values of several keys, in the order they were asked for, with
+    /// `None` for those that aren't there.
+    MultiGet(Vec<(String, Option<Arc<Value>>)>),
+    /// A request that couldn't be made sense of, with what was wrong with
+    /// it. That only ever describes the client's own request, so it's sent
+    /// whatever the error verbosity.
+    Malformed(String),
+    Unauthorized,
+    Forbidden,
+    BadGateway(Failure),
+    Timeout,
+    /// The request would have needed more memory than it's allowed.
+    InsufficientStorage,
+    /// The write would take the store past the named limit.
+    StoreFull(&'static str),
+    /// The server isn't ready to take traffic yet, and why.
+    Unavailable(&'static str),
+}
+
+/// Why a request failed, split into the part that's always safe to show a
+/// client and the part that might leak internals.
+struct Failure {
+    /// A stable name for the kind of failure.
+    code: &'static str,
+    /// The underlying cause, only sent with detailed error verbosity.
+    cause: String,
 }
 
-#[derive(Serialize, Deserialize)]
-struct Storage(HashMap<String, Value>);
+impl Failure {
+    fn new(code: &'static str, cause: impl ToString) -> Self {
+        Failure { code, cause: cause.to_string() }
+    }
 
-pub fn server_init() -> Result<()> {
-    let persisted = fs::read_to_string(PERSIST)
-        .map_err(|err| ServerError::IoError(err))?;
-    let mut storage = Storage(
-        serde_json::from_str(&persisted)
-            .unwrap_or(HashMap::new())
-    );
-    let listener = TcpListener::bind(ADDRESS).map_err(|_| ServerError::ConnectionError)?;
+    /// The body of the error response, carrying as much detail as the
+    /// configured verbosity allows.
+    fn to_json(&self, verbosity: ErrorVerbosity) -> Value {
+        match verbosity {
+            ErrorVerbosity::Terse => serde_json::json!({ "error": self.code }),
+            ErrorVerbosity::Detailed => serde_json::json!({ "error": self.code, "cause": self.cause }),
+        }
+    }
+}
 
-    println!("Listening on {}...", ADDRESS);
+/// The headers of a request, keyed by their lowercased names.
+struct Headers(HashMap<String, String>);
 
-    for stream in listener.incoming() {
-        let mut stream = stream?;
+impl Headers {
+    fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(String::as_str)
+    }
 
-        match parse_request(&mut stream) {
-            Ok(request) => {
-                let response = handle_request(request, &mut storage);
-                send_response(response, &mut stream)?;
-            }
-            Err(err) => {
-                if let ServerError::InvalidRequest = err {
-                    // got an invalid request; skip it
-                    continue;
-                } else {
-                    return Err(anyhow!(err));
-                }
-            }
+    /// Computes the point in time by which a request must be handled, using
+    /// the client's `X-Deadline-Ms` budget if one was sent and parses, or the
+    /// default timeout otherwise.
+    fn deadline(&self, received: Instant) -> Instant {
+        let budget = self
+            .get(DEADLINE_HEADER)
+            .and_then(|ms| ms.trim().parse().ok())
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_TIMEOUT);
+
+        received + budget
+    }
+}
+
+/// A stored value along with bookkeeping about it.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Record {
+    /// Shared with every other record holding an identical value when
+    /// values are deduplicated.
+    value: Arc<Value>,
+    /// When the value was last written, in seconds since the UNIX epoch.
+    modified: u64,
+    /// When the value stops being served, in seconds since the UNIX epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires: Option<u64>,
+    /// Arbitrary fields describing the key, such as tags or an owner, kept
+    /// apart from the value and surviving overwrites of it.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    meta: HashMap<String, String>,
+}
+
+impl Record {
+    fn new(value: impl Into<Arc<Value>>) -> Self {
+        Record {
+            value: value.into(),
+            modified: now(),
+            expires: None,
+            meta: HashMap::new(),
         }
     }
 
-    Ok(())
+    fn with_ttl(value: impl Into<Arc<Value>>, ttl_secs: Option<u64>) -> Self {
+        let mut record = Record::new(value);
+        record.expires = ttl_secs.map(|ttl| record.modified + ttl);
+        record
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    /// Replaces the value, keeping any expiry the record already had.
+    fn update(&mut self, value: Value) {
+        self.value = Arc::new(value);
+        self.modified = now();
+    }
 }
 
-impl Drop for Storage {
-    fn drop(&mut self) {
-        // Flush the contents of the HashMap to the persistence file 
-        println!("Flushing data to disk...");
+/// Reads a value as it was given to a set: as JSON if it parses, so that
+/// numbers, booleans and null keep their types, and as a string otherwise.
+fn typed_value(val: &str) -> Value {
+    serde_json::from_str(val).unwrap_or_else(|_| Value::from(val))
+}
+
+/// Checks a key a request names against the configured limit on key length.
+fn check_key(key: &str, config: &ServerConfig) -> Result<(), ParseError> {
+    match config.max_key_bytes {
+        Some(max) if key.len() > max => Err(ParseError::KeyTooLong { max }),
+        _ => Ok(()),
+    }
+}
+
+/// The length of a value as a set would have been given it: a string's own
+/// text, or the JSON of anything else.
+fn value_len(value: &Value) -> usize {
+    match value {
+        Value::String(s) => s.len(),
+        _ => value.to_string().len(),
+    }
+}
 
-        let path = Path::new(PERSIST);
-        let json = serde_json::to_string(&self.0).expect("Failed to serialize data"); 
+/// A rough count of the bytes a value takes up in memory, for budgeting.
+fn estimated_size(value: &Value) -> usize {
+    std::mem::size_of::<Value>()
+        + match value {
+            Value::String(s) => s.len(),
+            Value::Array(items) => items.iter().map(estimated_size).sum(),
+            Value::Object(map) => map.iter().map(|(k, v)| k.len() + estimated_size(v)).sum(),
+            _ => 0,
+        }
+}
 
-        let mut file = match File::create(&path) {
-            Ok(file) => file,
-            Err(_) => panic!("Failed to open persistence file"),
-        };
-    
-        if let Err(_) = file.write_all(json.as_bytes()) {
-            eprintln!("Failed to write to persistence file"); 
+/// What's left of the memory a request may gather its results into, if
+/// it's limited at all.
+struct Budget(Option<usize>);
+
+impl Budget {
+    /// Counts `bytes` against the budget, returning false once that takes
+    /// it over.
+    fn charge(&mut self, bytes: usize) -> bool {
+        match &mut self.0 {
+            Some(remaining) => match remaining.checked_sub(bytes) {
+                Some(left) => {
+                    *remaining = left;
+                    true
+                }
+                None => false,
+            },
+            None => true,
         }
+    }
+}
 
-        println!("Successfully flushed data to disk");
+/// Reads a value as a number, accepting both JSON numbers and strings that
+/// hold one, since values set through the query string arrive as strings.
+fn numeric(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
     }
 }
 
-fn handle_request(request: Request, storage: &mut Storage) -> Response {
-    match request {
-        Request::Get(key) => {
-            if let Entry::Occupied(e) = storage.0.entry(key.clone()) {
-                let val = e.get();
+/// The SHA-256 of a value's compact JSON form. Since that's exactly the body
+/// of a JSON-encoded get with compact values, this matches the
+/// `X-Content-Hash` clients see there.
+fn content_hash(value: &Value) -> [u8; 32] {
+    Sha256::digest(value.to_string().as_bytes()).into()
+}
+
+/// Turns a computed number back into a value, as an integer when it is one.
+fn number_value(n: f64) -> Value {
+    // integers beyond 2^53 can't be told apart from their neighbours anyway
+    if n.fract() == 0.0 && n.abs() < 9_007_199_254_740_992.0 {
+        Value::from(n as i64)
+    } else {
+        Value::from(n)
+    }
+}
 
-                println!("GET: key={}, value={}", key, val);
+struct Storage {
+    data: HashMap<String, Record>,
+    persistence: Arc<dyn PersistenceBackend>,
+    /// Keys with a stale-while-revalidate refresh already in flight.
+    refreshing: HashSet<String>,
+    /// Share one copy of each distinct value between every key holding it.
+    dedup: bool,
+    /// The shared copy of each value in use, by the hash of its contents.
+    /// Entries die with the last record holding the value, and are swept
+    /// once enough of them have.
+    interned: HashMap<[u8; 32], Weak<Value>>,
+    /// Every key ever stored, for answering misses without the lock. Any
+    /// key going into `data` has to be added here first.
+    filter: Option<Arc<BloomFilter>>,
+    /// Flush to the persistence file when dropped. Off for stores that
+    /// should only ever live in memory.
+    persist_on_drop: bool,
+    /// Where every change is logged as it's made, if anywhere.
+    wal: Option<Arc<Wal>>,
+    /// The order keys were used in, when the store is capped at a number of
+    /// keys. Any key going into `data` has to be touched here.
+    lru: Option<Lru>,
+    /// How much the store takes up, when it's capped at a number of bytes.
+    footprint: Option<Footprint>,
+    /// Counts up with every snapshot taken of the store.
+    snapshots_taken: AtomicU64,
+    /// The newest snapshot of the store saved so far. It's held for the
+    /// whole save, so that overlapping flushes don't run into each other in
+    /// the backend and an older snapshot never replaces a newer one.
+    written: Arc<Mutex<u64>>,
+}
 
-                Response::GetSuccess(val.to_string())
-            } else {
-                println!("Failed to GET value for key={}", key);
-                
-                Response::NotFound
+impl Storage {
+    /// Notes that `key` has changed. Called after every change, with the
+    /// lock still held, so the log sees changes in the order they were made.
+    fn changed(&mut self, key: &str) {
+        self.account(key);
+        self.log(key);
+    }
+
+    /// Recounts what `key` takes up towards the store's byte limit.
+    fn account(&mut self, key: &str) {
+        if let Some(footprint) = &mut self.footprint {
+            footprint.update(key, self.data.get(key).map(|record| &*record.value));
+        }
+    }
+
+    /// Which of the store's limits writing `value` to `key` would break, if
+    /// any, by name.
+    fn over_limit(&self, key: &str, value: &Value, config: &ServerConfig) -> Option<&'static str> {
+        // an expired key still takes up its room until it's cleaned up
+        let adds_key = !self.data.contains_key(key);
+
+        if adds_key && config.store_limit_keys.is_some_and(|max| self.data.len() >= max) {
+            return Some("keys");
+        }
+
+        if let (Some(max), Some(footprint)) = (config.store_limit_bytes, &self.footprint) {
+            let others = footprint.bytes() - footprint.size_of(key);
+            let after = others + footprint::entry_size(key, value);
+
+            if after > max {
+                return Some("bytes");
             }
-        },
-        Request::Set(key, val) => {
-            match storage.0.entry(key.clone()) {
-                Entry::Occupied(o) => {
-                    // overwrite the current entry
-                    o.replace_entry(Value::from(val.clone()));
+        }
+
+        None
+    }
+
+    /// Appends what `key` holds now to the write-ahead log, if there is one.
+    fn log(&self, key: &str) {
+        let wal = match &self.wal {
+            Some(wal) => wal,
+            None => return,
+        };
+
+        let key = String::from(key);
+        let op = match self.data.get(&key) {
+            Some(record) => wal::Op::Put { key, record: record.clone() },
+            None => wal::Op::Remove { key },
+        };
+
+        if let Err(err) = wal.append(&op) {
+            error!("Failed to append to the write-ahead log: {}", err);
+        }
+    }
+
+    /// Removes `key`, noting the change whether or not it had expired: an
+    /// expired record still took up its room until now.
+    fn remove(&mut self, key: &str) -> Option<Record> {
+        let removed = self.data.remove(key);
+
+        if removed.is_some() {
+            self.changed(key);
+        }
+
+        removed
+    }
+
+    /// Stores a record, first noting its key in the bloom filter, and then
+    /// evicts whatever it pushed the store over its capacity.
+    fn insert(&mut self, key: String, record: Record) {
+        if let Some(filter) = &self.filter {
+            filter.insert(&key);
+        }
+
+        self.touch(&key);
+        self.data.insert(key.clone(), record);
+        self.changed(&key);
+        self.evict();
+    }
+
+    /// Stores `record` in place of whatever `key` holds, keeping the live
+    /// record's metadata, which describes the key rather than its value.
+    fn overwrite(&mut self, key: String, mut record: Record) {
+        let live = self.data.get_mut(&key).filter(|record| !record.is_expired(now()));
+
+        if let Some(current) = live {
+            record.meta = std::mem::take(&mut current.meta);
+        }
+
+        self.insert(key, record);
+    }
+
+    /// Adds `delta` to the number `key` holds, a missing key counting as
+    /// zero, and stores the result in place, keeping the key's expiry and
+    /// metadata. With a `floor`, the result goes no lower than it. Gives back
+    /// the new value and whether the floor held it up, or the response
+    /// refusing the change.
+    fn adjust(
+        &mut self,
+        key: &str,
+        delta: f64,
+        floor: Option<f64>,
+        config: &ServerConfig,
+    ) -> Result<(Value, bool), Response> {
+        let current = match self.data.get(key) {
+            Some(record) if !record.is_expired(now()) => match numeric(&record.value) {
+                Some(current) => current,
+                None => {
+                    debug!("Failed to adjust non-numeric value for key={}", key);
+
+                    let cause = format!("value for key={} is not a number", key);
+                    return Err(Response::BadRequest(Failure::new("not_a_number", cause)));
                 }
-                Entry::Vacant(v) => {
-                    v.insert(Value::from(val.clone()));
+            },
+            _ => 0.0,
+        };
+
+        let adjusted = current + delta;
+        let (result, floored) = match floor {
+            Some(floor) if adjusted < floor => (floor, true),
+            _ => (adjusted, false),
+        };
+
+        if !result.is_finite() {
+            debug!("Failed to adjust key={}: result is not finite", key);
+
+            return Err(Response::BadRequest(Failure::new("not_finite", ExprError::NotFinite)));
+        }
+
+        let result = number_value(result);
+
+        if let Some(limit) = self.over_limit(key, &result, config) {
+            debug!("Refusing to adjust key={}: the store is at its {} limit", key, limit);
+
+            return Err(Response::StoreFull(limit));
+        }
+
+        match self.data.get_mut(key).filter(|record| !record.is_expired(now())) {
+            Some(record) => {
+                record.update(result.clone());
+                self.touch(key);
+                self.changed(key);
+            }
+            None => {
+                let record = Record::with_ttl(result.clone(), config.ttl_for(key));
+                self.insert(String::from(key), record);
+            }
+        }
+
+        Ok((result, floored))
+    }
+
+    /// Marks `key` as just used, so it's the last to be evicted.
+    fn touch(&mut self, key: &str) {
+        if let Some(lru) = &mut self.lru {
+            lru.touch(key);
+        }
+    }
+
+    /// Evicts the least recently used keys until the store is back within
+    /// its capacity. Called after anything that may have added a key; the
+    /// key just added is the most recently used, so it's never the one to go.
+    fn evict(&mut self) {
+        let lru = match &mut self.lru {
+            Some(lru) => lru,
+            None => return,
+        };
+        let mut evicted = Vec::new();
+
+        while self.data.len() > lru.capacity() {
+            // keys that were removed since they were last used are skipped
+            match lru.pop_oldest() {
+                Some(key) => {
+                    if self.data.remove(&key).is_some() {
+                        evicted.push(key);
+                    }
                 }
+                None => break,
             }
-            
-            println!("SET: key={}, value={}", key, val);
+        }
 
-            Response::SetSuccess
+        if lru.len() > 2 * self.data.len() + LRU_SWEEP_SLACK {
+            let data = &self.data;
+            lru.retain(|key| data.contains_key(key));
+        }
+
+        lru.record_evictions(evicted.len());
+
+        for key in evicted {
+            debug!("EVICT: key={}", key);
+            self.changed(&key);
+        }
+    }
+
+    /// Wraps a value for storing, handing back the copy that's already
+    /// shared if some other key holds an identical value and deduplication
+    /// is on.
+    fn intern(&mut self, value: Value) -> Arc<Value> {
+        if !self.dedup {
+            return Arc::new(value);
+        }
+
+        let hash = content_hash(&value);
+
+        // the hash only finds the candidate; equality is what's trusted
+        if let Some(shared) = self.interned.get(&hash).and_then(Weak::upgrade) {
+            if *shared == value {
+                return shared;
+            }
+        }
+
+        if self.interned.len() > 2 * self.data.len() + INTERN_SWEEP_SLACK {
+            self.interned.retain(|_, shared| shared.strong_count() > 0);
+        }
+
+        let shared = Arc::new(value);
+        self.interned.insert(hash, Arc::downgrade(&shared));
+        shared
+    }
+
+    /// An empty store that treats values the way this one does, for trying
+    /// things out on. It shares none of this store's data, logs nothing and
+    /// is never saved.
+    fn scratch(&self) -> Storage {
+        Storage {
+            data: HashMap::new(),
+            persistence: Arc::clone(&self.persistence),
+            refreshing: HashSet::new(),
+            dedup: self.dedup,
+            interned: HashMap::new(),
+            filter: None,
+            persist_on_drop: false,
+            wal: None,
+            lru: None,
+            footprint: self.footprint.as_ref().map(|_| Footprint::default()),
+            snapshots_taken: AtomicU64::new(1),
+            written: Arc::new(Mutex::new(0)),
         }
     }
+
+    /// Parses a persistence snapshot. Snapshots written before records
+    /// carried any bookkeeping are plain maps of values, so those are still
+    /// accepted and treated as freshly written.
+    fn load(persisted: &str) -> Result<HashMap<String, Record>, serde_json::Error> {
+        serde_json::from_str(persisted).or_else(|_| {
+            serde_json::from_str::<HashMap<String, Value>>(persisted).map(|data| {
+                data.into_iter()
+                    .map(|(key, value)| (key, Record::new(value)))
+                    .collect()
+            })
+        })
+    }
+}
+
+/// The current time in seconds since the UNIX epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+/// Builds the store from what `backend` and the write-ahead log hold.
+fn load_storage(
+    config: &ServerConfig,
+    backend: Arc<dyn PersistenceBackend>,
+    metrics: &Arc<Metrics>,
+) -> Result<Storage, ServerError> {
+    // a snapshot that can't be parsed starts the store off empty
+    let mut persisted = match backend.load() {
+        Ok(persisted) => persisted,
+        Err(ServerError::SerdeError(_)) => HashMap::new(),
+        Err(err) => return Err(err),
+    };
+    // the log holds whatever changed after that snapshot was saved
+    let wal = match &config.wal_path {
+        Some(path) => Some(Arc::new(Wal::open(path.clone(), config.wal_fsync, &mut persisted)?)),
+        None => None,
+    };
+    let mut storage = Storage {
+        data: HashMap::new(),
+        persistence: backend,
+        refreshing: HashSet::new(),
+        dedup: config.dedup_values,
+        interned: HashMap::new(),
+        filter: config.bloom_filter_keys.map(|keys| Arc::new(BloomFilter::new(keys))),
+        persist_on_drop: true,
+        wal: None,
+        lru: config.max_entries.map(|capacity| Lru::new(capacity, Arc::clone(metrics))),
+        footprint: config.store_limit_bytes.map(|_| Footprint::default()),
+        snapshots_taken: AtomicU64::new(1),
+        written: Arc::new(Mutex::new(0)),
+    };
+
+    // values come off disk as separate copies, so share them up front; a
+    // snapshot holding more keys than the store is capped at is cut down to
+    // size as it goes in
+    for (key, mut record) in persisted {
+        record.value = storage.intern(Arc::unwrap_or_clone(record.value));
+        storage.insert(key, record);
+    }
+
+    // what was just loaded is already on disk, so it isn't logged again
+    storage.wal = wal;
+
+    Ok(storage)
 }
 
-fn send_response(response: Response, stream: &mut TcpStream) -> Result<(), ServerError> {
-    let (status_line, filename, rv) = match response {
-        Response::GetSuccess(val) => (SUCCESS_STATUS, "get_success.html", Some(val)),
-        Response::SetSuccess => (SUCCESS_STATUS, "set_success.html", None),
-        _ => (NOT_FOUND_STATUS, "404.html", None),
+pub fn server_init(config: ServerConfig) -> Result<()> {
+    let path = config.persist_path.clone().unwrap_or_else(|| PathBuf::from(PERSIST));
+    let backend = JsonFileBackend {
+        path,
+        backup_dir: config.backup_dir.clone(),
+        no_flush_if_empty: config.no_flush_if_empty,
+        threads: config.snapshot_threads.unwrap_or(1),
     };
 
-    let contents = fs::read_to_string(filename).map_err(|_| ServerError::NoResponseFound)?;
+    server_init_with_backend(config, Arc::new(backend))
+}
 
-    let response = if rv.is_some() {
-        format!("{}{}{}", status_line, contents, rv.unwrap())
-    } else {
-        format!("{}{}", status_line, contents)
+/// Like `server_init`, but loads and saves the store through `backend`
+/// instead of the JSON file the config names.
+pub fn server_init_with_backend(
+    config: ServerConfig,
+    backend: Arc<dyn PersistenceBackend>,
+) -> Result<()> {
+    // check everything up front, so a misconfigured server fails with the
+    // full list of problems instead of tripping over them one at a time
+    let mut problems = config.problems();
+    let address = config.address.clone().unwrap_or_else(|| String::from(ADDRESS));
+    let listener = match config.listen_fd {
+        _ if config.no_tcp => None,
+        Some(fd) if fd >= 0 => inherit_listener(fd)
+            .map_err(|err| problems.push(format!("could not use listener fd {}: {}", fd, err)))
+            .ok(),
+        // already reported as a problem
+        Some(_) => None,
+        None => TcpListener::bind(&address)
+            .map_err(|err| problems.push(format!("could not bind to {}: {}", address, err)))
+            .ok(),
+    };
+    let unix_listener = match &config.unix_socket {
+        Some(path) => bind_unix_socket(path)
+            .map_err(|err| problems.push(format!("could not bind to {}: {}", path.display(), err)))
+            .ok(),
+        None => None,
     };
 
-    stream.write_all(response.as_bytes())?;
-    stream.flush()?;
+    if !problems.is_empty() {
+        return Err(anyhow!(ServerError::InvalidConfig { problems }));
+    }
+
+    // the listeners are already bound, so clients that connect while the
+    // store loads are told it isn't ready rather than left hanging
+    let metrics = Arc::new(Metrics::default());
+    let loaded = AtomicBool::new(false);
+    let storage = thread::scope(|scope| {
+        let (loaded, config, metrics) = (&loaded, &config, &metrics);
+
+        if let Some(listener) = &listener {
+            scope.spawn(move || answer_loading(listener.incoming(), loaded, config));
+        }
+        if let Some(unix_listener) = &unix_listener {
+            scope.spawn(move || answer_loading(unix_listener.incoming(), loaded, config));
+        }
+
+        let storage = load_storage(config, backend, metrics);
+        loaded.store(true, Ordering::SeqCst);
+
+        // each responder is blocked on accept, and a connection of our own
+        // gets it to look at the flag
+        let tcp = listener.as_ref().and_then(|listener| listener.local_addr().ok()).map(Wake::Tcp);
+        let unix = unix_listener.as_ref().and(config.unix_socket.clone()).map(Wake::Unix);
+
+        for wake in tcp.iter().chain(&unix) {
+            if let Err(err) = wake.wake() {
+                error!("Failed to wake the listener: {}", err);
+            }
+        }
+
+        storage
+    })?;
+    let server = Arc::new(Server::new(config, storage, metrics));
+
+    let interval = server.config.persist_interval_secs.unwrap_or(DEFAULT_PERSIST_INTERVAL_SECS);
+    let storage = Arc::downgrade(&server.storage);
+    thread::spawn(move || persist_periodically(storage, Duration::from_secs(interval)));
+
+    if server.config.admin_cli {
+        let server = Arc::downgrade(&server);
+        thread::spawn(move || cli::run(server));
+    }
+
+    // declared after the server so that it's dropped first: every worker
+    // finishes and lets go of the server before the store flushes, once
+    let threads = server.config.threads.unwrap_or(DEFAULT_THREADS);
+    let pool = Arc::new(ThreadPool::new(threads));
+
+    // the signal wakes whichever listener the main thread is blocked on
+    let wake = match (&listener, &server.config.unix_socket) {
+        (Some(listener), _) => listener.local_addr().ok().map(Wake::Tcp),
+        (None, Some(path)) => Some(Wake::Unix(path.clone())),
+        (None, None) => None,
+    };
+    let shutdown = Arc::new(AtomicBool::new(false));
+
+    if let Err(err) = handle_interrupt(Arc::clone(&shutdown), wake) {
+        error!("Failed to install the Ctrl-C handler: {}", err);
+    }
+
+    if let Some(unix_listener) = unix_listener {
+        if let Some(path) = &server.config.unix_socket {
+            info!("Listening on {}...", path.display());
+        }
+
+        if listener.is_none() {
+            return Ok(accept(&server, &pool, unix_listener.incoming(), &shutdown)?);
+        }
+
+        let stopped = Arc::clone(&shutdown);
+
+        // the socket is served alongside TCP, which owns the server and the
+        // pool; holding them weakly lets both shut down with the TCP side
+        let (server, pool) = (Arc::downgrade(&server), Arc::downgrade(&pool));
+
+        thread::spawn(move || {
+            for stream in unix_listener.incoming() {
+                let (server, pool) = match (server.upgrade(), pool.upgrade()) {
+                    (Some(server), Some(pool)) if !stopped.load(Ordering::SeqCst) => (server, pool),
+                    _ => break,
+                };
+
+                match stream {
+                    Ok(stream) => dispatch(server, &pool, stream),
+                    Err(err) => {
+                        error!("Stopped serving the unix socket: {}", err);
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    if let Some(listener) = listener {
+        match listener.local_addr() {
+            Ok(addr) => info!("Listening on {}...", addr),
+            Err(_) => info!("Listening on {}...", address),
+        }
+
+        accept(&server, &pool, listener.incoming(), &shutdown)?;
+    }
 
     Ok(())
 }
 
-fn parse_get(request: &str) -> Result<String, ParseError> {
-    let parts: Vec<&str> = request.split("key=").collect();
+/// A stream connections are accepted as.
+trait Connection: Read + Write + Send + 'static {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
 
-    if parts.len() != 2 {
-        return Err(ParseError::InvalidRequest { code: 1 });
+    /// Who's on the other end, as it's given in logs.
+    fn peer(&self) -> String;
+}
+
+impl Connection for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
     }
 
-    let last_part = parts.last().unwrap();
+    fn peer(&self) -> String {
+        self.peer_addr()
+            .map_or_else(|_| String::from("unknown"), |addr| addr.ip().to_string())
+    }
+}
 
-    match last_part.split_whitespace().next() {
-        Some(key) => Ok(String::from(key)),
-        None => Err(ParseError::MissingKey),
+impl Connection for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    // a unix socket's peers are all on this machine, and unnamed
+    fn peer(&self) -> String {
+        String::from("unix")
     }
 }
 
-fn parse_set(request: &str) -> Result<(String, String), ParseError> {
-    let parts: Vec<&str> = request.split("set?").collect();
+/// How to reach the listener the main thread accepts on.
+enum Wake {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
 
-    if parts.len() != 2 {
-        return Err(ParseError::InvalidRequest { code: 2 });
+impl Wake {
+    /// Connects to the listener, so that an accept blocked on it returns.
+    fn wake(&self) -> io::Result<()> {
+        match self {
+            Wake::Tcp(addr) => TcpStream::connect(addr).map(drop),
+            Wake::Unix(path) => UnixStream::connect(path).map(drop),
+        }
     }
+}
 
-    let last_part = parts.last().unwrap();
+/// Installs a Ctrl-C handler that stops the server gracefully: it flags the
+/// shutdown and then connects to the listener so that the blocked accept
+/// returns and sees it. Once the accept loop ends, the server is dropped
+/// and the store flushes as it would on any other exit. A second Ctrl-C
+/// exits on the spot, without flushing.
+fn handle_interrupt(shutdown: Arc<AtomicBool>, wake: Option<Wake>) -> Result<(), ctrlc::Error> {
+    ctrlc::set_handler(move || {
+        if shutdown.swap(true, Ordering::SeqCst) {
+            warn!("Interrupted again, exiting without flushing");
+            process::exit(130);
+        }
 
-    match last_part.split_whitespace().next() {
-        Some(kv) => {
-            let kv: Vec<&str> = kv.split('=').collect();
+        info!("Shutting down, flushing...");
 
-            if kv.len() != 2 {
-                return Err(ParseError::InvalidRequest { code: 3 });
-            }
+        if let Err(err) = wake.as_ref().map_or(Ok(()), Wake::wake) {
+            error!("Failed to wake the listener: {}", err);
+        }
+    })
+}
+
+/// Answers every connection with a 503 until the store has `loaded`. The
+/// connection that's accepted once it has is answered too, since it may be a
+/// client's rather than the one sent to wake us.
+fn answer_loading<S: Connection>(
+    incoming: impl Iterator<Item = io::Result<S>>,
+    loaded: &AtomicBool,
+    config: &ServerConfig,
+) {
+    for stream in incoming {
+        if let Ok(mut stream) = stream {
+            // the request only has to be read so that closing the connection
+            // doesn't reset it before the client sees the response
+            let _ = stream.set_read_timeout(Some(LOADING_READ_TIMEOUT));
+            let _ = read_request(&mut stream, &mut Vec::new(), max_request_bytes(config));
+
+            let response = Response::Unavailable("loading");
+            let _ = send_response(response, &mut stream, Encoding::default(), true, config);
+        }
 
-            Ok((
-                String::from(*kv.first().unwrap()),
-                String::from(*kv.last().unwrap()),
-            ))
+        if loaded.load(Ordering::SeqCst) {
+            break;
         }
-        None => Err(ParseError::InvalidRequest { code: 4 }),
     }
 }
 
-fn parse_request(stream: &mut TcpStream) -> Result<Request, ServerError> {
-    let mut buffer = [0; BUFFER_SIZE];
-    stream.read(&mut buffer)?;
+/// Hands connections to the pool as they're accepted, until the server is
+/// shutting down or accepting one fails.
+fn accept<S: Connection>(
+    server: &Arc<Server>,
+    pool: &ThreadPool,
+    incoming: impl Iterator<Item = io::Result<S>>,
+    shutdown: &AtomicBool,
+) -> Result<(), ServerError> {
+    for stream in incoming {
+        // the connection that woke us up for the shutdown isn't served
+        if shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+
+        dispatch(Arc::clone(server), pool, stream?);
+    }
 
-    let request = String::from_utf8_lossy(&buffer[..]);
-    let request = request
-        .lines()
-        .take(1)
-        .next()
-        .ok_or(ServerError::NoRequestFound)?;
+    Ok(())
+}
 
-    if request.starts_with(GET_HEADER) {
-        // get the key from the request
-        let key = parse_get(request).map_err(|err| ServerError::ParseError {
+/// Queues a connection to be served by the next free worker.
+fn dispatch<S: Connection>(
+    server: Arc<Server>,
+    pool: &ThreadPool,
+    mut stream: S,
+) {
+    // slow start paces how fast connections are taken on, so it has to hold
+    // up the accepting thread rather than a worker
+    if let Some(slow_start) = &server.slow_start {
+        thread::sleep(slow_start.delay());
+    }
+
+    let received = Instant::now();
+
+    pool.execute(move || {
+        if let Err(err) = server.serve(&mut stream, received) {
+            warn!("Failed to serve connection: {:?}", err);
+        }
+    });
+}
+
+/// Takes over a TCP listener that was opened and bound by whoever started the
+/// server, such as the instance being replaced in a rolling restart.
+fn inherit_listener(fd: RawFd) -> io::Result<TcpListener> {
+    // SAFETY: the fd was handed to us to own, and nothing else in this
+    // process opens or closes it
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+
+    // a descriptor that isn't a bound socket fails here rather than on the
+    // first accept
+    listener.local_addr()?;
+
+    Ok(listener)
+}
+
+/// Binds a unix socket at `path`, first clearing away a socket file left
+/// behind by a server that didn't shut down cleanly. A socket that something
+/// is still listening on is left alone, and binding fails.
+fn bind_unix_socket(path: &Path) -> io::Result<UnixListener> {
+    if path.exists() && UnixStream::connect(path).is_err() {
+        info!("Removing stale socket at {}", path.display());
+        fs::remove_file(path)?;
+    }
+
+    UnixListener::bind(path)
+}
+
+/// Everything that handling a connection needs, shared between listeners.
+struct Server {
+    storage: Arc<Mutex<Storage>>,
+    /// The same filter the store keeps up to date, reachable without its lock.
+    filter: Option<Arc<BloomFilter>>,
+    metrics: Arc<Metrics>,
+    config: ServerConfig,
+    slow_start: Option<SlowStart>,
+    /// The most recent requests that were slow to handle.
+    slow_log: SlowLog,
+    /// Increments held back from the store, when they're coalesced.
+    coalescer: Option<Coalescer>,
+}
+
+impl Server {
+    /// Serves `storage` as `config` says, once it's been loaded.
+    fn new(config: ServerConfig, storage: Storage, metrics: Arc<Metrics>) -> Self {
+        Server {
+            filter: storage.filter.clone(),
+            storage: Arc::new(Mutex::new(storage)),
+            metrics,
+            // the ramp starts once the store has been loaded
+            slow_start: config.slow_start.map(SlowStart::new),
+            slow_log: SlowLog::new(config.slow_request_log.unwrap_or(slow_log::DEFAULT_CAPACITY)),
+            coalescer: config.coalesce_window.map(Coalescer::new),
+            config,
+        }
+    }
+
+    /// Writes any increments held back from the store into it.
+    fn write_back(&self, storage: &mut Storage) {
+        let coalescer = match &self.coalescer {
+            Some(coalescer) => coalescer,
+            None => return,
+        };
+
+        for (key, delta) in coalescer.drain() {
+            if storage.adjust(&key, delta, None, &self.config).is_err() {
+                warn!("Dropped increments held back for key={}", key);
+            }
+        }
+    }
+
+    /// Serves requests from the stream until it's closed: after the first
+    /// one unless keep-alive is configured, otherwise once the client asks
+    /// for it, goes idle, or reaches the per-connection maximum. Fails if a
+    /// request can't be made sense of or a response can't be sent.
+    ///
+    /// A connection's requests are handled one at a time, in order, on the
+    /// same worker, and each is done with the store before its response is
+    /// sent. So a client always reads its own writes from earlier on the
+    /// connection, including requests it pipelined.
+    fn serve<S: Connection>(&self, stream: &mut S, received: Instant) -> Result<(), ServerError> {
+        let max_requests = self.config.keepalive_max_requests.unwrap_or(1);
+        let mut pending = Vec::new();
+        let peer = stream.peer();
+
+        for served in 0..max_requests {
+            let received = if served == 0 {
+                Some(received)
+            } else {
+                // an idle connection mustn't hold on to a worker forever
+                stream.set_read_timeout(Some(KEEPALIVE_IDLE_TIMEOUT))?;
+                None
+            };
+
+            let last = served + 1 == max_requests;
+
+            match self.serve_request(stream, &mut pending, received, last, &peer) {
+                Ok(true) => {}
+                Ok(false) => break,
+                // the client hung up or went quiet between requests
+                Err(ServerError::NoRequestFound) if received.is_none() => break,
+                Err(ServerError::IoError(err))
+                    if received.is_none()
+                        && matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) =>
+                {
+                    break
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Logs a request that took over the slow request threshold to handle,
+    /// counting from when it was `received`, and keeps it for `/trace-slow`.
+    fn note_if_slow(&self, operation: &'static str, key: Option<String>, received: Instant) {
+        let elapsed = received.elapsed();
+        let threshold = match self.config.slow_request_ms {
+            Some(ms) => Duration::from_millis(ms),
+            None => slow_log::DEFAULT_THRESHOLD,
+        };
+
+        if elapsed < threshold {
+            return;
+        }
+
+        warn!(
+            "Slow request operation={} key={} took {:?}",
+            operation,
+            key.as_deref().unwrap_or("-"),
+            elapsed
+        );
+
+        self.slow_log.record(SlowRequest {
+            operation,
+            key,
+            duration_ms: elapsed.as_secs_f64() * 1_000.0,
+            at: now(),
+        });
+    }
+
+    /// Reads one request from the stream, handles it and writes the response
+    /// back, telling the client the connection closes if `last` is set.
+    /// `received` is when the request came in, if that's known before it's
+    /// read; otherwise it's taken to be once it has been read. Returns whether
+    /// the connection can be kept open for another request. `peer` is who
+    /// sent it, for logging rejections.
+    fn serve_request<S: Read + Write>(
+        &self,
+        stream: &mut S,
+        pending: &mut Vec<u8>,
+        received: Option<Instant>,
+        last: bool,
+        peer: &str,
+    ) -> Result<bool, ServerError> {
+        let config = &self.config;
+
+        match parse_request(stream, pending, config.duplicate_params, max_request_bytes(config)) {
+            Ok((request, headers)) => {
+                let received = received.unwrap_or_else(Instant::now);
+                let close = last || headers.get(CONNECTION_HEADER) == Some("close");
+
+                // scope the connection to its own slice of the key space
+                let request = match headers.get(KEY_PREFIX_HEADER) {
+                    Some(prefix) if !prefix.is_empty() => request.with_key_prefix(prefix),
+                    _ => request,
+                };
+
+                let deadline = headers.deadline(received);
+                let authorized = match &config.basic_auth {
+                    Some(auth) => auth.verify(headers.get(AUTHORIZATION_HEADER)),
+                    None => true,
+                };
+
+                let encoding = Encoding::negotiate(headers.get(ACCEPT_HEADER));
+
+                let key_too_long =
+                    request.keys().iter().find_map(|key| check_key(key, config).err());
+
+                let response = if !authorized {
+                    let message = "Rejected request with missing or invalid credentials";
+                    self.reject(peer, "auth_failure", message);
+
+                    Response::Unauthorized
+                } else if let Some(err) = key_too_long {
+                    self.reject(peer, "key_too_long", &format!("Rejected request: {}", err));
+
+                    Response::BadRequest(Failure::new("key_too_long", err))
+                } else if request.is_admin() && config.basic_auth.is_none() {
+                    let message = "Rejected admin request on a server without credentials";
+                    self.reject(peer, "admin_disabled", message);
+
+                    Response::Forbidden
+                } else if !config.allows(request.operation()) {
+                    let message = format!("Rejected disabled operation {}", request.operation());
+                    self.reject(peer, "operation_disabled", &message);
+
+                    Response::Forbidden
+                } else if encoding.is_none() && request.returns_value() {
+                    // refuse before handling, so that a getdel doesn't remove
+                    // a value the client could never have received
+                    let message = "Rejected request with no supported encoding";
+                    self.reject(peer, "not_acceptable", message);
+
+                    Response::NotAcceptable
+                } else {
+                    let (operation, key) = (request.operation(), request.key().map(String::from));
+                    let response = handle_request(request, self, deadline);
+                    self.note_if_slow(operation, key, received);
+
+                    response
+                };
+
+                let encoding = encoding.unwrap_or_default();
+
+                // the status line, headers and body each go out in their own
+                // write, so buffering gathers them into as few syscalls as
+                // fit; send_response flushes at the end of the response
+                match config.write_buffer_bytes {
+                    Some(capacity) => {
+                        let mut stream = BufWriter::with_capacity(capacity, stream);
+                        send_response(response, &mut stream, encoding, close, config)?;
+                    }
+                    None => send_response(response, stream, encoding, close, config)?,
+                }
+
+                Ok(!close)
+            }
+            Err(err @ ServerError::InvalidRequest) => {
+                self.reject(peer, "invalid_request", "Rejected unrecognized request");
+
+                let response = Response::Malformed(err.to_string());
+                send_response(response, stream, Encoding::default(), true, config)?;
+                Ok(false)
+            }
+            Err(ServerError::ParseError { reason }) => {
+                self.reject(peer, "parse_error", &format!("Rejected request: {}", reason));
+
+                send_response(Response::Malformed(reason), stream, Encoding::default(), true, config)?;
+                Ok(false)
+            }
+            Err(err @ ServerError::RequestTooLarge { .. }) => {
+                self.reject(peer, "too_large", &format!("Rejected request: {}", err));
+
+                // the rest of the request is still unread, so the connection
+                // can't be reused
+                send_response(Response::TooLarge, stream, Encoding::default(), true, config)?;
+                Ok(false)
+            }
+            Err(err @ ServerError::DuplicateParam { .. }) => {
+                self.reject(peer, "duplicate_param", &format!("Rejected request: {}", err));
+
+                let response = Response::BadRequest(Failure::new("duplicate_param", err));
+                send_response(response, stream, Encoding::default(), true, config)?;
+                Ok(false)
+            }
+            Err(err @ ServerError::InvalidBody) => {
+                self.reject(peer, "invalid_body", &format!("Rejected request: {}", err));
+
+                let response = Response::BadRequest(Failure::new("invalid_body", err));
+                send_response(response, stream, Encoding::default(), true, config)?;
+                Ok(false)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Notes a request that was turned away. With `log_rejected` set it's
+    /// logged as a warning giving the client and a one-word reason, so that
+    /// monitoring can match on it; otherwise it's just the message.
+    fn reject(&self, peer: &str, reason: &str, message: &str) {
+        if self.config.log_rejected {
+            warn!("rejected client={} reason={}: {}", peer, reason, message);
+        } else {
+            info!("{}", message);
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        // held back increments have to reach the store before it's flushed
+        self.write_back(&mut lock(&self.storage));
+    }
+}
+
+impl Drop for Storage {
+    fn drop(&mut self) {
+        if !self.persist_on_drop {
+            return;
+        }
+
+        if let Err(err) = self.flush() {
+            error!("Failed to flush data to disk: {}", err);
+        }
+    }
+}
+
+impl Storage {
+    /// Saves the contents of the store through its persistence backend.
+    fn flush(&self) -> Result<(), ServerError> {
+        save(&*self.persistence, &self.written, &self.snapshot())
+    }
+
+    fn snapshot(&self) -> Snapshot<'_> {
+        Snapshot {
+            data: Cow::Borrowed(&self.data),
+            taken: self.snapshots_taken.fetch_add(1, Ordering::SeqCst),
+            logged: self.wal.as_ref().map(|wal| (Arc::clone(wal), wal.position())),
+        }
+    }
+}
+
+/// Copies the records out of a shared store, holding its lock only that
+/// long; values are shared rather than copied, so that's quick next to
+/// serializing them. Along with them comes what `save` needs to write them.
+fn detach(
+    shared: &Mutex<Storage>,
+) -> (Snapshot<'static>, Arc<dyn PersistenceBackend>, Arc<Mutex<u64>>) {
+    let storage = lock(shared);
+    let snapshot = storage.snapshot();
+    let detached = Snapshot {
+        data: Cow::Owned(snapshot.data.into_owned()),
+        taken: snapshot.taken,
+        logged: snapshot.logged,
+    };
+
+    (detached, storage.persistence.clone(), Arc::clone(&storage.written))
+}
+
+/// Flushes a shared store without holding its lock while writing.
+fn flush_shared(shared: &Mutex<Storage>) -> Result<(), ServerError> {
+    let (snapshot, persistence, written) = detach(shared);
+    save(&*persistence, &written, &snapshot)
+}
+
+/// Flushes the store every `interval` for as long as it's around, so that a
+/// crash only loses the writes since the last flush.
+fn persist_periodically(storage: Weak<Mutex<Storage>>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+
+        // the store is let go of before writing, so that this thread is
+        // never the one left holding it when the server shuts down
+        let (snapshot, persistence, written) = match storage.upgrade() {
+            Some(shared) => detach(&shared),
+            None => break,
+        };
+
+        if let Err(err) = save(&*persistence, &written, &snapshot) {
+            error!("Failed to flush data to disk: {}", err);
+        }
+    }
+}
+
+/// The store's records as of one moment.
+struct Snapshot<'a> {
+    data: Cow<'a, HashMap<String, Record>>,
+    /// Counts up with every snapshot taken, so that a write that comes late
+    /// can tell a newer snapshot has already been written.
+    taken: u64,
+    /// The write-ahead log and how far into it the snapshot goes, so the
+    /// changes it holds can be dropped from the log once it's saved.
+    logged: Option<(Arc<Wal>, u64)>,
+}
+
+/// Saves a snapshot through `backend`, unless `written` says a newer one
+/// already has been.
+fn save(
+    backend: &dyn PersistenceBackend,
+    written: &Mutex<u64>,
+    snapshot: &Snapshot,
+) -> Result<(), ServerError> {
+    info!("Flushing data to disk...");
+
+    let mut written = written.lock().unwrap_or_else(PoisonError::into_inner);
+
+    if *written > snapshot.taken {
+        info!("Skipping flush: a newer snapshot is already on disk");
+        return Ok(());
+    }
+
+    backend.save(&snapshot.data)?;
+    *written = snapshot.taken;
+
+    // a log that isn't cut back only means replaying changes the snapshot
+    // already has, so failing here doesn't fail the flush
+    if let Some((wal, position)) = &snapshot.logged {
+        if let Err(err) = wal.truncate(*position) {
+            error!("Failed to truncate the write-ahead log: {}", err);
+        }
+    }
+
+    Ok(())
+}
+
+/// Locks the store. A panic while the lock was held can't leave the map
+/// itself half-updated, so a poisoned lock is simply taken over.
+fn lock(storage: &Mutex<Storage>) -> MutexGuard<'_, Storage> {
+    storage.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn handle_request(request: Request, server: &Server, deadline: Instant) -> Response {
+    let (shared, metrics, config) = (&server.storage, &server.metrics, &server.config);
+
+    if Instant::now() >= deadline {
+        // the client's budget ran out before we got to its request
+        debug!("Deadline exceeded before handling request");
+        metrics.record_timeout();
+
+        return Response::Timeout;
+    }
+
+    // a key the filter has never seen is a miss, and with nothing upstream
+    // to read it through from there's no need to wait for the lock to say so
+    if let (Request::Get(key, _), Some(filter), None) =
+        (&request, &server.filter, &config.upstream)
+    {
+        if !filter.may_contain(key) {
+            debug!("Failed to GET value for key={}", key);
+            metrics.record_get();
+            metrics.record_miss();
+
+            return Response::NotFound;
+        }
+    }
+
+    // an increment to a counter already being coalesced needn't wait for
+    // the lock either
+    if let (Request::Incr { key, by }, Some(coalescer)) = (&request, &server.coalescer) {
+        if let Some(value) = coalescer.incr(key, *by, now()) {
+            debug!("INCR: key={}, by={}, value={} (coalesced)", key, by, value);
+            metrics.record_set();
+            metrics.record_coalesced();
+
+            return Response::GetSuccess(Arc::new(number_value(value)));
+        }
+    }
+
+    let mut guard = lock(shared);
+    let storage = &mut *guard;
+    let mut budget = Budget(config.request_memory_bytes);
+
+    // whatever the request is, it sees the increments held back so far
+    server.write_back(storage);
+
+    match request {
+        Request::Get(key, options) => {
+            metrics.record_get();
+
+            if let Some(record) = storage.data.get(&key) {
+                let val = Arc::clone(&record.value);
+
+                if record.is_expired(now()) {
+                    if let (true, Some(upstream)) = (options.swr, &config.upstream) {
+                        debug!("GET: serving stale key={}, value={}", key, val);
+
+                        if storage.refreshing.insert(key.clone()) {
+                            revalidate(upstream.clone(), key, Arc::downgrade(shared), config);
+                        }
+
+                        return Response::GetSuccess(val);
+                    }
+
+                    // expired values are cleaned up the next time they're read
+                    storage.remove(&key);
+                } else {
+                    debug!("GET: key={}, value={}", key, val);
+                    storage.touch(&key);
+
+                    return Response::GetSuccess(val);
+                }
+            }
+
+            metrics.record_miss();
+
+            match &config.upstream {
+                Some(upstream) => {
+                    // nobody else should have to wait on the store while
+                    // the upstream takes its time
+                    drop(guard);
+                    read_through(upstream, key, shared, config, deadline)
+                }
+                None => {
+                    debug!("Failed to GET value for key={}", key);
+
+                    Response::NotFound
+                }
+            }
+        },
+        Request::Set(key, val, options) => {
+            metrics.record_set();
+
+            let mut val = if options.trim {
+                String::from(val.trim())
+            } else {
+                val
+            };
+
+            let max = config.max_value_bytes.unwrap_or(DEFAULT_MAX_VALUE_BYTES);
+            let truncated = if val.len() <= max {
+                None
+            } else {
+                match config.oversized_values {
+                    OversizedValues::Reject => {
+                        debug!("Refusing to SET key={}: value is over {} bytes", key, max);
+
+                        return Response::TooLarge;
+                    }
+                    OversizedValues::Truncate => {
+                        // cut back to a character boundary, so what's kept
+                        // is still valid text
+                        let end = (0..=max).rev().find(|&end| val.is_char_boundary(end)).unwrap_or(0);
+                        val.truncate(end);
+
+                        Some(end)
+                    }
+                }
+            };
+
+            let value = typed_value(&val);
+
+            if let Some(refused) = refuse_set(storage, &key, &value, &options, config) {
+                return refused;
+            }
+
+            if let (Some(upstream), Some(mode)) = (&config.upstream, config.write_through) {
+                guard = match write_through(upstream, mode, &key, &val, deadline, guard, shared) {
+                    Ok(guard) => guard,
+                    Err(err) => {
+                        warn!("Failed to write key={} through to upstream: {}", key, err);
+
+                        return Response::BadGateway(Failure::new("write_through_failed", err));
+                    }
+                };
+
+                // the store may have changed while it was let go of
+                if let Some(refused) = refuse_set(&guard, &key, &value, &options, config) {
+                    return refused;
+                }
+            }
+
+            let storage = &mut *guard;
+            let ttl_secs = options.ttl_secs.or_else(|| config.ttl_for(&key));
+            let shared = storage.intern(value);
+
+            storage.overwrite(key.clone(), Record::with_ttl(shared, ttl_secs));
+            debug!("SET: key={}, value={}", key, val);
+
+            match truncated {
+                Some(len) => Response::SetTruncated(len),
+                None => Response::SetSuccess,
+            }
+        }
+        Request::GetDel(key) => {
+            metrics.record_getdel();
+
+            // the lookup and the removal happen in one step, so two consumers
+            // can never both walk away with the same value
+            let record = storage.remove(&key).filter(|record| !record.is_expired(now()));
+
+            if let Some(Record { value: val, .. }) = record {
+                debug!("GETDEL: key={}, value={}", key, val);
+
+                Response::GetSuccess(val)
+            } else {
+                debug!("Failed to GETDEL value for key={}", key);
+                metrics.record_miss();
+
+                Response::NotFound
+            }
+        }
+        Request::Delete(key) => {
+            metrics.record_delete();
+
+            // an expired key is already gone as far as clients can tell
+            match storage.remove(&key).filter(|record| !record.is_expired(now())) {
+                Some(_) => {
+                    debug!("DELETE: key={}", key);
+
+                    Response::DeleteSuccess
+                }
+                None => {
+                    debug!("Failed to DELETE missing key={}", key);
+                    metrics.record_miss();
+
+                    Response::NotFound
+                }
+            }
+        }
+        Request::Eval { key, expr } => {
+            let record = match storage.data.get_mut(&key) {
+                Some(record) if !record.is_expired(now()) => record,
+                _ => {
+                    debug!("Failed to EVAL missing key={}", key);
+                    metrics.record_miss();
+
+                    return Response::NotFound;
+                }
+            };
+
+            let current = match numeric(&record.value) {
+                Some(current) => current,
+                None => {
+                    debug!("Failed to EVAL non-numeric value for key={}", key);
+
+                    let cause = format!("value for key={} is not a number", key);
+                    return Response::BadRequest(Failure::new("not_a_number", cause));
+                }
+            };
+
+            match expr::eval(&expr, current) {
+                Ok(result) => {
+                    let result = number_value(result);
+                    record.update(result.clone());
+                    storage.changed(&key);
+
+                    debug!("EVAL: key={}, expr={}, value={}", key, expr, result);
+
+                    Response::GetSuccess(result.into())
+                }
+                Err(err) => {
+                    debug!("Failed to EVAL expr={} for key={}: {}", expr, key, err);
+
+                    Response::BadRequest(Failure::new("invalid_expr", err))
+                }
+            }
+        }
+        Request::Render { key, vars } => {
+            let template = match storage.data.get(&key) {
+                Some(record) if !record.is_expired(now()) => match &*record.value {
+                    Value::String(template) => template,
+                    _ => {
+                        debug!("Failed to RENDER non-string value for key={}", key);
+
+                        let cause = format!("value for key={} is not a string", key);
+                        return Response::BadRequest(Failure::new("not_a_template", cause));
+                    }
+                },
+                _ => {
+                    debug!("Failed to RENDER missing key={}", key);
+                    metrics.record_miss();
+
+                    return Response::NotFound;
+                }
+            };
+
+            match template::render(template, &vars) {
+                Ok(rendered) => {
+                    metrics.record_get();
+                    debug!("RENDER: key={}, value={}", key, rendered);
+
+                    Response::GetSuccess(Arc::new(Value::String(rendered)))
+                }
+                Err(err) => {
+                    debug!("Failed to RENDER key={}: {}", key, err);
+
+                    let code = match err {
+                        TemplateError::MissingVar { .. } => "missing_placeholder",
+                        TemplateError::Unclosed { .. } => "invalid_template",
+                    };
+                    Response::BadRequest(Failure::new(code, err))
+                }
+            }
+        }
+        Request::Decr { key, by, floor } => match storage.adjust(&key, -by, floor, config) {
+            Ok((result, floored)) => {
+                metrics.record_set();
+                debug!("DECR: key={}, by={}, value={}, floored={}", key, by, result, floored);
+
+                Response::Json(serde_json::json!({
+                    "value": result,
+                    "floored": floored,
+                }))
+            }
+            Err(refused) => refused,
+        },
+        Request::Incr { key, by } => match storage.adjust(&key, by, None, config) {
+            Ok((result, _)) => {
+                metrics.record_set();
+                debug!("INCR: key={}, by={}, value={}", key, by, result);
+
+                if let (Some(coalescer), Some(value)) = (&server.coalescer, numeric(&result)) {
+                    let expires = storage.data.get(&key).and_then(|record| record.expires);
+                    coalescer.hold(key, value, expires);
+                }
+
+                Response::GetSuccess(result.into())
+            }
+            Err(refused) => refused,
+        },
+        Request::SetMeta { key, field, value } => match storage.data.get_mut(&key) {
+            Some(record) if !record.is_expired(now()) => {
+                debug!("SETMETA: key={}, field={}, value={}", key, field, value);
+                record.meta.insert(field, value);
+                storage.changed(&key);
+
+                Response::SetSuccess
+            }
+            _ => {
+                debug!("Failed to SETMETA missing key={}", key);
+
+                Response::NotFound
+            }
+        },
+        Request::GetMeta { key, field } => {
+            let value = storage
+                .data
+                .get(&key)
+                .filter(|record| !record.is_expired(now()))
+                .and_then(|record| record.meta.get(&field));
+
+            match value {
+                Some(value) => {
+                    debug!("GETMETA: key={}, field={}, value={}", key, field, value);
+
+                    Response::GetSuccess(Arc::new(Value::from(value.as_str())))
+                }
+                None => {
+                    debug!("Failed to GETMETA field={} for key={}", field, key);
+
+                    Response::NotFound
+                }
+            }
+        }
+        Request::Benchmark { ops, keys } => {
+            if !config.benchmark_enabled {
+                debug!("Refusing to BENCHMARK with benchmarks disabled");
+
+                return Response::Forbidden;
+            }
+
+            // the benchmark gets a store of its own, so the real one is
+            // neither held up by it nor left holding its keys
+            let mut scratch = storage.scratch();
+            drop(guard);
+
+            let report = benchmark::run(&mut scratch, ops, keys);
+
+            debug!("BENCHMARK: {:?}", report);
+
+            match serde_json::to_value(report) {
+                Ok(report) => Response::Json(report),
+                Err(err) => {
+                    error!("Failed to serialize benchmark report: {}", err);
+
+                    Response::BadRequest(Failure::new("benchmark_failed", err))
+                }
+            }
+        }
+        Request::ResetMetrics => {
+            let snapshot = metrics.reset();
+
+            debug!("METRICS RESET: {:?}", snapshot);
+
+            Response::Metrics(snapshot)
+        }
+        Request::TraceSlow => Response::Json(serde_json::json!(server.slow_log.recent())),
+        Request::Stats => {
+            let now = now();
+            let live = storage.data.iter().filter(|(_, record)| !record.is_expired(now));
+            let (mut keys, mut bytes, mut deduplicated_bytes) = (0, 0, 0);
+            let mut counted = HashSet::new();
+
+            for (key, record) in live {
+                let (key_bytes, value_bytes) = footprint::entry_sizes(key, &record.value);
+                keys += 1;
+                bytes += key_bytes + value_bytes;
+                deduplicated_bytes += key_bytes;
+
+                // records sharing an interned value point at the same one
+                if counted.insert(Arc::as_ptr(&record.value)) {
+                    deduplicated_bytes += value_bytes;
+                }
+            }
+
+            Response::Stats(Stats {
+                keys,
+                bytes,
+                deduplicated_bytes,
+                counters: metrics.snapshot(),
+            })
+        }
+        Request::FlushNamespace(namespace) => {
+            let prefix = format!("{}{}", namespace, NAMESPACE_DELIMITER);
+            let doomed: Vec<String> = storage
+                .data
+                .keys()
+                .filter(|key| key.starts_with(&prefix))
+                .cloned()
+                .collect();
+
+            // expired keys go too, but only the live ones count as removed
+            let now = now();
+            let removed = doomed
+                .iter()
+                .filter_map(|key| storage.remove(key))
+                .filter(|record| !record.is_expired(now))
+                .count();
+
+            if removed == 0 {
+                debug!("Failed to FLUSH missing namespace={}", namespace);
+
+                Response::NotFound
+            } else {
+                debug!("FLUSH: namespace={}, removed={}", namespace, removed);
+
+                Response::NamespaceFlushed(removed)
+            }
+        }
+        Request::Diff(path) => diff_snapshot(&path, storage, config),
+        Request::InferSchema { prefix, sample, scope } => {
+            let full_prefix = format!("{}{}", scope, prefix);
+            let now = now();
+
+            let values = storage
+                .data
+                .iter()
+                .filter(|(key, record)| key.starts_with(&full_prefix) && !record.is_expired(now))
+                .map(|(_, record)| &*record.value)
+                .take(sample);
+            let schema = schema::infer(values);
+
+            debug!("INFER SCHEMA: prefix={}, sampled={}", prefix, schema.sampled);
+
+            match serde_json::to_value(schema) {
+                Ok(schema) => Response::Json(schema),
+                Err(err) => {
+                    error!("Failed to serialize inferred schema: {}", err);
+
+                    Response::BadRequest(Failure::new("infer_schema_failed", err))
+                }
+            }
+        }
+        Request::Move { key, from_ns, to_ns, nx } => {
+            let source = format!("{}{}{}", from_ns, NAMESPACE_DELIMITER, key);
+            let target = format!("{}{}{}", to_ns, NAMESPACE_DELIMITER, key);
+            let now = now();
+
+            if storage.data.get(&source).is_none_or(|record| record.is_expired(now)) {
+                debug!("Failed to MOVE missing key={}", source);
+                metrics.record_miss();
+
+                return Response::NotFound;
+            }
+
+            if nx {
+                let existing = storage.data.get(&target).filter(|record| !record.is_expired(now));
+
+                if let Some(existing) = existing {
+                    debug!("Refusing to MOVE key={} over existing key={}", source, target);
+
+                    return Response::Conflict(Some(Arc::clone(&existing.value)));
+                }
+            }
+
+            // both halves happen under the one lock, so no reader ever sees
+            // the key in both namespaces or in neither
+            if let Some(record) = storage.remove(&source) {
+                storage.insert(target.clone(), record);
+            }
+
+            debug!("MOVE: key={} to key={}", source, target);
+
+            Response::SetSuccess
+        }
+        Request::Favicon => Response::NoContent,
+        // still ramping up after startup counts as not ready, so traffic
+        // isn't sent over before the server can take all of it
+        Request::Ready => match &server.slow_start {
+            Some(slow_start) if slow_start.ramping() => Response::Unavailable("warming_up"),
+            _ => Response::Json(serde_json::json!({ "status": "ready" })),
+        },
+        Request::GetOrCreate { key, default } => {
+            metrics.record_get();
+
+            if let Some(record) = storage.data.get(&key).filter(|record| !record.is_expired(now())) {
+                let val = Arc::clone(&record.value);
+
+                debug!("GETORCREATE: key={}, value={}", key, val);
+                storage.touch(&key);
+
+                return Response::GetSuccess(val);
+            }
+
+            // the default is never cut down, since the caller is counting on
+            // getting back exactly what it asked for
+            if default.len() > config.max_value_bytes.unwrap_or(DEFAULT_MAX_VALUE_BYTES) {
+                debug!("Refusing to GETORCREATE key={}: value is too large", key);
+
+                return Response::TooLarge;
+            }
+
+            let value = typed_value(&default);
+
+            if let Some(limit) = storage.over_limit(&key, &value, config) {
+                debug!("Refusing to GETORCREATE key={}: the store is at its {} limit", key, limit);
+
+                return Response::StoreFull(limit);
+            }
+
+            // the check and the write happen under the one lock, so callers
+            // racing on an absent key all come away with the same value
+            let shared = storage.intern(value);
+            let record = Record::with_ttl(Arc::clone(&shared), config.ttl_for(&key));
+            storage.insert(key.clone(), record);
+
+            metrics.record_miss();
+            metrics.record_set();
+            debug!("GETORCREATE: created key={}, value={}", key, shared);
+
+            Response::GetSuccess(shared)
+        }
+        Request::Cas { key, expected, new } => {
+            metrics.record_set();
+
+            // like a getorcreate, the new value is never cut down, since the
+            // caller will go on to expect exactly it
+            if new.len() > config.max_value_bytes.unwrap_or(DEFAULT_MAX_VALUE_BYTES) {
+                debug!("Refusing to CAS key={}: value is too large", key);
+
+                return Response::TooLarge;
+            }
+
+            let value = typed_value(&new);
+
+            if let Some(refused) = refuse_cas(storage, &key, expected.as_deref(), &value, config) {
+                return refused;
+            }
+
+            if let (Some(upstream), Some(mode)) = (&config.upstream, config.write_through) {
+                guard = match write_through(upstream, mode, &key, &new, deadline, guard, shared) {
+                    Ok(guard) => guard,
+                    Err(err) => {
+                        warn!("Failed to write key={} through to upstream: {}", key, err);
+
+                        return Response::BadGateway(Failure::new("write_through_failed", err));
+                    }
+                };
+
+                // another client may have swapped the value out meanwhile
+                if let Some(refused) = refuse_cas(&guard, &key, expected.as_deref(), &value, config) {
+                    return refused;
+                }
+            }
+
+            // the check and the write happen under the one lock, so of two
+            // clients swapping out the same value only one gets to
+            let storage = &mut *guard;
+            let shared = storage.intern(value);
+
+            storage.overwrite(key.clone(), Record::with_ttl(shared, config.ttl_for(&key)));
+            debug!("CAS: key={}, value={}", key, new);
+
+            Response::SetSuccess
+        }
+        Request::Persist(key) => {
+            let removed = match storage.data.get_mut(&key) {
+                Some(record) if !record.is_expired(now()) => record.expires.take().is_some(),
+                _ => {
+                    debug!("Failed to PERSIST missing key={}", key);
+                    metrics.record_miss();
+
+                    return Response::NotFound;
+                }
+            };
+
+            if removed {
+                storage.changed(&key);
+            }
+
+            debug!("PERSIST: key={}, removed_ttl={}", key, removed);
+
+            Response::Json(serde_json::json!({ "removed_ttl": removed }))
+        }
+        Request::Exists(key) => {
+            let exists = storage.data.get(&key).is_some_and(|record| !record.is_expired(now()));
+
+            debug!("EXISTS: key={}, exists={}", key, exists);
+
+            Response::Json(Value::Bool(exists))
+        }
+        Request::List { prefix, scope } => {
+            let full_prefix = format!("{}{}", scope, prefix);
+            let now = now();
+
+            let mut keys: Vec<&str> = Vec::new();
+
+            for (key, record) in &storage.data {
+                if !key.starts_with(&full_prefix) || record.is_expired(now) {
+                    continue;
+                }
+
+                if !budget.charge(key.len()) {
+                    debug!("Aborting KEYS: results are over the request memory budget");
+
+                    return Response::InsufficientStorage;
+                }
+
+                keys.push(&key[scope.len()..]);
+            }
+
+            keys.sort_unstable();
+
+            debug!("KEYS: prefix={}, found={}", prefix, keys.len());
+
+            Response::Json(Value::from(keys))
+        }
+        Request::MultiGet { keys, scope } => {
+            let now = now();
+            let mut values = Vec::with_capacity(keys.len());
+
+            for key in keys {
+                metrics.record_get();
+
+                let value = storage
+                    .data
+                    .get(&format!("{}{}", scope, key))
+                    .filter(|record| !record.is_expired(now))
+                    .map(|record| Arc::clone(&record.value));
+
+                if value.is_none() {
+                    metrics.record_miss();
+                }
+
+                let size = value.as_deref().map_or(0, estimated_size);
+
+                if !budget.charge(key.len() + size) {
+                    debug!("Aborting MGET: results are over the request memory budget");
+
+                    return Response::InsufficientStorage;
+                }
+
+                values.push((key, value));
+            }
+
+            let found = values.iter().filter(|(_, value)| value.is_some()).count();
+            debug!("MGET: keys={}, found={}", values.len(), found);
+
+            Response::MultiGet(values)
+        }
+        Request::CountByPrefix { delimiter, scope } => {
+            let now = now();
+            let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+
+            for (key, record) in &storage.data {
+                let key = match key.strip_prefix(&scope) {
+                    Some(key) if !record.is_expired(now) => key,
+                    _ => continue,
+                };
+
+                // a key without the delimiter is a group of its own
+                let group = key.split_once(&delimiter).map_or(key, |(group, _)| group);
+
+                match counts.get_mut(group) {
+                    Some(count) => *count += 1,
+                    None => {
+                        if !budget.charge(group.len()) {
+                            debug!("Aborting COUNT-BY-PREFIX: over the request memory budget");
+
+                            return Response::InsufficientStorage;
+                        }
+
+                        counts.insert(group, 1);
+                    }
+                }
+            }
+
+            debug!("COUNT-BY-PREFIX: delimiter={}, groups={}", delimiter, counts.len());
+
+            Response::Json(serde_json::json!(counts))
+        }
+        Request::Explain(line) => Response::Json(explain(&line, config.duplicate_params)),
+        Request::Trace(head) => {
+            if !config.trace_enabled {
+                debug!("Refusing to TRACE with tracing disabled");
+
+                return Response::Forbidden;
+            }
+
+            debug!("TRACE: {} bytes", head.len());
+
+            Response::Trace(head)
+        }
+        Request::Expired { scope } => {
+            // expired keys are only reaped when they're next read, so these
+            // are the ones nothing has touched since they expired
+            let now = now();
+            let mut expired: Vec<(&str, u64)> = storage
+                .data
+                .iter()
+                .filter(|(_, record)| record.is_expired(now))
+                .filter_map(|(key, record)| Some((key.strip_prefix(scope.as_str())?, record.expires?)))
+                .collect();
+
+            expired.sort_unstable();
+
+            debug!("DEBUG EXPIRED: count={}", expired.len());
+
+            let expired: Vec<Value> = expired
+                .into_iter()
+                .map(|(key, expires)| serde_json::json!({ "key": key, "expired": expires }))
+                .collect();
+
+            Response::Json(Value::from(expired))
+        }
+        Request::SnapshotSubset { path, selection } => {
+            snapshot_subset(&path, &selection, storage, config)
+        }
+        Request::Export => {
+            // the same serialization the flush uses, so an export can be
+            // dropped in as a persistence file and imported back as it is
+            let threads = config.snapshot_threads.unwrap_or(1);
+
+            match snapshot::serialize(&storage.data, threads) {
+                Ok(json) => {
+                    debug!("EXPORT: keys={}", storage.data.len());
+
+                    Response::Export(json)
+                }
+                Err(err) => {
+                    error!("Failed to serialize the store for export: {}", err);
+
+                    Response::BadRequest(Failure::new("export_failed", err))
+                }
+            }
+        }
+        Request::Import { body, replace } => import(&body, replace, storage, config),
+        Request::Scan(options) => scan(options, storage, metrics, deadline, budget),
+        Request::Changed { since, values, scope } => {
+            let now = now();
+            let mut changed = Vec::new();
+
+            for (key, record) in &storage.data {
+                let key = match key.strip_prefix(scope.as_str()) {
+                    Some(key) => key,
+                    None => continue,
+                };
+
+                if Instant::now() >= deadline {
+                    debug!("Deadline exceeded while collecting changed keys");
+                    metrics.record_timeout();
+
+                    return Response::Timeout;
+                }
+
+                if record.modified > since && !record.is_expired(now) {
+                    let size = if values { key.len() + estimated_size(&record.value) } else { key.len() };
+
+                    if !budget.charge(size) {
+                        debug!("Aborting CHANGED: results are over the request memory budget");
+
+                        return Response::InsufficientStorage;
+                    }
+
+                    changed.push((key, record));
+                }
+            }
+
+            changed.sort_by_key(|(key, _)| *key);
+
+            debug!("CHANGED: since={}, count={}", since, changed.len());
+
+            if values {
+                let changed: Map<String, Value> = changed
+                    .into_iter()
+                    .map(|(key, record)| (String::from(key), Value::clone(&record.value)))
+                    .collect();
+
+                Response::Json(Value::Object(changed))
+            } else {
+                let changed = changed.into_iter().map(|(key, _)| Value::from(key)).collect();
+
+                Response::Json(Value::Array(changed))
+            }
+        }
+    }
+}
+
+/// Why a set of `value` to `key` can't go ahead against the store as it is,
+/// if there's a reason: a precondition it was given doesn't hold, or it
+/// would take the store past a limit.
+fn refuse_set(
+    storage: &Storage,
+    key: &str,
+    value: &Value,
+    options: &SetOptions,
+    config: &ServerConfig,
+) -> Option<Response> {
+    let current = storage
+        .data
+        .get(key)
+        .filter(|record| !record.is_expired(now()))
+        .map(|record| &record.value);
+
+    if let Some(expected) = &options.if_hash {
+        let current_hash = current.map(|current| hex_encode(&content_hash(current)));
+
+        if current_hash.as_ref() != Some(expected) {
+            debug!("Refusing to SET key={}: content hash isn't {}", key, expected);
+
+            return Some(Response::Conflict(current.cloned()));
+        }
+    }
+
+    if let Some(json_type) = options.if_type {
+        // an absent key has no type to protect
+        if current.is_some_and(|current| !json_type.matches(current)) {
+            debug!("Refusing to SET key={}: current value isn't {:?}", key, json_type);
+
+            return Some(Response::Conflict(current.cloned()));
+        }
+    }
+
+    if let Some(limit) = storage.over_limit(key, value, config) {
+        debug!("Refusing to SET key={}: the store is at its {} limit", key, limit);
+
+        return Some(Response::StoreFull(limit));
+    }
+
+    None
+}
+
+/// Why swapping `key` from `expected` to `value` can't go ahead against the
+/// store as it is, if there's a reason.
+fn refuse_cas(
+    storage: &Storage,
+    key: &str,
+    expected: Option<&str>,
+    value: &Value,
+    config: &ServerConfig,
+) -> Option<Response> {
+    let current = storage
+        .data
+        .get(key)
+        .filter(|record| !record.is_expired(now()))
+        .map(|record| &record.value);
+
+    // the expected value is read the way a set would have stored it, but a
+    // plain string match counts too, so that "1" still matches a value that
+    // was stored as the string "1"
+    let matches = match (current, expected) {
+        (None, None) => true,
+        (Some(current), Some(expected)) => {
+            **current == typed_value(expected) || current.as_str() == Some(expected)
+        }
+        _ => false,
+    };
+
+    if !matches {
+        debug!("Refusing to CAS key={}: current value isn't the expected one", key);
+
+        return Some(Response::Conflict(current.cloned()));
+    }
+
+    if let Some(limit) = storage.over_limit(key, value, config) {
+        debug!("Refusing to CAS key={}: the store is at its {} limit", key, limit);
+
+        return Some(Response::StoreFull(limit));
+    }
+
+    None
+}
+
+/// Returns one batch of a scan over the store: the first `count` live keys,
+/// in key order, that come after the cursor. Each batch is its own request,
+/// so the store is only locked while a single batch is gathered and writes
+/// carry on between batches. Because the cursor is the last key returned, a
+/// scan never returns a key twice, but keys added behind the cursor while a
+/// scan is underway are missed, and values may change between batches.
+fn scan(
+    options: ScanOptions,
+    storage: &Storage,
+    metrics: &Metrics,
+    deadline: Instant,
+    mut budget: Budget,
+) -> Response {
+    let now = now();
+    let full_prefix = format!("{}{}", options.scope, options.prefix);
+    let after = options.after.as_ref().map(|after| format!("{}{}", options.scope, after));
+
+    // keep only the `count` smallest keys past the cursor as we go, so a
+    // batch costs one pass over the store no matter how big it is
+    let mut batch: BinaryHeap<&String> = BinaryHeap::with_capacity(options.count + 1);
+
+    for (key, record) in &storage.data {
+        if Instant::now() >= deadline {
+            debug!("Deadline exceeded while scanning");
+            metrics.record_timeout();
+
+            return Response::Timeout;
+        }
+
+        if !key.starts_with(&full_prefix)
+            || after.as_ref().is_some_and(|after| key <= after)
+            || record.is_expired(now)
+        {
+            continue;
+        }
+
+        batch.push(key);
+
+        if batch.len() > options.count {
+            batch.pop();
+        }
+    }
+
+    let batch = batch.into_sorted_vec();
+    let done = batch.len() < options.count;
+    let scope_len = options.scope.len();
+
+    let mut entries = Map::new();
+
+    for key in &batch {
+        let value = &storage.data[*key].value;
+
+        if !budget.charge(key.len() + estimated_size(value)) {
+            debug!("Aborting SCAN: results are over the request memory budget");
+
+            return Response::InsufficientStorage;
+        }
+
+        entries.insert(key[scope_len..].to_string(), Value::clone(value));
+    }
+
+    let cursor = match batch.last() {
+        Some(last) if !done => hex_encode(&last.as_bytes()[scope_len..]),
+        _ => String::new(),
+    };
+
+    debug!("SCAN: prefix={}, count={}", options.prefix, entries.len());
+
+    Response::Json(serde_json::json!({
+        "cursor": cursor,
+        "entries": entries,
+    }))
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hex_decode(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Describes how the server would parse and route a request line, or why it
+/// would refuse it, without running it.
+fn explain(line: &str, duplicates: DuplicateParams) -> Value {
+    let mut parts = line.split_whitespace();
+    let mut explanation = Map::new();
+
+    explanation.insert(String::from("method"), serde_json::json!(parts.next()));
+    explanation.insert(String::from("path"), serde_json::json!(parts.next()));
+
+    // only the request line is explained, so there's no more to its head
+    // and nothing in its body
+    match route(line, line, "", duplicates) {
+        Ok(request) => {
+            explanation.insert(String::from("handler"), request.operation().into());
+            explanation.insert(String::from("key"), serde_json::json!(request.key()));
+
+            // a POST /set's value is its body, which isn't explained
+            if let Request::Set(_, val, _) = &request {
+                if !line.starts_with(POST_SET_HEADER) {
+                    explanation.insert(String::from("value"), val.as_str().into());
+                }
+            }
+        }
+        Err(err) => {
+            explanation.insert(String::from("error"), err.to_string().into());
+        }
+    }
+
+    debug!("EXPLAIN: {}", line);
+
+    Value::Object(explanation)
+}
+
+/// Compares the live store against the snapshot at `path`, which has to live
+/// inside the configured snapshot directory. Keys are reported as added when
+/// only the live store has them, removed when only the snapshot does, and
+/// changed when both have them with different values.
+fn diff_snapshot(path: &str, storage: &Storage, config: &ServerConfig) -> Response {
+    let path = match &config.snapshot_dir {
+        Some(dir) => match resolve_within(dir, path) {
+            Some(path) => path,
+            None => {
+                debug!("Refusing to DIFF path={} outside the snapshot directory", path);
+
+                return Response::Forbidden;
+            }
+        },
+        None => {
+            debug!("Refusing to DIFF without a configured snapshot directory");
+
+            return Response::Forbidden;
+        }
+    };
+
+    let snapshot = match fs::read_to_string(&path) {
+        Ok(persisted) => persisted,
+        Err(err) => {
+            debug!("Failed to read snapshot at {}: {}", path.display(), err);
+
+            return Response::NotFound;
+        }
+    };
+
+    let snapshot = match Storage::load(&snapshot) {
+        Ok(snapshot) => snapshot,
+        Err(err) => {
+            debug!("Failed to parse snapshot at {}: {}", path.display(), err);
+
+            return Response::BadRequest(Failure::new("invalid_snapshot", err));
+        }
+    };
+
+    let now = now();
+    let live: HashMap<&String, &Value> = storage
+        .data
+        .iter()
+        .filter(|(_, record)| !record.is_expired(now))
+        .map(|(key, record)| (key, &*record.value))
+        .collect();
+
+    let mut added: Vec<&str> = Vec::new();
+    let mut changed: Vec<&str> = Vec::new();
+
+    for (key, value) in &live {
+        match snapshot.get(*key) {
+            None => added.push(key),
+            Some(record) if *record.value != **value => changed.push(key),
+            Some(_) => {}
+        }
+    }
+
+    let mut removed: Vec<&str> = snapshot
+        .keys()
+        .filter(|key| !live.contains_key(key))
+        .map(String::as_str)
+        .collect();
+
+    added.sort_unstable();
+    removed.sort_unstable();
+    changed.sort_unstable();
+
+    debug!(
+        "DIFF: path={}, added={}, removed={}, changed={}",
+        path.display(),
+        added.len(),
+        removed.len(),
+        changed.len()
+    );
+
+    Response::Json(serde_json::json!({
+        "added": added,
+        "removed": removed,
+        "changed": changed,
+    }))
+}
+
+/// Writes the selected live keys to a file of their own in the snapshot
+/// directory, in the same format as the persistence file so it can be
+/// diffed or loaded later. Only a bare file name is accepted, so the file
+/// can't land anywhere else.
+fn snapshot_subset(path: &str, selection: &Selection, storage: &Storage, config: &ServerConfig) -> Response {
+    let dir = match &config.snapshot_dir {
+        Some(dir) => dir,
+        None => {
+            debug!("Refusing to SNAPSHOT without a configured snapshot directory");
+
+            return Response::Forbidden;
+        }
+    };
+
+    let mut components = Path::new(path).components();
+    let target = match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None) => dir.join(name),
+        _ => {
+            debug!("Refusing to SNAPSHOT to path={} outside the snapshot directory", path);
+
+            return Response::Forbidden;
+        }
+    };
+
+    let now = now();
+    let live = |key: &&String| storage.data.get(*key).is_some_and(|record| !record.is_expired(now));
+    let subset: HashMap<&String, &Record> = match selection {
+        Selection::Keys(keys) => keys
+            .iter()
+            .filter(live)
+            .map(|key| (key, &storage.data[key]))
+            .collect(),
+        Selection::Prefix(prefix) => storage
+            .data
+            .iter()
+            .filter(|(key, record)| key.starts_with(prefix.as_str()) && !record.is_expired(now))
+            .collect(),
+    };
+
+    // write next to the target and rename over it, so a reader never sees a
+    // half-written snapshot
+    let tmp = target.with_extension("json.tmp");
+    let written = serde_json::to_vec(&subset)
+        .map_err(io::Error::from)
+        .and_then(|json| fs::write(&tmp, json))
+        .and_then(|()| fs::rename(&tmp, &target));
+
+    if let Err(err) = written {
+        error!("Failed to write snapshot to {}: {}", target.display(), err);
+
+        return Response::BadRequest(Failure::new("snapshot_failed", err));
+    }
+
+    debug!("SNAPSHOT: path={}, count={}", target.display(), subset.len());
+
+    Response::Json(serde_json::json!({
+        "path": path,
+        "written": subset.len(),
+    }))
+}
+
+/// Resolves `path` relative to `dir`, returning it only if the file exists
+/// and doesn't escape `dir` through `..` components or symlinks.
+fn resolve_within(dir: &Path, path: &str) -> Option<PathBuf> {
+    let dir = dir.canonicalize().ok()?;
+    let resolved = dir.join(path).canonicalize().ok()?;
+
+    if resolved.starts_with(&dir) {
+        Some(resolved)
+    } else {
+        None
+    }
+}
+
+/// Refreshes an expired key from the upstream on its own thread, replacing
+/// the stale value once the fetch completes. If the upstream no longer has
+/// the key the stale value is dropped; if the fetch fails it's left in place
+/// so that a later request can retry.
+fn revalidate(upstream: Upstream, key: String, shared: Weak<Mutex<Storage>>, config: &ServerConfig) {
+    let ttl_secs = config.ttl_for(&key);
+    let max_value = config.max_value_bytes.unwrap_or(DEFAULT_MAX_VALUE_BYTES);
+
+    thread::spawn(move || {
+        let fetched = upstream.fetch(&key, DEFAULT_TIMEOUT, max_value);
+
+        // the store is only held weakly, so that a slow fetch can't keep it
+        // from being dropped and flushed at shutdown
+        let shared = match shared.upgrade() {
+            Some(shared) => shared,
+            None => {
+                debug!("REVALIDATE: dropping key={}, the store is gone", key);
+                return;
+            }
+        };
+        let mut storage = lock(&shared);
+
+        storage.refreshing.remove(&key);
+
+        match fetched {
+            Ok(Some(val)) => {
+                debug!("REVALIDATE: key={}, value={}", key, val);
+
+                // the upstream's body is read the way a set's value is
+                let val = storage.intern(typed_value(&val));
+                storage.insert(key, Record::with_ttl(val, ttl_secs));
+            }
+            Ok(None) => {
+                debug!("REVALIDATE: key={} is gone from upstream", key);
+
+                storage.remove(&key);
+            }
+            Err(err) => warn!("Failed to revalidate key={} from upstream: {}", key, err),
+        }
+    });
+}
+
+/// Loads an export into the store. The body is read the way a persistence
+/// file is, so it has to be a JSON object; each key it holds overwrites the
+/// stored one, and with `replace` every key it doesn't hold is removed. An
+/// entry a set of the same key and value would be refused is left out, and
+/// reported back under `rejected` with the reason.
+fn import(body: &str, replace: bool, storage: &mut Storage, config: &ServerConfig) -> Response {
+    let mut imported = match Storage::load(body) {
+        Ok(imported) => imported,
+        Err(err) => {
+            debug!("Refusing to IMPORT: body isn't a JSON object of keys");
+
+            return Response::BadRequest(Failure::new("invalid_import", err));
+        }
+    };
+
+    let mut removed = 0;
+
+    if replace {
+        let gone: Vec<String> =
+            storage.data.keys().filter(|key| !imported.contains_key(*key)).cloned().collect();
+
+        for key in gone {
+            storage.remove(&key);
+            removed += 1;
+        }
+    }
+
+    let max_value = config.max_value_bytes.unwrap_or(DEFAULT_MAX_VALUE_BYTES);
+    let mut rejected = Map::new();
+    let mut count = 0;
+
+    // in key order, so that which entries a store limit turns away doesn't
+    // depend on how the map happens to iterate
+    let mut keys: Vec<String> = imported.keys().cloned().collect();
+    keys.sort_unstable();
+
+    for key in keys {
+        let mut record = imported.remove(&key).unwrap();
+
+        let refused = if check_key(&key, config).is_err() {
+            Some("key_too_long")
+        } else if value_len(&record.value) > max_value {
+            Some("too_large")
+        } else {
+            storage.over_limit(&key, &record.value, config).map(|_| "store_full")
+        };
+
+        if let Some(reason) = refused {
+            debug!("Refusing to IMPORT key={}: {}", key, reason);
+            rejected.insert(key, reason.into());
+
+            continue;
+        }
+
+        record.value = storage.intern(Arc::unwrap_or_clone(record.value));
+        storage.insert(key, record);
+        count += 1;
+    }
+
+    debug!("IMPORT: imported={}, removed={}, rejected={}", count, removed, rejected.len());
+
+    Response::Json(serde_json::json!({ "imported": count, "removed": removed, "rejected": rejected }))
+}
+
+/// Fetches a key that isn't stored locally from the upstream, storing what
+/// comes back with the key's default TTL. The store is only locked once the
+/// fetch is done, and a value set in the meantime wins over the fetched one.
+fn read_through(
+    upstream: &Upstream,
+    key: String,
+    shared: &Mutex<Storage>,
+    config: &ServerConfig,
+    deadline: Instant,
+) -> Response {
+    let timeout = deadline.saturating_duration_since(Instant::now());
+    let max_value = config.max_value_bytes.unwrap_or(DEFAULT_MAX_VALUE_BYTES);
+
+    match upstream.fetch(&key, timeout, max_value) {
+        Ok(Some(val)) => {
+            debug!("READ-THROUGH: key={}, value={}", key, val);
+
+            let mut storage = lock(shared);
+
+            if let Some(record) = storage.data.get(&key).filter(|record| !record.is_expired(now())) {
+                return Response::GetSuccess(Arc::clone(&record.value));
+            }
+
+            // the upstream's body is read the way a set's value is
+            let val = storage.intern(typed_value(&val));
+            let record = Record::with_ttl(Arc::clone(&val), config.ttl_for(&key));
+            storage.insert(key, record);
+
+            Response::GetSuccess(val)
+        }
+        Ok(None) => {
+            debug!("Failed to GET value for key={} from upstream", key);
+
+            Response::NotFound
+        }
+        Err(err) => {
+            warn!("Failed to read key={} through from upstream: {}", key, err);
+
+            if config.upstream_bad_gateway {
+                Response::BadGateway(Failure::new("upstream_failed", err))
+            } else {
+                Response::NotFound
+            }
+        }
+    }
+}
+
+/// Forwards a set to the upstream. In sync mode this waits for the upstream
+/// to confirm the write, letting go of the store's lock while it does and
+/// handing back the lock taken afresh; in async mode the write happens on its
+/// own thread and failures are only logged.
+fn write_through<'a>(
+    upstream: &Upstream,
+    mode: WriteThrough,
+    key: &str,
+    val: &str,
+    deadline: Instant,
+    storage: MutexGuard<'a, Storage>,
+    shared: &'a Mutex<Storage>,
+) -> Result<MutexGuard<'a, Storage>, ServerError> {
+    match mode {
+        WriteThrough::Sync => {
+            drop(storage);
+
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            upstream.store(key, val, timeout)?;
+
+            Ok(lock(shared))
+        }
+        WriteThrough::Async => {
+            let (upstream, key, val) = (upstream.clone(), String::from(key), String::from(val));
+
+            thread::spawn(move || {
+                if let Err(err) = upstream.store(&key, &val, DEFAULT_TIMEOUT) {
+                    warn!("Failed to write key={} through to upstream: {}", key, err);
+                }
+            });
+
+            Ok(storage)
+        }
+    }
+}
+
+/// Writes a response, with a `Connection: close` header if `close` is set.
+fn send_response(
+    response: Response,
+    stream: &mut impl Write,
+    encoding: Encoding,
+    close: bool,
+    config: &ServerConfig,
+) -> Result<(), ServerError> {
+    let hash_body = config.content_hash && matches!(response, Response::GetSuccess(_));
+    let no_content = matches!(response, Response::NoContent);
+
+    let (status_line, body): (Cow<str>, Vec<u8>) = match response {
+        Response::Metrics(snapshot) => (SUCCESS_STATUS.into(), serde_json::to_vec(&snapshot)?),
+        Response::Stats(stats) => (SUCCESS_STATUS.into(), serde_json::to_vec(&stats)?),
+        Response::Export(json) => (SUCCESS_STATUS.into(), json.into_bytes()),
+        Response::NamespaceFlushed(removed) => (
+            SUCCESS_STATUS.into(),
+            serde_json::json!({ "removed": removed }).to_string().into_bytes(),
+        ),
+        Response::Json(json) => (SUCCESS_STATUS.into(), json.to_string().into_bytes()),
+        Response::MultiGet(values) => {
+            (SUCCESS_STATUS.into(), serde_json::to_vec(&InOrder(&values))?)
+        }
+        Response::Trace(head) => (TRACE_SUCCESS_STATUS.into(), head.into_bytes()),
+        Response::GetSuccess(val) => {
+            let (content_type, body) = encoding.encode(&val, config.value_format)?;
+            let status_line = format!("HTTP/1.1 200 OK\r\nContent-Type: {}\r\n\r\n", content_type);
+
+            (status_line.into(), body)
+        }
+        Response::SetSuccess | Response::DeleteSuccess => (
+            SUCCESS_STATUS.into(),
+            serde_json::json!({ "status": "ok" }).to_string().into_bytes(),
+        ),
+        Response::NoContent => (NO_CONTENT_STATUS.into(), Vec::new()),
+        Response::SetTruncated(len) => {
+            let warning = format!("Warning: 199 - \"value truncated to {} bytes\"", len);
+
+            (
+                with_header(SUCCESS_STATUS, &warning).into(),
+                serde_json::json!({ "status": "ok", "truncated": len }).to_string().into_bytes(),
+            )
+        }
+        Response::NotAcceptable => (NOT_ACCEPTABLE_STATUS.into(), error_body("not_acceptable")),
+        Response::TooLarge => (TOO_LARGE_STATUS.into(), error_body("too_large")),
+        Response::Conflict(current) if config.conflict_returns_value => (
+            CONFLICT_STATUS.into(),
+            serde_json::json!({ "current": current }).to_string().into_bytes(),
+        ),
+        Response::Conflict(_) => (CONFLICT_STATUS.into(), error_body("conflict")),
+        Response::BadRequest(failure) => (
+            BAD_REQUEST_STATUS.into(),
+            failure.to_json(config.error_verbosity).to_string().into_bytes(),
+        ),
+        Response::Malformed(message) => (
+            BAD_REQUEST_STATUS.into(),
+            serde_json::json!({ "error": "malformed_request", "message": message })
+                .to_string()
+                .into_bytes(),
+        ),
+        Response::Unauthorized => (UNAUTHORIZED_STATUS.into(), error_body("unauthorized")),
+        Response::StoreFull(limit) => (
+            INSUFFICIENT_STORAGE_STATUS.into(),
+            serde_json::json!({ "error": "store_full", "limit": limit }).to_string().into_bytes(),
+        ),
+        Response::Unavailable(reason) => (SERVICE_UNAVAILABLE_STATUS.into(), error_body(reason)),
+        Response::Forbidden => (FORBIDDEN_STATUS.into(), error_body("forbidden")),
+        Response::BadGateway(failure) => (
+            BAD_GATEWAY_STATUS.into(),
+            failure.to_json(config.error_verbosity).to_string().into_bytes(),
+        ),
+        Response::Timeout => (TIMEOUT_STATUS.into(), error_body("timeout")),
+        Response::InsufficientStorage => {
+            (INSUFFICIENT_STORAGE_STATUS.into(), error_body("insufficient_storage"))
+        }
+        Response::NotFound => (NOT_FOUND_STATUS.into(), error_body("not_found")),
+    };
+
+    let (status_line, body) = match config.max_response_bytes {
+        Some(max) if body.len() > max => {
+            debug!("Response of {} bytes exceeds the {} byte limit", body.len(), max);
+
+            (TOO_LARGE_STATUS.into(), error_body("too_large"))
+        }
+        _ if hash_body => {
+            let hash = hex_encode(&Sha256::digest(&body));
+            let header = format!("{}: {}", CONTENT_HASH_HEADER, hash);
+
+            (with_header(&status_line, &header).into(), body)
+        }
+        _ => (status_line, body),
+    };
+
+    // the length lets a client that keeps the connection open find where
+    // the response ends; a 204 has no body, so mustn't give one
+    let mut status_line = if no_content {
+        status_line.into_owned()
+    } else {
+        with_header(&status_line, &format!("Content-Length: {}", body.len()))
+    };
+
+    if close {
+        status_line = with_header(&status_line, "Connection: close");
+    }
+
+    stream.write_all(status_line.as_bytes())?;
+    stream.write_all(&body)?;
+    stream.flush()?;
+
+    Ok(())
+}
+
+/// Adds a header line to a status line and header block that's already
+/// terminated by the blank line ending the headers.
+fn with_header(status_line: &str, header: &str) -> String {
+    let head = status_line.strip_suffix("\r\n").unwrap_or(status_line);
+    format!("{}{}\r\n\r\n", head, header)
+}
+
+/// Key/value pairs that serialize as an object with its members in the
+/// order given, which a `Map` would sort.
+struct InOrder<'a>(&'a [(String, Option<Arc<Value>>)]);
+
+impl Serialize for InOrder<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+
+        for (key, value) in self.0 {
+            map.serialize_entry(key, value)?;
+        }
+
+        map.end()
+    }
+}
+
+/// The body of an error response that has nothing to say beyond its code.
+fn error_body(code: &str) -> Vec<u8> {
+    serde_json::json!({ "error": code }).to_string().into_bytes()
+}
+
+fn parse_get(request: &str) -> Result<(String, GetOptions), ParseError> {
+    let parts: Vec<&str> = request.split("key=").collect();
+
+    if parts.len() != 2 {
+        return Err(ParseError::InvalidRequest { code: 1 });
+    }
+
+    let last_part = parts.last().unwrap();
+
+    match last_part.split_whitespace().next() {
+        Some(query) => {
+            let mut params = query.split('&');
+            let key = params.next().unwrap();
+
+            if key.is_empty() {
+                return Err(ParseError::MissingKey);
+            }
+
+            let mut options = GetOptions::default();
+
+            for param in params {
+                match param.split_once('=') {
+                    Some(("swr", flag)) => {
+                        options.swr = flag
+                            .parse()
+                            .map_err(|_| ParseError::InvalidRequest { code: 5 })?;
+                    }
+                    _ => return Err(ParseError::InvalidRequest { code: 6 }),
+                }
+            }
+
+            Ok((decode(key)?, options))
+        }
+        None => Err(ParseError::MissingKey),
+    }
+}
+
+fn parse_set(request: &str) -> Result<(String, String, SetOptions), ParseError> {
+    let query = request
+        .split_whitespace()
+        .nth(1)
+        .and_then(|target| target.split_once('?'))
+        .map(|(_, query)| query)
+        .ok_or(ParseError::InvalidRequest { code: 4 })?;
+
+    // an empty parameter, as a trailing `&` leaves, says nothing
+    let mut params = query.split('&').filter(|param| !param.is_empty());
+
+    // only the first `=` separates the key from the value, so the value may
+    // hold more of them, as base64 padding does
+    let (key, val) = match params.next().and_then(|kv| kv.split_once('=')) {
+        Some((key, val)) if !key.is_empty() => (key, val),
+        _ => return Err(ParseError::InvalidRequest { code: 3 }),
+    };
+
+    let mut options = SetOptions::default();
+
+    for param in params {
+        parse_set_option(&mut options, param)?;
+    }
+
+    // split on the raw text first, so that an encoded `=` or `&` ends up in
+    // the key or value rather than splitting them
+    Ok((decode(key)?, decode(val)?, options))
+}
+
+/// Parses a `POST /set`, which names the key as `key=` in the query and
+/// carries the value as the request body, so it isn't bound by what fits in
+/// a URL. The body is taken as it is, with no decoding.
+fn parse_post_set(request: &str, body: &str) -> Result<(String, String, SetOptions), ParseError> {
+    let query = request
+        .split_whitespace()
+        .nth(1)
+        .and_then(|target| target.split_once('?'))
+        .map(|(_, query)| query)
+        .ok_or(ParseError::InvalidRequest { code: 4 })?;
+
+    let mut key = None;
+    let mut options = SetOptions::default();
+
+    for param in query.split('&').filter(|param| !param.is_empty()) {
+        match param.split_once('=') {
+            Some(("key", name)) if !name.is_empty() => key = Some(decode(name)?),
+            Some(("key", _)) => return Err(ParseError::MissingKey),
+            _ => parse_set_option(&mut options, param)?,
+        }
+    }
+
+    let key = key.ok_or(ParseError::MissingKey)?;
+    Ok((key, String::from(body), options))
+}
+
+/// Applies one of the options a set takes after its key and value.
+fn parse_set_option(options: &mut SetOptions, param: &str) -> Result<(), ParseError> {
+    match param.split_once('=') {
+        Some(("trim", flag)) => {
+            options.trim = flag
+                .parse()
+                .map_err(|_| ParseError::InvalidRequest { code: 5 })?;
+        }
+        Some(("if_hash", hash)) => options.if_hash = Some(hash.to_ascii_lowercase()),
+        Some(("ttl", secs)) => {
+            let secs = secs
+                .parse()
+                .ok()
+                .filter(|&secs| secs > 0)
+                .ok_or(ParseError::InvalidRequest { code: 21 })?;
+            options.ttl_secs = Some(secs);
+        }
+        Some(("if_type", name)) => {
+            let json_type = JsonType::parse(name).ok_or(ParseError::InvalidRequest { code: 19 })?;
+            options.if_type = Some(json_type);
+        }
+        _ => return Err(ParseError::InvalidRequest { code: 6 }),
+    }
+
+    Ok(())
+}
+
+fn parse_delete(request: &str) -> Result<String, ParseError> {
+    let parts: Vec<&str> = request.split("key=").collect();
+
+    if parts.len() != 2 {
+        return Err(ParseError::InvalidRequest { code: 1 });
+    }
+
+    let last_part = parts.last().unwrap();
+
+    match last_part.split_whitespace().next() {
+        Some(key) if key.contains('&') => Err(ParseError::InvalidRequest { code: 6 }),
+        Some(key) if !key.is_empty() => decode(key),
+        _ => Err(ParseError::MissingKey),
+    }
+}
+
+/// Settles repeated query parameters according to `policy`, giving back a
+/// request line that names each parameter at most once. The leading
+/// `key=value` pair of a set is positional rather than named, so it's always
+/// kept as it is.
+fn dedupe_params(request: &str, policy: DuplicateParams) -> Result<Cow<'_, str>, ServerError> {
+    let mut parts = request.splitn(3, ' ');
+    let (method, target, rest) = (parts.next(), parts.next(), parts.next());
+
+    let (path, query) = match target.and_then(|target| target.split_once('?')) {
+        Some(split) => split,
+        None => return Ok(Cow::Borrowed(request)),
+    };
+
+    let mut pairs: Vec<&str> = query.split('&').collect();
+    let fixed = if path == "/set" && !pairs.is_empty() {
+        vec![pairs.remove(0)]
+    } else {
+        Vec::new()
+    };
+
+    let name = |pair: &str| pair.split('=').next().unwrap_or_default().to_string();
+    let names: Vec<String> = pairs.iter().map(|pair| name(pair)).collect();
+    let duplicate = names
+        .iter()
+        .enumerate()
+        .find(|(i, name)| names[..*i].contains(name))
+        .map(|(_, name)| name.clone());
+
+    let duplicate = match duplicate {
+        Some(duplicate) => duplicate,
+        None => return Ok(Cow::Borrowed(request)),
+    };
+
+    let kept: Vec<&str> = match policy {
+        DuplicateParams::Reject => return Err(ServerError::DuplicateParam { name: duplicate }),
+        DuplicateParams::First => pairs
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !names[..*i].contains(&names[*i]))
+            .map(|(_, pair)| *pair)
+            .collect(),
+        DuplicateParams::Last => pairs
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !names[i + 1..].contains(&names[*i]))
+            .map(|(_, pair)| *pair)
+            .collect(),
+    };
+
+    let query = fixed.into_iter().chain(kept).collect::<Vec<&str>>().join("&");
+    let mut line = format!("{} {}?{}", method.unwrap_or_default(), path, query);
+
+    if let Some(rest) = rest {
+        line.push(' ');
+        line.push_str(rest);
+    }
+
+    Ok(Cow::Owned(line))
+}
+
+/// Undoes the percent-encoding of one query string component.
+fn decode(component: &str) -> Result<String, ParseError> {
+    percent_encoding::percent_decode_str(component)
+        .decode_utf8()
+        .map(Cow::into_owned)
+        .map_err(|_| ParseError::InvalidRequest { code: 20 })
+}
+
+/// Splits the query string of a request line into its `name=value` pairs,
+/// decoding their values.
+fn query_params(request: &str) -> Result<Vec<(&str, String)>, ParseError> {
+    let query = request
+        .split_whitespace()
+        .nth(1)
+        .and_then(|target| target.split_once('?'))
+        .map(|(_, query)| query)
+        .unwrap_or("");
+
+    query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| param.split_once('=').unwrap_or((param, "")))
+        .map(|(name, val)| Ok((name, decode(val)?)))
+        .collect()
+}
+
+fn parse_changed(request: &str) -> Result<(u64, bool), ParseError> {
+    let mut since = None;
+    let mut values = false;
+
+    for (name, val) in query_params(request)? {
+        match name {
+            "since" => {
+                since = Some(val.parse().map_err(|_| ParseError::InvalidRequest { code: 9 })?);
+            }
+            "values" => {
+                values = val.parse().map_err(|_| ParseError::InvalidRequest { code: 5 })?;
+            }
+            _ => return Err(ParseError::InvalidRequest { code: 6 }),
+        }
+    }
+
+    match since {
+        Some(since) => Ok((since, values)),
+        None => Err(ParseError::InvalidRequest { code: 9 }),
+    }
+}
+
+fn parse_diff(request: &str) -> Result<String, ParseError> {
+    let mut path = None;
+
+    for (name, val) in query_params(request)? {
+        match name {
+            "path" if !val.is_empty() => path = Some(val),
+            _ => return Err(ParseError::InvalidRequest { code: 6 }),
+        }
+    }
+
+    path.ok_or(ParseError::InvalidRequest { code: 10 })
+}
+
+fn parse_eval(request: &str) -> Result<(String, String), ParseError> {
+    let (mut key, mut expr) = (None, None);
+
+    for (name, val) in query_params(request)? {
+        match name {
+            "key" if !val.is_empty() => key = Some(val),
+            "key" => return Err(ParseError::MissingKey),
+            "expr" => expr = Some(val),
+            _ => return Err(ParseError::InvalidRequest { code: 6 }),
+        }
+    }
+
+    match (key, expr) {
+        (Some(key), Some(expr)) => Ok((key, expr)),
+        (None, _) => Err(ParseError::MissingKey),
+        (_, None) => Err(ParseError::InvalidRequest { code: 11 }),
+    }
+}
+
+/// Reads the key of the template to render; every other parameter is a
+/// variable to fill its placeholders with.
+fn parse_render(request: &str) -> Result<(String, HashMap<String, String>), ParseError> {
+    let (mut key, mut vars) = (None, HashMap::new());
+
+    for (name, val) in query_params(request)? {
+        match name {
+            "key" if !val.is_empty() => key = Some(val),
+            "key" => return Err(ParseError::MissingKey),
+            _ => {
+                vars.insert(String::from(name), val);
+            }
+        }
+    }
+
+    let key = key.ok_or(ParseError::MissingKey)?;
+
+    Ok((key, vars))
+}
+
+fn parse_move(request: &str) -> Result<(String, String, String, bool), ParseError> {
+    let (mut key, mut from_ns, mut to_ns, mut nx) = (None, None, None, false);
+    let namespace = |val: String| {
+        Some(val)
+            .filter(|ns| !ns.is_empty() && !ns.contains(NAMESPACE_DELIMITER))
+            .ok_or(ParseError::InvalidRequest { code: 23 })
+    };
+
+    for (name, val) in query_params(request)? {
+        match name {
+            "key" if !val.is_empty() => key = Some(val),
+            "key" => return Err(ParseError::MissingKey),
+            "from_ns" => from_ns = Some(namespace(val)?),
+            "to_ns" => to_ns = Some(namespace(val)?),
+            "nx" => nx = val.parse().map_err(|_| ParseError::InvalidRequest { code: 5 })?,
+            _ => return Err(ParseError::InvalidRequest { code: 6 }),
+        }
+    }
+
+    let key = key.ok_or(ParseError::MissingKey)?;
+
+    match (from_ns, to_ns) {
+        (Some(from_ns), Some(to_ns)) => Ok((key, from_ns, to_ns, nx)),
+        _ => Err(ParseError::InvalidRequest { code: 23 }),
+    }
+}
+
+fn parse_infer_schema(request: &str) -> Result<(String, usize), ParseError> {
+    let (mut prefix, mut sample) = (String::new(), schema::DEFAULT_SAMPLE);
+
+    for (name, val) in query_params(request)? {
+        match name {
+            "prefix" => prefix = val,
+            "sample" => {
+                sample = val
+                    .parse()
+                    .ok()
+                    .filter(|sample| (1..=schema::MAX_SAMPLE).contains(sample))
+                    .ok_or(ParseError::InvalidRequest { code: 22 })?;
+            }
+            _ => return Err(ParseError::InvalidRequest { code: 6 }),
+        }
+    }
+
+    Ok((prefix, sample))
+}
+
+/// Parses a request that takes nothing but a key.
+fn parse_key(request: &str) -> Result<String, ParseError> {
+    let mut key = None;
+
+    for (name, val) in query_params(request)? {
+        match name {
+            "key" if !val.is_empty() => key = Some(val),
+            "key" => return Err(ParseError::MissingKey),
+            _ => return Err(ParseError::InvalidRequest { code: 6 }),
+        }
+    }
+
+    key.ok_or(ParseError::MissingKey)
+}
+
+fn parse_getorcreate(request: &str) -> Result<(String, String), ParseError> {
+    let (mut key, mut default) = (None, None);
+
+    for (name, val) in query_params(request)? {
+        match name {
+            "key" if !val.is_empty() => key = Some(val),
+            "key" => return Err(ParseError::MissingKey),
+            "default" => default = Some(val),
+            _ => return Err(ParseError::InvalidRequest { code: 6 }),
+        }
+    }
+
+    let key = key.ok_or(ParseError::MissingKey)?;
+    let default = default.ok_or(ParseError::InvalidRequest { code: 24 })?;
+
+    Ok((key, default))
+}
+
+fn parse_cas(request: &str) -> Result<(String, Option<String>, String), ParseError> {
+    let (mut key, mut expected, mut new) = (None, None, None);
+
+    for (name, val) in query_params(request)? {
+        match name {
+            "key" if !val.is_empty() => key = Some(val),
+            "key" => return Err(ParseError::MissingKey),
+            "expected" => expected = Some(val),
+            "new" => new = Some(val),
+            _ => return Err(ParseError::InvalidRequest { code: 6 }),
+        }
+    }
+
+    let key = key.ok_or(ParseError::MissingKey)?;
+    let expected = expected.ok_or(ParseError::InvalidRequest { code: 26 })?;
+    let new = new.ok_or(ParseError::InvalidRequest { code: 17 })?;
+
+    // an empty expected value stands for the key being absent
+    let expected = Some(expected).filter(|expected| !expected.is_empty());
+
+    Ok((key, expected, new))
+}
+
+fn parse_import(request: &str) -> Result<bool, ParseError> {
+    let mut replace = false;
+
+    for (name, val) in query_params(request)? {
+        match name {
+            "replace" => {
+                replace = val.parse().map_err(|_| ParseError::InvalidRequest { code: 5 })?;
+            }
+            _ => return Err(ParseError::InvalidRequest { code: 6 }),
+        }
+    }
+
+    Ok(replace)
+}
+
+fn parse_mget(request: &str) -> Result<Vec<String>, ParseError> {
+    let query = request
+        .split_whitespace()
+        .nth(1)
+        .and_then(|target| target.split_once('?'))
+        .map(|(_, query)| query)
+        .unwrap_or("");
+    let mut keys = None;
+
+    for param in query.split('&').filter(|param| !param.is_empty()) {
+        match param.split_once('=') {
+            // split before decoding, so that an encoded `,` stays in its key
+            Some(("keys", list)) => {
+                let (mut listed, mut seen) = (Vec::new(), HashSet::new());
+
+                for key in list.split(',').filter(|key| !key.is_empty()) {
+                    let key = decode(key)?;
+
+                    // a key asked for twice is only answered once
+                    if seen.insert(key.clone()) {
+                        listed.push(key);
+                    }
+                }
+
+                keys = Some(listed);
+            }
+            _ => return Err(ParseError::InvalidRequest { code: 6 }),
+        }
+    }
+
+    keys.ok_or(ParseError::MissingKey)
+}
+
+fn parse_count_by_prefix(request: &str) -> Result<String, ParseError> {
+    let mut delimiter = NAMESPACE_DELIMITER.to_string();
+
+    for (name, val) in query_params(request)? {
+        match name {
+            "delimiter" if !val.is_empty() => delimiter = val,
+            "delimiter" => return Err(ParseError::InvalidRequest { code: 25 }),
+            _ => return Err(ParseError::InvalidRequest { code: 6 }),
+        }
+    }
+
+    Ok(delimiter)
+}
+
+fn parse_keys(request: &str) -> Result<String, ParseError> {
+    let mut prefix = String::new();
+
+    for (name, val) in query_params(request)? {
+        match name {
+            "prefix" => prefix = val,
+            _ => return Err(ParseError::InvalidRequest { code: 6 }),
+        }
+    }
+
+    Ok(prefix)
+}
+
+fn parse_decr(request: &str) -> Result<(String, f64, Option<f64>), ParseError> {
+    let (mut key, mut by, mut floor) = (None, 1.0, None);
+    let number = |val: &str| {
+        val.parse::<f64>()
+            .ok()
+            .filter(|n| n.is_finite())
+            .ok_or(ParseError::InvalidRequest { code: 15 })
+    };
+
+    for (name, val) in query_params(request)? {
+        match name {
+            "key" if !val.is_empty() => key = Some(val),
+            "key" => return Err(ParseError::MissingKey),
+            "by" => by = number(&val)?,
+            "floor" => floor = Some(number(&val)?),
+            _ => return Err(ParseError::InvalidRequest { code: 6 }),
+        }
+    }
+
+    let key = key.ok_or(ParseError::MissingKey)?;
+
+    Ok((key, by, floor))
+}
+
+fn parse_incr(request: &str) -> Result<(String, f64), ParseError> {
+    let (mut key, mut by) = (None, 1.0);
+
+    for (name, val) in query_params(request)? {
+        match name {
+            "key" if !val.is_empty() => key = Some(val),
+            "key" => return Err(ParseError::MissingKey),
+            "by" => {
+                by = val
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|n| n.is_finite())
+                    .ok_or(ParseError::InvalidRequest { code: 15 })?;
+            }
+            _ => return Err(ParseError::InvalidRequest { code: 6 }),
+        }
+    }
+
+    let key = key.ok_or(ParseError::MissingKey)?;
+
+    Ok((key, by))
+}
+
+fn parse_meta(request: &str) -> Result<(String, String, Option<String>), ParseError> {
+    let (mut key, mut field, mut value) = (None, None, None);
+
+    for (name, val) in query_params(request)? {
+        match name {
+            "key" if !val.is_empty() => key = Some(val),
+            "key" => return Err(ParseError::MissingKey),
+            "field" if !val.is_empty() => field = Some(val),
+            "value" => value = Some(val),
+            _ => return Err(ParseError::InvalidRequest { code: 6 }),
+        }
+    }
+
+    let key = key.ok_or(ParseError::MissingKey)?;
+    let field = field.ok_or(ParseError::InvalidRequest { code: 16 })?;
+
+    Ok((key, field, value))
+}
+
+/// Reads the target file from the query string and the keys to snapshot from
+/// either a `prefix` parameter or a JSON array of keys in the body.
+fn parse_snapshot_subset(request: &str, body: &str) -> Result<(String, Selection), ParseError> {
+    let (mut path, mut prefix) = (None, None);
+
+    for (name, val) in query_params(request)? {
+        match name {
+            "path" if !val.is_empty() => path = Some(val),
+            "prefix" => prefix = Some(val),
+            _ => return Err(ParseError::InvalidRequest { code: 6 }),
+        }
+    }
+
+    let path = path.ok_or(ParseError::InvalidRequest { code: 10 })?;
+    let body = body.trim();
+
+    let selection = match prefix {
+        Some(prefix) if body.is_empty() => Selection::Prefix(prefix),
+        None if !body.is_empty() => {
+            let keys = serde_json::from_str(body).map_err(|_| ParseError::InvalidRequest { code: 18 })?;
+            Selection::Keys(keys)
+        }
+        // exactly one of the two has to say what to take
+        _ => return Err(ParseError::InvalidRequest { code: 18 }),
+    };
+
+    Ok((path, selection))
+}
+
+fn parse_benchmark(request: &str) -> Result<(usize, usize), ParseError> {
+    let (mut ops, mut keys) = (1_000, 100);
+
+    for (name, val) in query_params(request)? {
+        let val: usize = val
+            .parse()
+            .map_err(|_| ParseError::InvalidRequest { code: 12 })?;
+
+        match name {
+            "ops" if (1..=benchmark::MAX_OPS).contains(&val) => ops = val,
+            "keys" if (1..=benchmark::MAX_KEYS).contains(&val) => keys = val,
+            "ops" | "keys" => return Err(ParseError::InvalidRequest { code: 12 }),
+            _ => return Err(ParseError::InvalidRequest { code: 6 }),
+        }
+    }
+
+    Ok((ops, keys))
+}
+
+fn parse_scan(request: &str) -> Result<ScanOptions, ParseError> {
+    let mut options = ScanOptions {
+        after: None,
+        count: DEFAULT_SCAN_COUNT,
+        prefix: String::new(),
+        scope: String::new(),
+    };
+
+    for (name, val) in query_params(request)? {
+        match name {
+            // the cursor is the hex of the last key returned, which keeps it
+            // safe to put in a URL whatever the key contains
+            "cursor" if val.is_empty() => options.after = None,
+            "cursor" => {
+                let after = hex_decode(&val)
+                    .and_then(|after| String::from_utf8(after).ok())
+                    .ok_or(ParseError::InvalidRequest { code: 13 })?;
+                options.after = Some(after);
+            }
+            "count" => {
+                options.count = val
+                    .parse()
+                    .ok()
+                    .filter(|count| (1..=MAX_SCAN_COUNT).contains(count))
+                    .ok_or(ParseError::InvalidRequest { code: 14 })?;
+            }
+            "prefix" => options.prefix = val,
+            _ => return Err(ParseError::InvalidRequest { code: 6 }),
+        }
+    }
+
+    Ok(options)
+}
+
+fn parse_flush_namespace(request: &str) -> Result<String, ParseError> {
+    let path = request
+        .split_whitespace()
+        .nth(1)
+        .ok_or(ParseError::InvalidRequest { code: 7 })?;
+
+    match path
+        .strip_prefix("/ns/")
+        .and_then(|rest| rest.strip_suffix("/flush"))
+    {
+        Some(namespace) if !namespace.is_empty() => Ok(String::from(namespace)),
+        _ => Err(ParseError::InvalidRequest { code: 8 }),
+    }
+}
+
+fn parse_headers<'a>(lines: impl Iterator<Item = &'a str>) -> Headers {
+    let headers = lines
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            Some((name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        })
+        .collect();
+
+    Headers(headers)
+}
+
+/// Reads a whole request off the stream: everything up to the blank line
+/// that ends the headers, however many reads that takes, and then as much
+/// body as a `Content-Length` header promises. Whatever's read past its end,
+/// from a client that sent its next request early, is left in `pending`,
+/// which is also where the request starts from. A request over `max` bytes
+/// is refused.
+fn read_request(
+    stream: &mut impl Read,
+    pending: &mut Vec<u8>,
+    max: usize,
+) -> Result<Vec<u8>, ServerError> {
+    let mut request = std::mem::take(pending);
+    let mut buffer = [0; BUFFER_SIZE];
+    let mut searched = 0;
+
+    let head_len = loop {
+        if let Some(end) = find(&request[searched..], b"\r\n\r\n") {
+            break searched + end + 4;
+        }
+
+        if request.len() > max {
+            return Err(ServerError::RequestTooLarge { max });
+        }
+
+        let len = stream.read(&mut buffer)?;
+
+        if len == 0 {
+            // the client stopped sending without finishing its headers, so
+            // make do with what arrived
+            break request.len();
+        }
+
+        // the terminator may straddle two reads
+        searched = request.len().saturating_sub(3);
+        request.extend_from_slice(&buffer[..len]);
+    };
+
+    let head = String::from_utf8_lossy(&request[..head_len]);
+    let body_len = parse_headers(head.lines().skip(1))
+        .get(CONTENT_LENGTH_HEADER)
+        .and_then(|len| len.parse::<usize>().ok())
+        .unwrap_or(0);
+
+    // the length is the client's to choose, so it may be big enough to
+    // overflow
+    let total = head_len
+        .checked_add(body_len)
+        .filter(|&total| total <= max)
+        .ok_or(ServerError::RequestTooLarge { max })?;
+
+    while request.len() < total {
+        let len = stream.read(&mut buffer)?;
+
+        if len == 0 {
+            break;
+        }
+
+        request.extend_from_slice(&buffer[..len]);
+    }
+
+    if request.len() > total {
+        *pending = request.split_off(total);
+    }
+
+    Ok(request)
+}
+
+/// The most a single request, headers and body together, may take up: enough
+/// for the largest value a set may store, sent in the query string, where
+/// percent-encoding can take three bytes for each one of the value's.
+fn max_request_bytes(config: &ServerConfig) -> usize {
+    let max_value = config.max_value_bytes.unwrap_or(DEFAULT_MAX_VALUE_BYTES);
+
+    max_value.saturating_mul(3).saturating_add(REQUEST_HEAD_BYTES)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// Whether `request` is for `path`, and not for a longer path that merely
+/// starts the same way: the path has to end in the query string or the
+/// space before the HTTP version.
+fn is_route(request: &str, path: &str) -> bool {
+    request.strip_prefix(path).is_some_and(|rest| rest.starts_with([' ', '?']))
+}
+
+fn parse_request(
+    stream: &mut impl Read,
+    pending: &mut Vec<u8>,
+    duplicates: DuplicateParams,
+    max_bytes: usize,
+) -> Result<(Request, Headers), ServerError> {
+    let request = read_request(stream, pending, max_bytes)?;
+    let (head, body) = match find(&request, b"\r\n\r\n") {
+        Some(end) => (&request[..end], &request[end + 4..]),
+        None => (&request[..], &[][..]),
+    };
+    // a head that isn't UTF-8 can still be made sense of, but a body is
+    // taken as it is, so one that isn't UTF-8 is refused
+    let head = String::from_utf8_lossy(head);
+    let body = str::from_utf8(body).map_err(|_| ServerError::InvalidBody)?;
+    let mut lines = head.lines();
+    let request = lines.next().ok_or(ServerError::NoRequestFound)?;
+    let headers = parse_headers(lines);
+
+    Ok((route(request, &head, body, duplicates)?, headers))
+}
+
+/// Works out which request a request line makes, without running it. The
+/// endpoints that take more than their request line find the rest of the
+/// request's head and its body in `head` and `body`.
+fn route(
+    request: &str,
+    head: &str,
+    body: &str,
+    duplicates: DuplicateParams,
+) -> Result<Request, ServerError> {
+    if is_route(request, EXPLAIN_HEADER) {
+        // only the request line of the explained request matters
+        let explained = body.lines().next().unwrap_or_default();
+        return Ok(Request::Explain(String::from(explained)));
+    }
+
+    if is_route(request, TRACE_HEADER) {
+        // the head as it arrived, with the blank line that ended it
+        return Ok(Request::Trace(format!("{}\r\n\r\n", head)));
+    }
+
+    if is_route(request, IMPORT_HEADER) {
+        let replace = parse_import(request).map_err(|err| ServerError::ParseError {
+            reason: err.to_string(),
+        })?;
+        return Ok(Request::Import { body: String::from(body), replace });
+    }
+
+    if request.starts_with(SNAPSHOT_SUBSET_HEADER) {
+        let (path, selection) =
+            parse_snapshot_subset(request, body).map_err(|err| ServerError::ParseError {
+                reason: err.to_string(),
+            })?;
+        return Ok(Request::SnapshotSubset { path, selection });
+    }
+
+    let request = &*dedupe_params(request, duplicates)?;
+
+    if request.starts_with(POST_SET_HEADER) {
+        // the value is the body, so the request line alone can't make a set
+        let (key, val, options) =
+            parse_post_set(request, body).map_err(|err| ServerError::ParseError {
+                reason: err.to_string(),
+            })?;
+        Ok(Request::Set(key, val, options))
+    } else if request.starts_with(GET_HEADER) {
+        // get the key from the request
+        let (key, options) = parse_get(request).map_err(|err| ServerError::ParseError {
+            reason: err.to_string(),
+        })?;
+        Ok(Request::Get(key, options))
+    } else if request.starts_with(GETDEL_HEADER) {
+        // get the key to fetch and remove from the request; a stale value is
+        // never handed out by a getdel, so there's nothing for swr to change
+        let (key, _) = parse_get(request).map_err(|err| ServerError::ParseError {
+            reason: err.to_string(),
+        })?;
+        Ok(Request::GetDel(key))
+    } else if request.starts_with(DELETE_HEADER) {
+        // get the key to remove from the request
+        let key = parse_delete(request).map_err(|err| ServerError::ParseError {
+            reason: err.to_string(),
+        })?;
+        Ok(Request::Delete(key))
+    } else if is_route(request, METRICS_RESET_HEADER) {
+        Ok(Request::ResetMetrics)
+    } else if is_route(request, EXPORT_HEADER) {
+        Ok(Request::Export)
+    } else if request.starts_with(STATS_HEADER) {
+        Ok(Request::Stats)
+    } else if request.starts_with(TRACE_SLOW_HEADER) {
+        Ok(Request::TraceSlow)
+    } else if request.starts_with(NAMESPACE_HEADER) {
+        // get the namespace to clear from the request path
+        let namespace = parse_flush_namespace(request).map_err(|err| ServerError::ParseError {
+            reason: err.to_string(),
+        })?;
+        Ok(Request::FlushNamespace(namespace))
+    } else if request.starts_with(CHANGED_HEADER) {
+        let (since, values) = parse_changed(request).map_err(|err| ServerError::ParseError {
+            reason: err.to_string(),
+        })?;
+        let scope = String::new();
+        Ok(Request::Changed { since, values, scope })
+    } else if request.starts_with(DIFF_HEADER) {
+        let path = parse_diff(request).map_err(|err| ServerError::ParseError {
+            reason: err.to_string(),
+        })?;
+        Ok(Request::Diff(path))
+    } else if request.starts_with(EVAL_HEADER) {
+        let (key, expr) = parse_eval(request).map_err(|err| ServerError::ParseError {
+            reason: err.to_string(),
+        })?;
+        Ok(Request::Eval { key, expr })
+    } else if request.starts_with(DECR_HEADER) {
+        let (key, by, floor) = parse_decr(request).map_err(|err| ServerError::ParseError {
+            reason: err.to_string(),
+        })?;
+        Ok(Request::Decr { key, by, floor })
+    } else if request.starts_with(RENDER_HEADER) {
+        let (key, vars) = parse_render(request).map_err(|err| ServerError::ParseError {
+            reason: err.to_string(),
+        })?;
+        Ok(Request::Render { key, vars })
+    } else if request.starts_with(FAVICON_HEADER) {
+        Ok(Request::Favicon)
+    } else if request.starts_with(READY_HEADER) {
+        Ok(Request::Ready)
+    } else if request.starts_with(MOVE_HEADER) {
+        let (key, from_ns, to_ns, nx) = parse_move(request).map_err(|err| ServerError::ParseError {
+            reason: err.to_string(),
+        })?;
+        Ok(Request::Move { key, from_ns, to_ns, nx })
+    } else if is_route(request, INFER_SCHEMA_HEADER) {
+        let (prefix, sample) = parse_infer_schema(request).map_err(|err| ServerError::ParseError {
+            reason: err.to_string(),
+        })?;
+        Ok(Request::InferSchema { prefix, sample, scope: String::new() })
+    } else if request.starts_with(GETORCREATE_HEADER) {
+        let (key, default) = parse_getorcreate(request).map_err(|err| ServerError::ParseError {
+            reason: err.to_string(),
+        })?;
+        Ok(Request::GetOrCreate { key, default })
+    } else if request.starts_with(CAS_HEADER) {
+        let (key, expected, new) = parse_cas(request).map_err(|err| ServerError::ParseError {
+            reason: err.to_string(),
+        })?;
+        Ok(Request::Cas { key, expected, new })
+    } else if request.starts_with(EXISTS_HEADER) {
+        let key = parse_key(request).map_err(|err| ServerError::ParseError {
+            reason: err.to_string(),
+        })?;
+        Ok(Request::Exists(key))
+    } else if request.starts_with(PERSIST_HEADER) {
+        let key = parse_key(request).map_err(|err| ServerError::ParseError {
+            reason: err.to_string(),
+        })?;
+        Ok(Request::Persist(key))
+    } else if request.starts_with(MGET_HEADER) {
+        let keys = parse_mget(request).map_err(|err| ServerError::ParseError {
+            reason: err.to_string(),
+        })?;
+        Ok(Request::MultiGet { keys, scope: String::new() })
+    } else if is_route(request, COUNT_BY_PREFIX_HEADER) {
+        let delimiter = parse_count_by_prefix(request).map_err(|err| ServerError::ParseError {
+            reason: err.to_string(),
+        })?;
+        Ok(Request::CountByPrefix { delimiter, scope: String::new() })
+    } else if is_route(request, KEYS_HEADER) {
+        let prefix = parse_keys(request).map_err(|err| ServerError::ParseError {
+            reason: err.to_string(),
+        })?;
+        Ok(Request::List { prefix, scope: String::new() })
+    } else if request.starts_with(INCR_HEADER) {
+        let (key, by) = parse_incr(request).map_err(|err| ServerError::ParseError {
+            reason: err.to_string(),
+        })?;
+        Ok(Request::Incr { key, by })
+    } else if is_route(request, EXPIRED_HEADER) {
+        Ok(Request::Expired { scope: String::new() })
+    } else if request.starts_with(SETMETA_HEADER) {
+        let (key, field, value) = parse_meta(request).map_err(|err| ServerError::ParseError {
+            reason: err.to_string(),
+        })?;
+        let value = value.ok_or_else(|| ServerError::ParseError {
+            reason: ParseError::InvalidRequest { code: 17 }.to_string(),
+        })?;
+        Ok(Request::SetMeta { key, field, value })
+    } else if request.starts_with(GETMETA_HEADER) {
+        let (key, field, _) = parse_meta(request).map_err(|err| ServerError::ParseError {
+            reason: err.to_string(),
+        })?;
+        Ok(Request::GetMeta { key, field })
+    } else if is_route(request, BENCHMARK_HEADER) {
+        let (ops, keys) = parse_benchmark(request).map_err(|err| ServerError::ParseError {
+            reason: err.to_string(),
+        })?;
+        Ok(Request::Benchmark { ops, keys })
+    } else if is_route(request, SCAN_HEADER) {
+        let options = parse_scan(request).map_err(|err| ServerError::ParseError {
             reason: err.to_string(),
         })?;
-        Ok(Request::Get(key))
+        Ok(Request::Scan(options))
     } else if request.starts_with(SET_HEADER) {
         // get the key and value from the request
-        let (key, val) = parse_set(request).map_err(|err| ServerError::ParseError {
+        let (key, val, options) = parse_set(request).map_err(|err| ServerError::ParseError {
             reason: err.to_string(),
         })?;
-        Ok(Request::Set(key, val))
+        Ok(Request::Set(key, val, options))
     } else {
         Err(ServerError::InvalidRequest)
     }
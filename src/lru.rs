@@ -0,0 +1,74 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use crate::metrics::Metrics;
+
+/// The order keys were last used in, for evicting the least recently used
+/// once the store grows past its capacity. Each use takes the next tick of a
+/// counter, so the order is exact rather than approximated from timestamps.
+///
+/// Keys aren't told about their removal; one that's gone by the time it's
+/// picked for eviction is simply skipped, and the stragglers are swept out
+/// once enough of them pile up.
+pub struct Lru {
+    capacity: usize,
+    /// The tick each key was last used at.
+    used: HashMap<String, u64>,
+    /// The keys by the tick they were last used at, oldest first.
+    order: BTreeMap<u64, String>,
+    next: u64,
+    /// Where evictions are counted.
+    metrics: Arc<Metrics>,
+}
+
+impl Lru {
+    pub fn new(capacity: usize, metrics: Arc<Metrics>) -> Self {
+        Lru {
+            capacity,
+            used: HashMap::new(),
+            order: BTreeMap::new(),
+            next: 0,
+            metrics,
+        }
+    }
+
+    /// The most keys the store may hold.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// How many keys are being tracked, removed ones included.
+    pub fn len(&self) -> usize {
+        self.used.len()
+    }
+
+    /// Marks `key` as the most recently used.
+    pub fn touch(&mut self, key: &str) {
+        let key = match self.used.get(key) {
+            Some(tick) => self.order.remove(tick).unwrap_or_else(|| String::from(key)),
+            None => String::from(key),
+        };
+
+        self.used.insert(key.clone(), self.next);
+        self.order.insert(self.next, key);
+        self.next += 1;
+    }
+
+    /// Stops tracking the least recently used key and hands it back.
+    pub fn pop_oldest(&mut self) -> Option<String> {
+        let (_, key) = self.order.pop_first()?;
+        self.used.remove(&key);
+
+        Some(key)
+    }
+
+    /// Stops tracking every key `live` says is gone.
+    pub fn retain(&mut self, live: impl Fn(&str) -> bool) {
+        self.order.retain(|_, key| live(key));
+        self.used.retain(|key, _| live(key));
+    }
+
+    pub fn record_evictions(&self, evicted: usize) {
+        self.metrics.record_evictions(evicted);
+    }
+}
@@ -0,0 +1,111 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Serialize;
+
+/// Counters for the operations the server has handled.
+#[derive(Default)]
+pub struct Metrics {
+    gets: AtomicU64,
+    sets: AtomicU64,
+    getdels: AtomicU64,
+    deletes: AtomicU64,
+    misses: AtomicU64,
+    timeouts: AtomicU64,
+    /// Keys dropped to keep the store within its capacity.
+    evictions: AtomicU64,
+    /// Increments answered without taking the store's lock, having been
+    /// coalesced with an earlier one to the same counter.
+    coalesced: AtomicU64,
+}
+
+/// A point-in-time copy of the counters in `Metrics`.
+#[derive(Debug, Serialize)]
+pub struct MetricsSnapshot {
+    pub gets: u64,
+    pub sets: u64,
+    pub getdels: u64,
+    pub deletes: u64,
+    pub misses: u64,
+    pub timeouts: u64,
+    pub evictions: u64,
+    pub coalesced: u64,
+}
+
+/// What `/stats` reports: how big the store is right now, alongside the
+/// operation counters.
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    /// How many keys are live, leaving out any that have expired.
+    pub keys: usize,
+    /// Roughly how many bytes the live keys and their values take up. A
+    /// value shared between keys is counted once for each of them.
+    pub bytes: usize,
+    /// The same, but with a value shared between keys counted only once,
+    /// which is what deduplicating values saves.
+    pub deduplicated_bytes: usize,
+    #[serde(flatten)]
+    pub counters: MetricsSnapshot,
+}
+
+impl Metrics {
+    pub fn record_get(&self) {
+        self.gets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_set(&self) {
+        self.sets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_getdel(&self) {
+        self.getdels.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_delete(&self) {
+        self.deletes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_timeout(&self) {
+        self.timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_evictions(&self, evicted: usize) {
+        self.evictions.fetch_add(evicted as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_coalesced(&self) {
+        self.coalesced.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Reads every counter, leaving them running.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            gets: self.gets.load(Ordering::Relaxed),
+            sets: self.sets.load(Ordering::Relaxed),
+            getdels: self.getdels.load(Ordering::Relaxed),
+            deletes: self.deletes.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            coalesced: self.coalesced.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Reads every counter and zeroes it in the same step, so that each call
+    /// reports exactly the operations since the previous one.
+    pub fn reset(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            gets: self.gets.swap(0, Ordering::Relaxed),
+            sets: self.sets.swap(0, Ordering::Relaxed),
+            getdels: self.getdels.swap(0, Ordering::Relaxed),
+            deletes: self.deletes.swap(0, Ordering::Relaxed),
+            misses: self.misses.swap(0, Ordering::Relaxed),
+            timeouts: self.timeouts.swap(0, Ordering::Relaxed),
+            evictions: self.evictions.swap(0, Ordering::Relaxed),
+            coalesced: self.coalesced.swap(0, Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use log::error;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed set of worker threads that run jobs in the order they're queued.
+/// A job that panics only takes itself down, and its worker goes on to the
+/// next one. Dropping the pool lets every queued job finish, then joins the
+/// workers.
+pub struct ThreadPool {
+    workers: Vec<JoinHandle<()>>,
+    sender: Option<Sender<Job>>,
+}
+
+impl ThreadPool {
+    /// Starts a pool of `size` workers.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is zero, since a pool without workers would accept
+    /// jobs and never run them.
+    pub fn new(size: usize) -> Self {
+        assert!(size > 0, "a thread pool needs at least one worker");
+
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..size)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || work(&receiver))
+            })
+            .collect();
+
+        ThreadPool {
+            workers,
+            sender: Some(sender),
+        }
+    }
+
+    /// Queues a job for the next free worker.
+    pub fn execute<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Some(sender) = &self.sender {
+            // the workers only hang up once the pool is dropped
+            let _ = sender.send(Box::new(job));
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // closing the channel is what tells the workers to stop
+        drop(self.sender.take());
+
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() {
+                error!("A worker thread panicked");
+            }
+        }
+    }
+}
+
+fn work(receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        // the lock is only held while waiting for a job, not while running it
+        let job = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => return,
+        };
+
+        match job {
+            Ok(job) => {
+                // whatever the job was working on is left to its own
+                // handling of poisoning, as the store's lock already does
+                if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                    error!("A job panicked");
+                }
+            }
+            Err(_) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn a_panicking_job_leaves_its_worker_running() {
+        let pool = ThreadPool::new(1);
+        let (done, finished) = mpsc::channel();
+
+        pool.execute(|| panic!("the job fails"));
+        pool.execute(move || done.send(()).unwrap());
+
+        assert!(finished.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+}
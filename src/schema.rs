@@ -0,0 +1,78 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use serde_json::Value;
+
+/// How many values are sampled when the request doesn't say.
+pub const DEFAULT_SAMPLE: usize = 100;
+/// The most values a single inference may look at, to keep it from holding
+/// the store for an unbounded amount of time.
+pub const MAX_SAMPLE: usize = 10_000;
+
+/// What a sample of values has in common.
+#[derive(Debug, Serialize)]
+pub struct Schema {
+    /// How many values were looked at.
+    pub sampled: usize,
+    /// How many of them were of each type.
+    pub types: BTreeMap<&'static str, usize>,
+    /// The top-level fields of the values that are objects.
+    pub fields: BTreeMap<String, Field>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Field {
+    /// How many times the field held each type.
+    pub types: BTreeMap<&'static str, usize>,
+    /// The share of sampled objects that have the field, from 0 to 1.
+    pub presence: f64,
+}
+
+/// Infers a schema from the given values.
+pub fn infer<'a>(values: impl Iterator<Item = &'a Value>) -> Schema {
+    let mut schema = Schema {
+        sampled: 0,
+        types: BTreeMap::new(),
+        fields: BTreeMap::new(),
+    };
+    let mut objects = 0;
+
+    for value in values {
+        schema.sampled += 1;
+        *schema.types.entry(type_name(value)).or_default() += 1;
+
+        if let Value::Object(map) = value {
+            objects += 1;
+
+            for (name, field) in map {
+                let entry = schema.fields.entry(name.clone()).or_insert_with(|| Field {
+                    types: BTreeMap::new(),
+                    presence: 0.0,
+                });
+
+                *entry.types.entry(type_name(field)).or_default() += 1;
+                // counts for now; turned into a share once every object is in
+                entry.presence += 1.0;
+            }
+        }
+    }
+
+    for field in schema.fields.values_mut() {
+        field.presence /= objects as f64;
+    }
+
+    schema
+}
+
+/// The name of a value's type, as `if_type` spells it.
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "bool",
+        Value::Number(n) if n.is_f64() => "float",
+        Value::Number(_) => "int",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
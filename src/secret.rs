@@ -0,0 +1,93 @@
+use std::fmt;
+
+/// A value that must never be compared with `==` or printed, such as a
+/// password. All comparisons go through `constant_time_eq`.
+#[derive(Clone)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(secret: impl Into<String>) -> Self {
+        Secret(secret.into())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Checks whether `candidate` is this secret, in constant time.
+    pub fn matches(&self, candidate: &[u8]) -> bool {
+        constant_time_eq(self.0.as_bytes(), candidate)
+    }
+}
+
+impl PartialEq for Secret {
+    fn eq(&self, other: &Self) -> bool {
+        self.matches(other.0.as_bytes())
+    }
+}
+
+impl Eq for Secret {}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+/// Compares two byte strings in time that depends only on their lengths, so
+/// that how long a check takes reveals nothing about where a guess went wrong.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    #[cfg(test)]
+    tests::COMPARISONS.with(|comparisons| comparisons.set(comparisons.get() + 1));
+
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::auth::BasicAuth;
+
+    thread_local! {
+        /// How many times `constant_time_eq` has run on this thread, so that
+        /// tests can tell the secret checks went through it.
+        pub(crate) static COMPARISONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    fn comparisons() -> usize {
+        COMPARISONS.with(Cell::get)
+    }
+
+    #[test]
+    fn equal_strings_and_only_those_compare_equal() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secrets"));
+        assert!(!constant_time_eq(b"secret", b""));
+    }
+
+    #[test]
+    fn secret_checks_go_through_the_constant_time_comparison() {
+        let secret = Secret::new("secret");
+
+        let before = comparisons();
+        assert!(secret.matches(b"secret"));
+        assert!(secret == Secret::new("secret"));
+        assert_eq!(comparisons(), before + 2);
+
+        let auth = BasicAuth { username: String::from("admin"), password: secret };
+
+        // the username and the password are both compared, even when the
+        // first is already wrong
+        let before = comparisons();
+        assert!(!auth.verify(Some("Basic c29tZW9uZTp3cm9uZw==")));
+        assert_eq!(comparisons(), before + 2);
+    }
+}
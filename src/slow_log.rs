@@ -0,0 +1,57 @@
+use std::collections::VecDeque;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+use serde::Serialize;
+
+/// How long a request may take before it counts as slow, unless configured
+/// otherwise.
+pub const DEFAULT_THRESHOLD: Duration = Duration::from_millis(100);
+/// How many slow requests are kept, unless configured otherwise.
+pub const DEFAULT_CAPACITY: usize = 100;
+
+/// One request that took longer than the threshold.
+#[derive(Debug, Clone, Serialize)]
+pub struct SlowRequest {
+    /// The kind of request, as the operation lists name it.
+    pub operation: &'static str,
+    /// The key it was for, if it named a single one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// How long it took, from being received to being handled.
+    pub duration_ms: f64,
+    /// When it finished being handled, in seconds since the UNIX epoch.
+    pub at: u64,
+}
+
+/// The most recent slow requests, oldest first, with the oldest making way
+/// once it's full.
+pub struct SlowLog {
+    capacity: usize,
+    entries: Mutex<VecDeque<SlowRequest>>,
+}
+
+impl SlowLog {
+    pub fn new(capacity: usize) -> Self {
+        SlowLog {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn record(&self, request: SlowRequest) {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+
+        entries.push_back(request);
+    }
+
+    /// A copy of what's been kept, oldest first.
+    pub fn recent(&self) -> Vec<SlowRequest> {
+        let entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        entries.iter().cloned().collect()
+    }
+}
@@ -0,0 +1,65 @@
+use std::time::{Duration, Instant};
+
+/// The pause between accepted connections right at startup, before the ramp
+/// has made any progress.
+const INITIAL_DELAY: Duration = Duration::from_millis(100);
+
+/// Throttles how quickly connections are accepted for a window of time after
+/// startup, shrinking the pause between accepts linearly until the server is
+/// running at full speed.
+pub struct SlowStart {
+    started: Instant,
+    window: Duration,
+}
+
+impl SlowStart {
+    pub fn new(window: Duration) -> Self {
+        SlowStart {
+            started: Instant::now(),
+            window,
+        }
+    }
+
+    /// Whether the ramp is still going, so the server isn't at full speed
+    /// yet.
+    pub fn ramping(&self) -> bool {
+        self.started.elapsed() < self.window
+    }
+
+    /// The pause to take before handling the next connection.
+    pub fn delay(&self) -> Duration {
+        let elapsed = self.started.elapsed();
+
+        if elapsed >= self.window {
+            return Duration::ZERO;
+        }
+
+        let remaining = 1.0 - elapsed.as_secs_f64() / self.window.as_secs_f64();
+        INITIAL_DELAY.mul_f64(remaining)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn the_pause_shrinks_until_the_window_is_up() {
+        let slow_start = SlowStart::new(Duration::from_millis(300));
+        let early = slow_start.delay();
+
+        assert!(slow_start.ramping());
+        assert!(early > INITIAL_DELAY / 2);
+
+        thread::sleep(Duration::from_millis(150));
+        let later = slow_start.delay();
+        assert!(later < early);
+        assert!(later > Duration::ZERO);
+
+        thread::sleep(Duration::from_millis(200));
+        assert!(!slow_start.ramping());
+        assert_eq!(slow_start.delay(), Duration::ZERO);
+    }
+}
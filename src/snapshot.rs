@@ -0,0 +1,85 @@
+//! Serializing the store for the persistence file. With more than one
+//! thread the entries are split into even runs, each written out on its own
+//! thread and then joined in order, so the result is byte for byte what
+//! serializing the whole map at once would have produced.
+
+use std::collections::HashMap;
+use std::panic;
+use std::thread;
+
+use crate::Record;
+
+/// Writes `data` out as a JSON object, spread over up to `threads` threads.
+pub fn serialize(data: &HashMap<String, Record>, threads: usize) -> serde_json::Result<String> {
+    if threads <= 1 || data.len() < 2 {
+        return serde_json::to_string(data);
+    }
+
+    // the map iterates in the same order here as it does for serde, which
+    // is what keeps the joined runs identical to the sequential output
+    let entries: Vec<(&String, &Record)> = data.iter().collect();
+    let run = entries.len().div_ceil(threads);
+
+    let runs = thread::scope(|scope| {
+        let handles: Vec<_> = entries
+            .chunks(run)
+            .map(|run| scope.spawn(move || serialize_run(run)))
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap_or_else(|err| panic::resume_unwind(err)))
+            .collect::<serde_json::Result<Vec<String>>>()
+    })?;
+
+    Ok(format!("{{{}}}", runs.join(",")))
+}
+
+/// Writes a run of entries as the comma-separated members of a JSON object,
+/// without the braces around them.
+fn serialize_run(entries: &[(&String, &Record)]) -> serde_json::Result<String> {
+    let mut json = String::new();
+
+    for (i, (key, record)) in entries.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+
+        json.push_str(&serde_json::to_string(key)?);
+        json.push(':');
+        json.push_str(&serde_json::to_string(record)?);
+    }
+
+    Ok(json)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    #[test]
+    fn every_thread_count_writes_the_same_bytes() {
+        let data: HashMap<String, Record> = (0..1_000)
+            .map(|i| {
+                let value = match i % 3 {
+                    0 => Value::from(i),
+                    1 => Value::from(format!("\"quoted\"\n{}", i)),
+                    _ => serde_json::json!({ "list": [i, null], "ok": true }),
+                };
+
+                (format!("key{}\u{e9}", i), Record::new(value))
+            })
+            .collect();
+
+        let sequential = serialize(&data, 1).unwrap();
+
+        for threads in [2, 3, 8, 1_000, 5_000] {
+            assert_eq!(serialize(&data, threads).unwrap(), sequential, "{} threads", threads);
+        }
+
+        let parsed: HashMap<String, Record> = serde_json::from_str(&sequential).unwrap();
+        assert_eq!(parsed.len(), data.len());
+    }
+}
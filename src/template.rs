@@ -0,0 +1,64 @@
+//! Placeholder filling for `/render`. A template is a string with `{{name}}`
+//! placeholders, each replaced by the variable of that name. Nothing else is
+//! special: there are no conditionals, loops or escapes, and the text that's
+//! filled in is never scanned for placeholders itself.
+
+use std::collections::HashMap;
+
+use crate::error::TemplateError;
+
+const OPEN: &str = "{{";
+const CLOSE: &str = "}}";
+
+/// Fills every placeholder in `template` from `vars`. Whitespace around a
+/// placeholder's name is ignored.
+pub fn render(template: &str, vars: &HashMap<String, String>) -> Result<String, TemplateError> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find(OPEN) {
+        rendered.push_str(&rest[..start]);
+
+        let after = &rest[start + OPEN.len()..];
+        let end = after.find(CLOSE).ok_or(TemplateError::Unclosed {
+            pos: template.len() - rest.len() + start,
+        })?;
+
+        let name = after[..end].trim();
+        let value = vars.get(name).ok_or_else(|| TemplateError::MissingVar {
+            name: String::from(name),
+        })?;
+
+        rendered.push_str(value);
+        rest = &after[end + CLOSE.len()..];
+    }
+
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|&(name, value)| (String::from(name), String::from(value))).collect()
+    }
+
+    #[test]
+    fn filled_text_is_never_expanded_again() {
+        let vars = vars(&[("a", "{{b}}"), ("b", "no")]);
+
+        assert_eq!(render("{{ a }} and {{b}}", &vars).unwrap(), "{{b}} and no");
+        assert_eq!(render("plain", &vars).unwrap(), "plain");
+    }
+
+    #[test]
+    fn a_bad_placeholder_is_an_error() {
+        let unclosed = render("ok {{b}} {{a", &vars(&[("b", "")]));
+        assert!(matches!(unclosed, Err(TemplateError::Unclosed { pos: 9 })));
+
+        let missing = render("{{c}}", &vars(&[]));
+        assert!(matches!(missing, Err(TemplateError::MissingVar { name }) if name == "c"));
+    }
+}
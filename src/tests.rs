@@ -0,0 +1,2537 @@
+//! Tests that hand requests to the server's handlers directly, without
+//! opening a socket.
+
+use std::cell::RefCell;
+use std::io::{BufReader, Cursor};
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::thread;
+
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+
+use super::*;
+
+/// A backend with nothing saved in it that keeps nothing it's given.
+struct Discard;
+
+impl PersistenceBackend for Discard {
+    fn load(&self) -> Result<HashMap<String, Record>, ServerError> {
+        Ok(HashMap::new())
+    }
+
+    fn save(&self, _: &HashMap<String, Record>) -> Result<(), ServerError> {
+        Ok(())
+    }
+}
+
+/// A backend that keeps what it's given in memory, for looking at after.
+#[derive(Default)]
+struct InMemory(Mutex<HashMap<String, Record>>);
+
+impl PersistenceBackend for InMemory {
+    fn load(&self) -> Result<HashMap<String, Record>, ServerError> {
+        Ok(self.0.lock().unwrap().clone())
+    }
+
+    fn save(&self, data: &HashMap<String, Record>) -> Result<(), ServerError> {
+        *self.0.lock().unwrap() = data.clone();
+        Ok(())
+    }
+}
+
+/// An empty store persisted through `backend`, and saved there on drop only
+/// if `persist_on_drop` is set.
+fn empty_store(backend: Arc<dyn PersistenceBackend>, persist_on_drop: bool) -> Storage {
+    Storage {
+        data: HashMap::new(),
+        persistence: backend,
+        refreshing: HashSet::new(),
+        dedup: false,
+        interned: HashMap::new(),
+        filter: None,
+        persist_on_drop,
+        wal: None,
+        lru: None,
+        footprint: None,
+        snapshots_taken: AtomicU64::new(1),
+        written: Arc::new(Mutex::new(0)),
+    }
+}
+
+/// An empty store that's never saved.
+fn storage() -> Arc<Mutex<Storage>> {
+    Arc::new(Mutex::new(empty_store(Arc::new(Discard), false)))
+}
+
+/// Handles `request` with the whole default timeout to do it in.
+fn handle(storage: &Arc<Mutex<Storage>>, request: Request) -> Response {
+    handle_configured(storage, &ServerConfig::default(), request)
+}
+
+/// Handles `request` like [`handle`], under `config`.
+fn handle_configured(
+    storage: &Arc<Mutex<Storage>>,
+    config: &ServerConfig,
+    request: Request,
+) -> Response {
+    let server = Server {
+        storage: Arc::clone(storage),
+        filter: lock(storage).filter.clone(),
+        metrics: Arc::new(Metrics::default()),
+        config: config.clone(),
+        slow_start: None,
+        slow_log: SlowLog::new(slow_log::DEFAULT_CAPACITY),
+        coalescer: None,
+    };
+
+    handle_on(&server, request)
+}
+
+/// Handles `request` on `server`, counting it in the server's metrics.
+fn handle_on(server: &Server, request: Request) -> Response {
+    handle_request(request, server, Instant::now() + DEFAULT_TIMEOUT)
+}
+
+/// A plain get of `key`, with no flags.
+fn get(key: &str) -> Request {
+    Request::Get(String::from(key), GetOptions::default())
+}
+
+/// A plain set of `key` to `val`, with no flags.
+fn set(key: &str, val: &str) -> Request {
+    Request::Set(String::from(key), String::from(val), SetOptions::default())
+}
+
+/// What a client reads back when `response` is sent to it in `encoding`.
+fn written(response: Response, encoding: Encoding, config: &ServerConfig) -> Vec<u8> {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("the listener binds");
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (mut stream, _) = listener.accept().unwrap();
+
+    send_response(response, &mut stream, encoding, true, config).expect("the response is sent");
+    drop(stream);
+
+    let mut read = Vec::new();
+    client.read_to_end(&mut read).unwrap();
+
+    read
+}
+
+/// Splits a written response into its head, as text, and its body.
+fn split(written: &[u8]) -> (String, Vec<u8>) {
+    let head_len = written.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+    let (head, body) = written.split_at(head_len);
+
+    (String::from_utf8_lossy(head).into_owned(), body.to_vec())
+}
+
+/// A server for `config` over an empty store that's never saved.
+pub(crate) fn server(config: ServerConfig) -> Server {
+    let metrics = Arc::new(Metrics::default());
+    let mut storage = load_storage(&config, Arc::new(Discard), &metrics).expect("the store loads");
+    storage.persist_on_drop = false;
+
+    Server::new(config, storage, metrics)
+}
+
+/// A connection whose client has already sent `input`, collecting whatever
+/// the server writes back.
+struct Exchange {
+    input: Cursor<Vec<u8>>,
+    output: Vec<u8>,
+    /// The most any one read hands over, as if the rest were still in
+    /// flight.
+    chunk: usize,
+    /// How many writes the server made, however much each one held.
+    writes: usize,
+}
+
+impl Exchange {
+    fn new(input: impl Into<Vec<u8>>) -> Self {
+        Exchange {
+            input: Cursor::new(input.into()),
+            output: Vec::new(),
+            chunk: usize::MAX,
+            writes: 0,
+        }
+    }
+}
+
+impl Read for Exchange {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.chunk);
+        self.input.read(&mut buf[..len])
+    }
+}
+
+impl Write for Exchange {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writes += 1;
+        self.output.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Connection for Exchange {
+    fn set_read_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn peer(&self) -> String {
+        String::from("test")
+    }
+}
+
+/// A response as the client reads it.
+struct Reply {
+    status: u16,
+    /// The status line and headers.
+    head: String,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).expect("the body is JSON")
+    }
+
+    /// The value of the header called `name`, matched case-insensitively.
+    fn header(&self, name: &str) -> Option<&str> {
+        self.head.lines().skip(1).find_map(|line| {
+            let (found, value) = line.split_once(':')?;
+            found.eq_ignore_ascii_case(name).then(|| value.trim())
+        })
+    }
+}
+
+/// Splits what the server wrote into the responses it's made up of.
+fn replies(mut output: &[u8]) -> Vec<Reply> {
+    let mut replies = Vec::new();
+
+    while !output.is_empty() {
+        let head_len = find(output, b"\r\n\r\n").expect("the response has an end to its head") + 4;
+        let head = String::from_utf8_lossy(&output[..head_len]).into_owned();
+        let status = head.split_whitespace().nth(1).and_then(|status| status.parse().ok());
+
+        let mut reply = Reply {
+            status: status.expect("the response has a status"),
+            head,
+            body: Vec::new(),
+        };
+        let body_len = reply.header("Content-Length").map_or(0, |len| len.parse().unwrap());
+
+        reply.body = output[head_len..head_len + body_len].to_vec();
+        output = &output[head_len + body_len..];
+        replies.push(reply);
+    }
+
+    replies
+}
+
+/// Sends `input` as one connection's worth of requests, returning every
+/// response to them.
+fn exchange(server: &Server, input: impl Into<Vec<u8>>) -> Vec<Reply> {
+    let mut stream = Exchange::new(input);
+
+    // a failure is only a connection ended early; what was sent before it
+    // is still there to check
+    let _ = server.serve(&mut stream, Instant::now());
+
+    replies(&stream.output)
+}
+
+/// Sends one raw request, head and body.
+fn send(server: &Server, request: &str) -> Reply {
+    exchange(server, request).into_iter().next().expect("the server responds")
+}
+
+/// Backdates `key`'s expiry so that it has just expired.
+pub(crate) fn expire(server: &Server, key: &str) {
+    let mut storage = lock(&server.storage);
+    let record = storage.data.get_mut(key).expect("the key is stored");
+
+    record.expires = Some(now() - 1);
+}
+
+fn headers(lines: &str) -> Headers {
+    parse_headers(lines.lines())
+}
+
+thread_local! {
+    /// The warnings logged on this thread, while something's collecting them.
+    static WARNINGS: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+/// A logger that hands each warning to the thread that logged it, so tests
+/// running side by side only ever see their own.
+struct Capture;
+
+impl log::Log for Capture {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        WARNINGS.with(|warnings| {
+            if let Some(warnings) = warnings.borrow_mut().as_mut() {
+                warnings.push(record.args().to_string());
+            }
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+/// Runs `f`, returning the warnings it logged.
+fn warnings_during(f: impl FnOnce()) -> Vec<String> {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        static CAPTURE: Capture = Capture;
+        log::set_logger(&CAPTURE).expect("no other logger is set");
+        log::set_max_level(log::LevelFilter::Warn);
+    });
+
+    WARNINGS.with(|warnings| *warnings.borrow_mut() = Some(Vec::new()));
+    f();
+    WARNINGS.with(|warnings| warnings.borrow_mut().take()).unwrap_or_default()
+}
+
+/// A fresh, empty directory for a test to keep its files in.
+pub(crate) fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("db-server-{}-{}", process::id(), name));
+
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).expect("the scratch directory is created");
+
+    dir
+}
+
+/// Waits up to a few seconds for `done` to hold, for what happens in the
+/// background.
+fn eventually(mut done: impl FnMut() -> bool) -> bool {
+    let give_up = Instant::now() + Duration::from_secs(5);
+
+    while !done() {
+        if Instant::now() >= give_up {
+            return false;
+        }
+
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    true
+}
+
+/// An upstream on a local port, answering fetches from what it holds and
+/// storing whatever's put to it.
+struct MockUpstream {
+    addr: SocketAddr,
+    values: Arc<Mutex<HashMap<String, String>>>,
+    /// The request line of every request it's been sent, in order.
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockUpstream {
+    fn start(values: &[(&str, &str)]) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("the upstream binds");
+        let values: HashMap<String, String> =
+            values.iter().map(|(key, val)| (String::from(*key), String::from(*val))).collect();
+        let upstream = MockUpstream {
+            addr: listener.local_addr().expect("the upstream has an address"),
+            values: Arc::new(Mutex::new(values)),
+            requests: Arc::new(Mutex::new(Vec::new())),
+        };
+
+        let (values, requests) = (Arc::clone(&upstream.values), Arc::clone(&upstream.requests));
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => break,
+                };
+                let (line, body) = match read_upstream_request(&mut stream) {
+                    Ok(request) => request,
+                    Err(_) => continue,
+                };
+                let mut parts = line.split_whitespace();
+                let (method, path) = (parts.next(), parts.next().unwrap_or_default());
+                let key = decode(path.trim_start_matches('/')).unwrap_or_default();
+
+                requests.lock().unwrap().push(line.clone());
+
+                let mut values = values.lock().unwrap();
+                let response = match (method, values.get(&key)) {
+                    (Some("PUT"), _) => {
+                        values.insert(key, body);
+                        String::from("HTTP/1.0 204 No Content\r\n\r\n")
+                    }
+                    (Some("GET"), Some(val)) => format!("HTTP/1.0 200 OK\r\n\r\n{}", val),
+                    _ => String::from("HTTP/1.0 404 Not Found\r\n\r\n"),
+                };
+
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+
+        upstream
+    }
+
+    fn upstream(&self) -> Upstream {
+        format!("http://{}", self.addr).parse().expect("the upstream URL parses")
+    }
+}
+
+/// Reads the request line and body of one request sent to an upstream.
+fn read_upstream_request(stream: &mut TcpStream) -> io::Result<(String, String)> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    let mut body_len = 0;
+
+    reader.read_line(&mut line)?;
+
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header)?;
+
+        match header.trim_end().split_once(':') {
+            Some((name, len)) if name.eq_ignore_ascii_case("Content-Length") => {
+                body_len = len.trim().parse().unwrap_or(0);
+            }
+            Some(_) => {}
+            None => break,
+        }
+    }
+
+    let mut body = vec![0; body_len];
+    reader.read_exact(&mut body)?;
+
+    Ok((String::from(line.trim_end()), String::from_utf8_lossy(&body).into_owned()))
+}
+
+#[test]
+fn a_deadline_is_the_clients_budget_or_the_default() {
+    let received = Instant::now();
+
+    assert_eq!(
+        headers("X-Deadline-Ms: 250").deadline(received),
+        received + Duration::from_millis(250)
+    );
+    assert_eq!(headers("").deadline(received), received + DEFAULT_TIMEOUT);
+    assert_eq!(headers("X-Deadline-Ms: soon").deadline(received), received + DEFAULT_TIMEOUT);
+}
+
+#[test]
+fn a_request_past_its_deadline_times_out() {
+    let server = server(ServerConfig::default());
+    let within = |budget: &str, request| {
+        let deadline = headers(&format!("X-Deadline-Ms: {}", budget)).deadline(Instant::now());
+        handle_request(request, &server, deadline)
+    };
+
+    assert!(matches!(within("5000", set("key", "1")), Response::SetSuccess));
+    assert!(matches!(within("0", get("key")), Response::Timeout));
+    assert!(matches!(within("5000", get("key")), Response::GetSuccess(_)));
+}
+
+#[test]
+fn getdel_hands_out_the_value_once() {
+    let storage = storage();
+    let getdel = || Request::GetDel(String::from("a"));
+
+    handle(&storage, set("a", "1"));
+
+    assert!(matches!(handle(&storage, getdel()), Response::GetSuccess(val) if *val == 1));
+    assert!(matches!(handle(&storage, get("a")), Response::NotFound));
+    assert!(matches!(handle(&storage, getdel()), Response::NotFound));
+}
+
+#[test]
+fn a_metrics_reset_starts_the_counts_over() {
+    let server = server(ServerConfig::default());
+
+    handle_on(&server, set("a", "1"));
+    handle_on(&server, set("b", "1"));
+    handle_on(&server, get("a"));
+
+    // the reset hands back the counts it cleared
+    match handle_on(&server, Request::ResetMetrics) {
+        Response::Metrics(cleared) => assert_eq!((cleared.sets, cleared.gets), (2, 1)),
+        _ => panic!("a reset answers with the counts"),
+    }
+
+    handle_on(&server, set("c", "1"));
+
+    let counts = server.metrics.reset();
+    assert_eq!((counts.sets, counts.gets), (1, 0));
+}
+
+#[test]
+fn trim_strips_a_trailing_newline_only_when_asked() {
+    let storage = storage();
+    let (_, _, options) = parse_set("GET /set?a=hi&trim=true HTTP/1.1").unwrap();
+    assert!(options.trim);
+
+    handle(&storage, Request::Set(String::from("a"), String::from("hi\n"), options));
+    handle(&storage, set("b", "hi\n"));
+
+    assert_eq!(*lock(&storage).data["a"].value, Value::from("hi"));
+    assert_eq!(*lock(&storage).data["b"].value, Value::from("hi\n"));
+    assert!(parse_set("GET /set?a=hi&trim=maybe HTTP/1.1").is_err());
+}
+
+#[test]
+fn flushing_a_namespace_leaves_the_others_alone() {
+    let storage = storage();
+    let flush = |namespace: &str| Request::FlushNamespace(String::from(namespace));
+
+    for key in ["a:1", "a:2", "a:3", "b:1", "c:1"] {
+        handle(&storage, set(key, "1"));
+    }
+    for key in ["a:3", "c:1"] {
+        lock(&storage).data.get_mut(key).unwrap().expires = Some(now() - 1);
+    }
+
+    // an expired key is flushed along with the rest, but isn't counted
+    assert!(matches!(handle(&storage, flush("a")), Response::NamespaceFlushed(2)));
+    assert!(matches!(handle(&storage, flush("c")), Response::NotFound));
+    assert_eq!(lock(&storage).data.keys().collect::<Vec<_>>(), ["b:1"]);
+
+    assert!(matches!(handle(&storage, flush("a")), Response::NotFound));
+    assert!(matches!(handle(&storage, flush("missing")), Response::NotFound));
+    assert_eq!(parse_flush_namespace("POST /ns/a/flush HTTP/1.1").unwrap(), "a");
+}
+
+#[test]
+fn a_response_over_the_limit_is_replaced_with_a_413() {
+    let mut config = ServerConfig::default();
+    config.max_response_bytes = Some(20);
+
+    // the JSON body is the value along with its quotes
+    let sent = |len| {
+        let response = Response::GetSuccess(Arc::new(Value::from("x".repeat(len))));
+        replies(&written(response, Encoding::Json, &config)).remove(0)
+    };
+
+    assert_eq!(sent(18).status, 200);
+
+    let big = sent(19);
+    assert_eq!(big.status, 413);
+    assert_eq!(big.json()["error"], "too_large");
+}
+
+#[test]
+fn changed_lists_only_keys_written_after_the_cutoff() {
+    let storage = storage();
+    let changed = |storage: &Arc<Mutex<Storage>>, query: &str| {
+        let (since, values) = parse_changed(&format!("GET /changed?{} HTTP/1.1", query)).unwrap();
+
+        match handle(storage, Request::Changed { since, values, scope: String::new() }) {
+            Response::Json(json) => json,
+            _ => panic!("/changed answers with JSON"),
+        }
+    };
+
+    for (key, modified) in [("old", 100), ("cutoff", 200), ("new", 300), ("newer", 400)] {
+        handle(&storage, set(key, "1"));
+        lock(&storage).data.get_mut(key).unwrap().modified = modified;
+    }
+
+    assert_eq!(changed(&storage, "since=200"), serde_json::json!(["new", "newer"]));
+    assert_eq!(
+        changed(&storage, "since=350&values=true"),
+        serde_json::json!({ "newer": 1 })
+    );
+}
+
+#[test]
+fn each_namespace_applies_its_own_default_ttl() {
+    let mut config = ServerConfig::default();
+    config.default_ttl_secs = Some(1_000);
+    config.namespaces.insert(String::from("short"), NamespaceConfig { default_ttl_secs: Some(10) });
+    config.namespaces.insert(String::from("long"), NamespaceConfig { default_ttl_secs: Some(100) });
+
+    let storage = storage();
+
+    for key in ["short:a", "long:a", "other:a", "plain"] {
+        handle_configured(&storage, &config, set(key, "1"));
+    }
+
+    let ttl = |key: &str| {
+        let record = &lock(&storage).data[key];
+        record.expires.map(|expires| expires - record.modified)
+    };
+
+    assert_eq!(ttl("short:a"), Some(10));
+    assert_eq!(ttl("long:a"), Some(100));
+    assert_eq!(ttl("other:a"), Some(1_000));
+    assert_eq!(ttl("plain"), Some(1_000));
+}
+
+#[test]
+fn values_come_back_in_the_encoding_asked_for() {
+    let sent = |accept| {
+        let encoding = Encoding::negotiate(Some(accept)).expect("the encoding is supported");
+        written(Response::GetSuccess(Arc::new(Value::from("hi"))), encoding, &ServerConfig::default())
+    };
+
+    let (head, body) = split(&sent("application/json"));
+    assert!(head.contains("Content-Type: application/json\r\n"));
+    assert_eq!(body, b"\"hi\"");
+
+    let (head, body) = split(&sent("text/plain"));
+    assert!(head.contains("Content-Type: text/plain; charset=utf-8\r\n"));
+    assert_eq!(body, b"hi");
+
+    let (head, body) = split(&sent("application/msgpack"));
+    assert!(head.contains("Content-Type: application/msgpack\r\n"));
+    assert_eq!(rmp_serde::from_slice::<Value>(&body).unwrap(), Value::from("hi"));
+
+    assert_eq!(Encoding::negotiate(Some("image/png")), None);
+}
+
+#[test]
+fn a_miss_is_read_through_and_cached() {
+    let mock = MockUpstream::start(&[("a", "from upstream")]);
+    let mut config = ServerConfig::default();
+    config.upstream = Some(mock.upstream());
+
+    let storage = storage();
+    let fetch = |key: &str| handle_configured(&storage, &config, get(key));
+
+    for _ in 0..2 {
+        let response = fetch("a");
+        assert!(matches!(response, Response::GetSuccess(val) if *val == "from upstream"));
+    }
+
+    // the second get was answered from the store
+    assert_eq!(*mock.requests.lock().unwrap(), ["GET /a HTTP/1.0"]);
+    assert!(matches!(fetch("missing"), Response::NotFound));
+}
+
+#[test]
+fn a_failing_upstream_is_a_miss_or_a_502() {
+    // nothing listens on a port that was bound and let go of again
+    let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+    for bad_gateway in [false, true] {
+        let mut config = ServerConfig::default();
+        config.upstream = Some(format!("http://{}", closed).parse().unwrap());
+        config.upstream_bad_gateway = bad_gateway;
+
+        let response = handle_configured(&storage(), &config, get("a"));
+
+        if bad_gateway {
+            assert!(matches!(response, Response::BadGateway(_)));
+        } else {
+            assert!(matches!(response, Response::NotFound));
+        }
+    }
+}
+
+#[test]
+fn sets_are_written_through_to_the_upstream() {
+    let mock = MockUpstream::start(&[]);
+
+    for mode in [WriteThrough::Sync, WriteThrough::Async] {
+        let mut config = ServerConfig::default();
+        config.upstream = Some(mock.upstream());
+        config.write_through = Some(mode);
+
+        let key = format!("{:?}", mode);
+
+        let response = handle_configured(&storage(), &config, set(&key, "1"));
+        assert!(matches!(response, Response::SetSuccess));
+
+        let stored = || mock.values.lock().unwrap().get(&key).map(String::as_str) == Some("1");
+        assert!(eventually(stored));
+    }
+}
+
+#[test]
+fn a_failed_sync_write_through_is_a_502() {
+    let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut config = ServerConfig::default();
+    config.upstream = Some(format!("http://{}", closed).parse().unwrap());
+    config.write_through = Some(WriteThrough::Sync);
+
+    let storage = storage();
+
+    let response = handle_configured(&storage, &config, set("a", "1"));
+    assert!(matches!(response, Response::BadGateway(_)));
+    assert!(!lock(&storage).data.contains_key("a"));
+}
+
+#[test]
+fn diff_reports_what_changed_since_a_snapshot() {
+    let dir = scratch_dir("diff_reports_what_changed_since_a_snapshot");
+    fs::write(dir.join("stale.json"), r#"{"a": 1, "b": 2, "c": 3}"#).unwrap();
+
+    let mut config = ServerConfig::default();
+    config.snapshot_dir = Some(dir);
+
+    let storage = storage();
+    let diff = |path: &str| Request::Diff(String::from(path));
+
+    for (key, val) in [("a", "1"), ("b", "20"), ("d", "4")] {
+        handle(&storage, set(key, val));
+    }
+
+    match handle_configured(&storage, &config, diff("stale.json")) {
+        Response::Json(json) => assert_eq!(
+            json,
+            serde_json::json!({ "added": ["d"], "removed": ["c"], "changed": ["b"] })
+        ),
+        _ => panic!("a diff answers with JSON"),
+    }
+
+    let outside = handle_configured(&storage, &config, diff("../stale.json"));
+    assert!(matches!(outside, Response::Forbidden));
+}
+
+#[test]
+fn eval_applies_an_expression_to_the_stored_number() {
+    let storage = storage();
+    let eval = |expr: &str| Request::Eval { key: String::from("a"), expr: String::from(expr) };
+
+    handle(&storage, set("a", "4"));
+
+    let evaluated = handle(&storage, eval("value*3"));
+    assert!(matches!(evaluated, Response::GetSuccess(val) if *val == 12));
+    assert_eq!(*lock(&storage).data["a"].value, Value::from(12));
+
+    assert!(matches!(handle(&storage, eval("value*")), Response::BadRequest(_)));
+    assert_eq!(*lock(&storage).data["a"].value, Value::from(12));
+
+    handle(&storage, set("a", "hi"));
+    assert!(matches!(handle(&storage, eval("value*3")), Response::BadRequest(_)));
+}
+
+#[test]
+fn every_config_problem_is_reported_at_once() {
+    let mut config = ServerConfig::default();
+    config.slow_start = Some(Duration::ZERO);
+    config.coalesce_window = Some(Duration::ZERO);
+    config.max_response_bytes = Some(0);
+    config.write_through = Some(WriteThrough::Sync);
+
+    let problems = config.problems();
+
+    assert_eq!(problems.len(), 4, "{:?}", problems);
+    assert!(problems.contains(&String::from("slow_start must be longer than zero")));
+    assert!(problems.contains(&String::from("coalesce_window must be longer than zero")));
+    assert!(problems.contains(&String::from("max_response_bytes must be greater than zero")));
+    assert!(problems.contains(&String::from("write_through requires an upstream")));
+    assert!(ServerConfig::default().problems().is_empty());
+}
+
+#[test]
+fn swr_serves_the_stale_value_and_refreshes_it_behind() {
+    let mock = MockUpstream::start(&[("a", "fresh")]);
+
+    let mut config = ServerConfig::default();
+    config.upstream = Some(mock.upstream());
+
+    let storage = storage();
+    let expire = || lock(&storage).data.get_mut("a").unwrap().expires = Some(now() - 1);
+    let swr = Request::Get(String::from("a"), GetOptions { swr: true });
+
+    handle_configured(&storage, &config, set("a", "stale"));
+    expire();
+
+    let served = handle_configured(&storage, &config, swr);
+    assert!(matches!(served, Response::GetSuccess(val) if *val == "stale"));
+    assert!(eventually(|| {
+        let storage = lock(&storage);
+        storage.refreshing.is_empty() && *storage.data["a"].value == "fresh"
+    }));
+
+    // without the flag, an expired key waits for the upstream instead
+    handle_configured(&storage, &config, set("a", "stale"));
+    expire();
+
+    let fetched = handle_configured(&storage, &config, get("a"));
+    assert!(matches!(fetched, Response::GetSuccess(val) if *val == "fresh"));
+}
+
+#[test]
+fn a_benchmark_reports_its_figures_and_leaves_no_keys_behind() {
+    let mut config = ServerConfig::default();
+    config.benchmark_enabled = true;
+
+    let storage = storage();
+    let benchmark = || Request::Benchmark { ops: 200, keys: 10 };
+    handle(&storage, set("real", "1"));
+
+    let report = match handle_configured(&storage, &config, benchmark()) {
+        Response::Json(report) => report,
+        _ => panic!("a benchmark answers with its report"),
+    };
+
+    assert_eq!((report["ops"].as_u64(), report["keys"].as_u64()), (Some(200), Some(10)));
+    assert!(report["elapsed_ms"].is_number());
+    assert!(report["ops_per_sec"].is_number());
+    for percentile in ["p50", "p90", "p99", "max"] {
+        assert!(report["latency_us"][percentile].is_number(), "{}", percentile);
+    }
+
+    assert_eq!(lock(&storage).data.keys().collect::<Vec<_>>(), ["real"]);
+
+    assert!(matches!(handle(&storage, benchmark()), Response::Forbidden));
+}
+
+#[test]
+fn the_content_hash_matches_the_body() {
+    let mut config = ServerConfig::default();
+    config.content_hash = true;
+
+    let value = || Response::GetSuccess(Arc::new(serde_json::json!({ "b": [1, 2] })));
+
+    let (head, body) = split(&written(value(), Encoding::Json, &config));
+    let hash: String = Sha256::digest(&body).iter().map(|byte| format!("{:02x}", byte)).collect();
+    assert!(head.contains(&format!("X-Content-Hash: {}\r\n", hash)));
+
+    let (head, _) = split(&written(value(), Encoding::Json, &ServerConfig::default()));
+    assert!(!head.contains("X-Content-Hash"));
+}
+
+#[test]
+fn each_key_prefix_sees_its_own_keys() {
+    let storage = storage();
+    let fetch = |request: Request| match handle(&storage, request) {
+        Response::GetSuccess(val) => Some(Value::clone(&val)),
+        _ => None,
+    };
+
+    handle(&storage, set("k", "one").with_key_prefix("a/"));
+    handle(&storage, set("k", "two").with_key_prefix("b/"));
+
+    assert_eq!(fetch(get("k").with_key_prefix("a/")), Some(Value::from("one")));
+    assert_eq!(fetch(get("k").with_key_prefix("b/")), Some(Value::from("two")));
+    assert_eq!(fetch(get("k")), None);
+    assert_eq!(fetch(get("a/k")), Some(Value::from("one")));
+}
+
+#[test]
+fn a_scan_covers_the_keys_that_stay_put_while_others_churn() {
+    let storage = storage();
+
+    for i in 0..500 {
+        handle(&storage, set(&format!("stable{:03}", i), &i.to_string()));
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let (storage, stop) = (Arc::clone(&storage), Arc::clone(&stop));
+
+        thread::spawn(move || {
+            let mut i = 0;
+
+            while !stop.load(Ordering::SeqCst) {
+                handle(&storage, set(&format!("churn{}", i % 50), "1"));
+                handle(&storage, Request::GetDel(format!("churn{}", (i + 25) % 50)));
+                i += 1;
+            }
+        })
+    };
+
+    let mut seen = HashSet::new();
+    let mut cursor = String::new();
+
+    for _ in 0..1_000 {
+        let options = parse_scan(&format!("GET /scan?count=37&cursor={} HTTP/1.1", cursor));
+        let batch = match handle(&storage, Request::Scan(options.unwrap())) {
+            Response::Json(batch) => batch,
+            _ => panic!("a scan answers with JSON"),
+        };
+
+        seen.extend(batch["entries"].as_object().unwrap().keys().cloned());
+        cursor = String::from(batch["cursor"].as_str().unwrap());
+
+        if cursor.is_empty() {
+            break;
+        }
+    }
+
+    stop.store(true, Ordering::SeqCst);
+    writer.join().unwrap();
+
+    assert!(cursor.is_empty(), "the scan never finished");
+    assert!((0..500).all(|i| seen.contains(&format!("stable{:03}", i))));
+}
+
+#[test]
+fn detailed_errors_carry_their_cause() {
+    let mut detailed = ServerConfig::default();
+    detailed.error_verbosity = ErrorVerbosity::Detailed;
+
+    let storage = storage();
+    let eval = || Request::Eval { key: String::from("a"), expr: String::from("value*3") };
+    let body = |config: &ServerConfig| {
+        replies(&written(handle(&storage, eval()), Encoding::Json, config)).remove(0).json()
+    };
+
+    handle(&storage, set("a", "hi"));
+
+    let terse = body(&ServerConfig::default());
+    let detailed = body(&detailed);
+
+    assert_eq!(terse, serde_json::json!({ "error": "not_a_number" }));
+    assert_eq!(detailed["error"], "not_a_number");
+    assert_eq!(detailed["cause"], "value for key=a is not a number");
+}
+
+#[test]
+fn disabled_operations_are_forbidden() {
+    let mut no_getdels = ServerConfig::default();
+    no_getdels.disabled_operations.insert(String::from("getdel"));
+
+    assert!(no_getdels.allows(get("a").operation()));
+    assert!(!no_getdels.allows(Request::GetDel(String::from("a")).operation()));
+
+    let mut only_gets = ServerConfig::default();
+    only_gets.enabled_operations = Some(HashSet::from([String::from("get")]));
+
+    assert!(only_gets.allows(get("a").operation()));
+    assert!(!only_gets.allows(set("a", "1").operation()));
+
+    only_gets.disabled_operations.insert(String::from("fetch"));
+    assert_eq!(only_gets.problems(), ["unknown operation \"fetch\""]);
+}
+
+#[test]
+fn decr_stops_at_its_floor() {
+    let storage = storage();
+    let decr = |key: &str, query: &str| {
+        let (key, by, floor) = parse_decr(&format!("GET /decr?key={}&{} HTTP/1.1", key, query))
+            .expect("the decr parses");
+
+        match handle(&storage, Request::Decr { key, by, floor }) {
+            Response::Json(reply) => reply,
+            _ => panic!("a decr answers with JSON"),
+        }
+    };
+
+    handle(&storage, set("tokens", "5"));
+
+    let reply = decr("tokens", "by=2&floor=0");
+    assert_eq!(reply, serde_json::json!({ "value": 3, "floored": false }));
+
+    let reply = decr("tokens", "by=4&floor=0");
+    assert_eq!(reply, serde_json::json!({ "value": 0, "floored": true }));
+    assert_eq!(*lock(&storage).data["tokens"].value, Value::from(0));
+
+    // a key that isn't there yet starts from zero, floor and all
+    let reply = decr("fresh", "by=3&floor=-1");
+    assert_eq!(reply, serde_json::json!({ "value": -1, "floored": true }));
+}
+
+#[test]
+fn unix_socket_serves_requests() {
+    let dir = scratch_dir("unix_socket");
+    let path = dir.join("db.sock");
+
+    // a socket file left behind by a listener that's gone
+    drop(UnixListener::bind(&path).expect("the socket binds"));
+    assert!(path.exists());
+
+    let listener = bind_unix_socket(&path).expect("the stale socket is cleared away");
+    let local = server(ServerConfig::default());
+
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            for stream in listener.incoming().take(2) {
+                let mut stream = stream.expect("the client connects");
+                local.serve(&mut stream, Instant::now()).expect("the request is served");
+            }
+        });
+
+        let request = |line: &str| {
+            let mut stream = UnixStream::connect(&path).expect("the client connects");
+            let request = format!("GET {} HTTP/1.1\r\n\r\n", line);
+            stream.write_all(request.as_bytes()).expect("the request is sent");
+
+            let mut output = Vec::new();
+            stream.read_to_end(&mut output).expect("the server responds");
+            replies(&output).remove(0)
+        };
+
+        assert_eq!(request("/set?a=1").status, 200);
+        assert_eq!(request("/get?key=a").json(), Value::from(1));
+    });
+}
+
+#[test]
+fn explain_names_a_malformed_sets_error_code() {
+    let explanation = super::explain("GET /set?a HTTP/1.1", DuplicateParams::default());
+
+    assert_eq!(explanation["method"], "GET");
+    assert!(explanation.get("handler").is_none());
+
+    let error = explanation["error"].as_str().expect("the parse error is explained");
+    assert!(error.contains("improperly formatted (3)"), "{}", error);
+
+    let explanation = super::explain("GET /set?a=1 HTTP/1.1", DuplicateParams::default());
+    assert_eq!(explanation["handler"], "set");
+    assert_eq!(explanation["key"], "a");
+}
+
+#[test]
+fn many_keys_with_one_large_value_share_it() {
+    let value = "x".repeat(10_000);
+    let fill = |dedup| {
+        let storage = storage();
+        lock(&storage).dedup = dedup;
+
+        for i in 0..50 {
+            handle(&storage, set(&format!("key{}", i), &value));
+        }
+
+        storage
+    };
+
+    let shared = fill(true);
+    let held = lock(&shared);
+    assert!(Arc::ptr_eq(&held.data["key0"].value, &held.data["key49"].value));
+    assert_eq!(*held.data["key49"].value, Value::from(value.as_str()));
+
+    let copied = fill(false);
+    let held = lock(&copied);
+    assert!(!Arc::ptr_eq(&held.data["key0"].value, &held.data["key49"].value));
+}
+
+#[test]
+fn an_inherited_listener_is_used_as_it_is() {
+    use std::os::unix::io::IntoRawFd;
+
+    // the socket a previous instance would hand over, already bound
+    let handed_over = TcpListener::bind("127.0.0.1:0").expect("the listener binds");
+    let addr = handed_over.local_addr().expect("the listener has an address");
+
+    let listener = inherit_listener(handed_over.into_raw_fd()).expect("the fd is taken over");
+    assert_eq!(listener.local_addr().expect("the listener has an address"), addr);
+
+    let local = server(ServerConfig::default());
+
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            let (mut stream, _) = listener.accept().expect("the client connects");
+            local.serve(&mut stream, Instant::now()).expect("the request is served");
+        });
+
+        let mut stream = TcpStream::connect(addr).expect("the client connects");
+        stream.write_all(b"GET /set?a=1 HTTP/1.1\r\n\r\n").expect("the request is sent");
+
+        let mut output = Vec::new();
+        stream.read_to_end(&mut output).expect("the server responds");
+        assert!(split(&output).0.starts_with("HTTP/1.1 200 OK\r\n"));
+    });
+
+    // a descriptor that isn't a socket is refused up front
+    let file = fs::File::open(file!()).expect("the file opens");
+    assert!(inherit_listener(file.into_raw_fd()).is_err());
+}
+
+#[test]
+fn metadata_is_kept_apart_from_the_value_and_goes_with_the_key() {
+    let storage = storage();
+    let setmeta = || Request::SetMeta {
+        key: String::from("a"),
+        field: String::from("owner"),
+        value: String::from("ops"),
+    };
+    let getmeta = |field: &str| {
+        let request = Request::GetMeta { key: String::from("a"), field: String::from(field) };
+        match handle(&storage, request) {
+            Response::GetSuccess(val) => Some(Value::clone(&val)),
+            _ => None,
+        }
+    };
+
+    assert!(matches!(handle(&storage, setmeta()), Response::NotFound));
+
+    handle(&storage, set("a", "1"));
+    assert!(matches!(handle(&storage, setmeta()), Response::SetSuccess));
+    assert_eq!(getmeta("owner"), Some(Value::from("ops")));
+    assert_eq!(getmeta("tags"), None);
+    assert!(matches!(handle(&storage, get("a")), Response::GetSuccess(val) if *val == 1));
+
+    // a key set again after it's taken starts with no metadata
+    handle(&storage, Request::GetDel(String::from("a")));
+    assert_eq!(getmeta("owner"), None);
+    handle(&storage, set("a", "2"));
+    assert_eq!(getmeta("owner"), None);
+}
+
+#[test]
+fn a_subset_snapshot_holds_exactly_the_keys_asked_for() {
+    let dir = scratch_dir("snapshot_subset");
+
+    let mut config = ServerConfig::default();
+    config.snapshot_dir = Some(dir.clone());
+
+    let storage = storage();
+    for (key, val) in [("a1", "1"), ("a2", "2"), ("b1", "3")] {
+        handle(&storage, set(key, val));
+    }
+
+    let subset = |path: &str, selection| {
+        let request = Request::SnapshotSubset { path: String::from(path), selection };
+        handle_configured(&storage, &config, request)
+    };
+    let saved = |file: &str| {
+        let json = fs::read(dir.join(file)).expect("the snapshot was written");
+        let saved: HashMap<String, Record> = serde_json::from_slice(&json).expect("it parses");
+        let mut keys: Vec<String> = saved.into_keys().collect();
+        keys.sort_unstable();
+        keys
+    };
+
+    // keys the store doesn't have are left out rather than failing
+    let keys = ["a1", "b1", "gone"].map(String::from).to_vec();
+    match subset("keys.json", Selection::Keys(keys)) {
+        Response::Json(json) => assert_eq!(json["written"], 2),
+        _ => panic!("a snapshot answers with JSON"),
+    }
+    assert_eq!(saved("keys.json"), ["a1", "b1"]);
+
+    match subset("prefix.json", Selection::Prefix(String::from("a"))) {
+        Response::Json(json) => assert_eq!(json["written"], 2),
+        _ => panic!("a snapshot answers with JSON"),
+    }
+    assert_eq!(saved("prefix.json"), ["a1", "a2"]);
+
+    let outside = subset("../out.json", Selection::Prefix(String::from("a")));
+    assert!(matches!(outside, Response::Forbidden));
+}
+
+#[test]
+fn if_hash_sets_only_over_the_value_it_names() {
+    let storage = storage();
+    handle(&storage, set("a", "1"));
+
+    let one = hex_encode(&content_hash(&Value::from(1)));
+    let set_if = |key: &str, val: &str, hash: &str| {
+        let line = format!("GET /set?{}={}&if_hash={} HTTP/1.1", key, val, hash);
+        let (key, val, options) = parse_set(&line).expect("the set parses");
+        handle(&storage, Request::Set(key, val, options))
+    };
+    let value = |key: &str| match handle(&storage, get(key)) {
+        Response::GetSuccess(val) => Some(Value::clone(&val)),
+        _ => None,
+    };
+
+    // hashes are compared without regard to case
+    assert!(matches!(set_if("a", "2", &one.to_ascii_uppercase()), Response::SetSuccess));
+    assert_eq!(value("a"), Some(Value::from(2)));
+
+    // the value has moved on, so the old hash no longer matches
+    assert!(matches!(set_if("a", "3", &one), Response::Conflict(_)));
+    assert_eq!(value("a"), Some(Value::from(2)));
+
+    assert!(matches!(set_if("b", "1", &one), Response::Conflict(_)));
+    assert_eq!(value("b"), None);
+}
+
+#[test]
+fn keys_at_the_limit_pass_and_one_byte_more_does_not() {
+    let mut config = ServerConfig::default();
+    config.max_key_bytes = Some(8);
+
+    let server = server(config);
+    let (fits, over) = ("k".repeat(8), "k".repeat(9));
+
+    for target in ["/set?{}=1", "/get?key={}", "/getdel?key={}"] {
+        let request = |key: &str| format!("GET {} HTTP/1.1\r\n\r\n", target.replace("{}", key));
+
+        assert_eq!(send(&server, &request(&fits)).status, 200, "{}", target);
+
+        let reply = send(&server, &request(&over));
+        assert_eq!(reply.status, 400, "{}", target);
+        assert!(String::from_utf8_lossy(&reply.body).contains("key_too_long"), "{}", target);
+    }
+}
+
+#[test]
+fn debug_expired_lists_keys_nothing_has_reaped_yet() {
+    let storage = storage();
+
+    for key in ["a", "b", "live"] {
+        handle(&storage, set(key, "1"));
+    }
+    for key in ["a", "b"] {
+        lock(&storage).data.get_mut(key).expect("the key is stored").expires = Some(now() - 1);
+    }
+
+    let listed = || match handle(&storage, Request::Expired { scope: String::new() }) {
+        Response::Json(json) => {
+            let listed = json.as_array().expect("a list");
+            listed.iter().map(|entry| entry["key"].clone()).collect::<Vec<_>>()
+        }
+        _ => panic!("the expired keys are listed as JSON"),
+    };
+    assert_eq!(listed(), ["a", "b"]);
+
+    // reading an expired key reaps it
+    assert!(matches!(handle(&storage, get("a")), Response::NotFound));
+    assert_eq!(listed(), ["b"]);
+}
+
+#[test]
+fn a_failed_if_hash_set_can_answer_with_the_current_value() {
+    let mut config = ServerConfig::default();
+    config.conflict_returns_value = true;
+
+    let returning = server(config);
+    let one = hex_encode(&content_hash(&Value::from("1")));
+    let request = |line: &str| format!("GET {} HTTP/1.1\r\n\r\n", line);
+
+    send(&returning, &request("/set?a=2"));
+
+    let reply = send(&returning, &request(&format!("/set?a=3&if_hash={}", one)));
+    assert_eq!(reply.status, 409);
+    assert_eq!(reply.json(), serde_json::json!({ "current": 2 }));
+
+    // a key that isn't there has no current value
+    let reply = send(&returning, &request(&format!("/set?b=3&if_hash={}", one)));
+    assert_eq!(reply.json(), serde_json::json!({ "current": null }));
+
+    // without the flag, a conflict is only an error
+    let terse = server(ServerConfig::default());
+    let reply = send(&terse, &request(&format!("/set?b=3&if_hash={}", one)));
+    assert_eq!(reply.status, 409);
+    assert_eq!(reply.json()["error"], "conflict");
+}
+
+#[test]
+fn a_buffered_response_takes_one_write() {
+    let writes = |write_buffer_bytes| {
+        let mut config = ServerConfig::default();
+        config.write_buffer_bytes = write_buffer_bytes;
+
+        let mut stream = Exchange::new("GET /set?a=1 HTTP/1.1\r\n\r\n");
+        server(config).serve(&mut stream, Instant::now()).expect("the request is served");
+
+        assert_eq!(replies(&stream.output)[0].status, 200);
+        stream.writes
+    };
+
+    // the status line and the page are gathered into the one write
+    let (unbuffered, buffered) = (writes(None), writes(Some(8 * 1024)));
+    assert_eq!(buffered, 1);
+    assert!(unbuffered > buffered, "{} writes unbuffered", unbuffered);
+}
+
+#[test]
+fn the_bloom_filter_never_hides_a_stored_key() {
+    let mut config = ServerConfig::default();
+    config.bloom_filter_keys = Some(100);
+
+    let filtered = server(config);
+    let value = |key: &str| match handle_on(&filtered, get(key)) {
+        Response::GetSuccess(val) => Some(Value::clone(&val)),
+        _ => None,
+    };
+
+    for i in 0..50 {
+        handle_on(&filtered, set(&format!("key{}", i), &i.to_string()));
+    }
+    for i in 0..50 {
+        assert_eq!(value(&format!("key{}", i)), Some(Value::from(i)));
+    }
+
+    assert_eq!(value("missing"), None);
+    let deleted = handle_on(&filtered, Request::Delete(String::from("key0")));
+    assert!(matches!(deleted, Response::DeleteSuccess));
+    assert_eq!(value("key0"), None);
+}
+
+#[test]
+fn a_repeated_key_goes_by_the_configured_policy() {
+    let request = |line: &str| format!("GET {} HTTP/1.1\r\nAccept: application/json\r\n\r\n", line);
+    let answers = [(DuplicateParams::First, "1"), (DuplicateParams::Last, "2")];
+
+    for (policy, expected) in answers {
+        let mut config = ServerConfig::default();
+        config.duplicate_params = policy;
+
+        let repeated = server(config);
+        send(&repeated, &request("/set?a=1"));
+        send(&repeated, &request("/set?b=2"));
+
+        let reply = send(&repeated, &request("/get?key=a&key=b"));
+        assert_eq!(reply.body, expected.as_bytes(), "{:?}", policy);
+    }
+
+    let mut config = ServerConfig::default();
+    config.duplicate_params = DuplicateParams::Reject;
+
+    let rejecting = server(config);
+    send(&rejecting, &request("/set?a=1"));
+
+    assert_eq!(send(&rejecting, &request("/get?key=a&key=b")).status, 400);
+    assert_eq!(send(&rejecting, &request("/get?key=a")).status, 200);
+}
+
+#[test]
+fn a_value_longer_than_one_read_is_stored_whole() {
+    let server = server(ServerConfig::default());
+    let value: String = (0..4096).map(|i| char::from(b'a' + (i % 26) as u8)).collect();
+
+    // the request line arrives a little at a time, well short of the value
+    let request = format!("GET /set?a={} HTTP/1.1\r\n\r\n", value);
+    let mut stream = Exchange::new(request);
+    stream.chunk = 100;
+
+    server.serve(&mut stream, Instant::now()).expect("the request is served");
+    assert_eq!(replies(&stream.output)[0].status, 200);
+    assert_eq!(*lock(&server.storage).data["a"].value, Value::from(value));
+}
+
+#[test]
+fn connections_arriving_after_the_shutdown_are_not_served() {
+    let server = Arc::new(server(ServerConfig::default()));
+    let pool = ThreadPool::new(1);
+    let shutdown = AtomicBool::new(true);
+
+    // the connection a Ctrl-C makes to wake the accept loop
+    let incoming = std::iter::once(Ok(Exchange::new("GET /set?a=1 HTTP/1.1\r\n\r\n")));
+
+    accept(&server, &pool, incoming, &shutdown).expect("the loop ends cleanly");
+    drop(pool);
+
+    assert!(lock(&server.storage).data.is_empty());
+}
+
+#[test]
+fn a_stored_template_renders_with_the_variables_given() {
+    let storage = storage();
+    let render = |key: &str, vars: &[(&str, &str)]| {
+        let vars = vars.iter().map(|&(name, val)| (String::from(name), String::from(val)));
+        handle(&storage, Request::Render { key: String::from(key), vars: vars.collect() })
+    };
+    let failure = |response| match response {
+        Response::BadRequest(failure) => failure.code,
+        _ => panic!("the render is refused"),
+    };
+
+    handle(&storage, set("greeting", "Hello, {{name}}!"));
+
+    let rendered = render("greeting", &[("name", "Alice")]);
+    assert!(matches!(rendered, Response::GetSuccess(val) if *val == "Hello, Alice!"));
+
+    // the template itself is left as it was
+    assert_eq!(*lock(&storage).data["greeting"].value, Value::from("Hello, {{name}}!"));
+
+    assert_eq!(failure(render("greeting", &[])), "missing_placeholder");
+
+    handle(&storage, set("number", "1"));
+    assert_eq!(failure(render("number", &[])), "not_a_template");
+    assert!(matches!(render("missing", &[]), Response::NotFound));
+}
+
+#[test]
+fn the_last_request_a_connection_may_make_closes_it() {
+    let mut config = ServerConfig::default();
+    config.keepalive_max_requests = Some(3);
+
+    let server = server(config);
+    let replies = exchange(&server, "GET /set?a=1 HTTP/1.1\r\n\r\n".repeat(5));
+
+    // the fourth and fifth requests are never read
+    assert_eq!(replies.len(), 3);
+    assert_eq!(replies[2].header("Connection"), Some("close"));
+    for reply in &replies[..2] {
+        assert_ne!(reply.header("Connection"), Some("close"));
+    }
+}
+
+#[test]
+fn trace_echoes_the_head_verbatim_once_enabled() {
+    let head = "GET /trace HTTP/1.1\r\nX-Forwarded-For: 10.0.0.1\r\nx-odd-CASE:  spaced \r\n\r\n";
+
+    assert_eq!(send(&server(ServerConfig::default()), head).status, 403);
+
+    let mut config = ServerConfig::default();
+    config.trace_enabled = true;
+
+    let reply = send(&server(config), head);
+    assert_eq!(reply.status, 200);
+    assert_eq!(String::from_utf8_lossy(&reply.body), head);
+}
+
+#[test]
+fn set_values_keep_their_json_types() {
+    let storage = storage();
+    let typed = [
+        ("5", Value::from(5)),
+        ("-12", Value::from(-12)),
+        ("2.5", Value::from(2.5)),
+        ("true", Value::from(true)),
+        ("false", Value::from(false)),
+        ("null", Value::Null),
+        ("word", Value::from("word")),
+        // quoted, a number stays a string
+        ("\"5\"", Value::from("5")),
+    ];
+
+    for (sent, expected) in typed {
+        handle(&storage, set("a", sent));
+        assert_eq!(*lock(&storage).data["a"].value, expected, "{}", sent);
+    }
+}
+
+#[test]
+fn if_type_sets_only_over_a_value_of_that_type() {
+    let server = server(ServerConfig::default());
+    let status = |query: &str| {
+        send(&server, &format!("GET /set?{} HTTP/1.1\r\n\r\n", query)).status
+    };
+    let count = || Value::clone(&lock(&server.storage).data["count"].value);
+
+    // an absent key has no type to go against
+    assert_eq!(status("count=5&if_type=string"), 200);
+
+    assert_eq!(status("count=6&if_type=int"), 200);
+    assert_eq!(count(), Value::from(6));
+
+    // a number mustn't be turned into a string by mistake
+    assert_eq!(status("count=six&if_type=string"), 409);
+    assert_eq!(status("count=6.5&if_type=float"), 409);
+    assert_eq!(count(), Value::from(6));
+
+    assert_eq!(status("count=7&if_type=number"), 200);
+
+    // an unknown type is a malformed request
+    assert_eq!(send(&server, "GET /set?count=8&if_type=bogus HTTP/1.1\r\n\r\n").status, 400);
+    assert_eq!(count(), Value::from(7));
+}
+
+#[test]
+fn oversized_values_are_truncated_or_refused_as_configured() {
+    let get = |server: &Server, target: &str| {
+        send(server, &format!("GET {} HTTP/1.1\r\n\r\n", target))
+    };
+
+    for policy in [OversizedValues::Reject, OversizedValues::Truncate] {
+        let mut config = ServerConfig::default();
+        config.max_value_bytes = Some(10);
+        config.oversized_values = policy;
+
+        let limited = server(config);
+        let reply = get(&limited, &format!("/set?a={}", "x".repeat(10)));
+        assert_eq!(reply.status, 200, "{:?}", policy);
+        assert_eq!(reply.header("Warning"), None, "{:?}", policy);
+
+        let reply = get(&limited, &format!("/set?b={}", "y".repeat(11)));
+        match policy {
+            OversizedValues::Reject => {
+                assert_eq!(reply.status, 413);
+                assert_eq!(get(&limited, "/get?key=b").status, 404);
+            }
+            OversizedValues::Truncate => {
+                assert_eq!(reply.status, 200);
+                assert_eq!(reply.header("Warning"), Some("199 - \"value truncated to 10 bytes\""));
+                assert_eq!(get(&limited, "/get?key=b").json(), Value::from("y".repeat(10)));
+            }
+        }
+    }
+
+    // a character that would be split is left out whole
+    let mut config = ServerConfig::default();
+    config.max_value_bytes = Some(10);
+    config.oversized_values = OversizedValues::Truncate;
+
+    let truncating = server(config);
+    assert_eq!(get(&truncating, "/set?c=xxxxxxxxx\u{e9}").json()["truncated"], 9);
+    assert_eq!(get(&truncating, "/get?key=c").json(), Value::from("x".repeat(9)));
+}
+
+#[test]
+fn keys_and_values_are_percent_decoded() {
+    let server = server(ServerConfig::default());
+    let get = |target: &str| send(&server, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+    let decoded = [
+        ("/set?my%20key=two%20words", "my%20key", "two words"),
+        ("/set?eq=a%3Db%26c", "eq", "a=b&c"),
+        ("/set?caf%C3%A9=%E6%97%A5%E6%9C%AC", "caf%C3%A9", "日本"),
+    ];
+
+    for (set, key, value) in decoded {
+        assert_eq!(get(set).status, 200, "{}", set);
+        assert_eq!(get(&format!("/get?key={}", key)).json(), Value::from(value));
+    }
+
+    let mut keys = lock(&server.storage).data.keys().cloned().collect::<Vec<_>>();
+    keys.sort_unstable();
+    assert_eq!(keys, ["café", "eq", "my key"]);
+
+    // a value that isn't UTF-8 once decoded is a malformed request
+    assert_eq!(send(&server, "GET /set?bad=%FF HTTP/1.1\r\n\r\n").status, 400);
+}
+
+#[test]
+fn keys_are_listed_in_order_under_a_prefix() {
+    let server = server(ServerConfig::default());
+    let get = |target: &str| send(&server, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+    assert_eq!(get("/keys").json(), serde_json::json!([]));
+
+    for set in ["/set?user%3Ab=1", "/set?order%3A1=2", "/set?user%3Aa=3", "/set?gone=4"] {
+        assert_eq!(get(set).status, 200);
+    }
+    expire(&server, "gone");
+
+    let (all, users) = (["order:1", "user:a", "user:b"], ["user:a", "user:b"]);
+    assert_eq!(get("/keys").json(), serde_json::json!(all));
+    assert_eq!(get("/keys?prefix=user%3A").json(), serde_json::json!(users));
+    assert_eq!(get("/keys?prefix=none").json(), serde_json::json!([]));
+}
+
+#[test]
+fn exists_answers_without_the_value() {
+    let server = server(ServerConfig::default());
+    let get = |target: &str| send(&server, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+    assert_eq!(get(&format!("/set?big={}", "x".repeat(1_000))).status, 200);
+
+    let present = get("/exists?key=big");
+    assert_eq!(present.status, 200);
+    assert_eq!(present.body, b"true");
+
+    // a missing key is still a successful answer
+    let absent = get("/exists?key=missing");
+    assert_eq!(absent.status, 200);
+    assert_eq!(absent.body, b"false");
+}
+
+#[test]
+fn a_scan_over_the_memory_budget_is_refused_cleanly() {
+    let mut config = ServerConfig::default();
+    config.request_memory_bytes = Some(1_000);
+
+    let server = server(config);
+    let get = |target: &str| send(&server, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+    for i in 0..20 {
+        assert_eq!(get(&format!("/set?key{:02}={}", i, "x".repeat(100))).status, 200);
+    }
+
+    let reply = get("/scan");
+    assert_eq!(reply.status, 507);
+    assert_eq!(reply.json(), serde_json::json!({ "error": "insufficient_storage" }));
+
+    // a batch small enough to fit still goes through, as does the listing
+    assert_eq!(get("/scan?count=2").status, 200);
+    assert_eq!(get("/keys").json().as_array().map(Vec::len), Some(20));
+}
+
+#[test]
+fn a_get_on_a_connection_sees_the_set_before_it() {
+    let mut config = ServerConfig::default();
+    config.keepalive_max_requests = Some(1_000);
+
+    let server = server(config);
+    let session: String = (0..500)
+        .map(|i| format!("GET /set?a={} HTTP/1.1\r\n\r\nGET /get?key=a HTTP/1.1\r\n\r\n", i))
+        .collect();
+
+    let replies = exchange(&server, session);
+    assert_eq!(replies.len(), 1_000);
+
+    for (i, pair) in replies.chunks(2).enumerate() {
+        assert_eq!(pair[0].status, 200);
+        assert_eq!(pair[1].json(), Value::from(i), "get {}", i);
+    }
+}
+
+#[test]
+fn a_ttl_on_set_overrides_the_default() {
+    let mut config = ServerConfig::default();
+    config.default_ttl_secs = Some(1_000);
+
+    let server = server(config);
+    let ttl = |key: &str| {
+        let record = &lock(&server.storage).data[key];
+        record.expires.map(|expires| expires - record.modified)
+    };
+
+    assert_eq!(send(&server, "GET /set?a=1&ttl=5 HTTP/1.1\r\n\r\n").status, 200);
+    assert_eq!(send(&server, "GET /set?b=1 HTTP/1.1\r\n\r\n").status, 200);
+    assert_eq!(ttl("a"), Some(5));
+    assert_eq!(ttl("b"), Some(1_000));
+
+    // a ttl that isn't a positive whole number is a malformed request
+    assert_eq!(send(&server, "GET /set?c=1&ttl=0 HTTP/1.1\r\n\r\n").status, 400);
+    assert_eq!(send(&server, "GET /set?c=1&ttl=soon HTTP/1.1\r\n\r\n").status, 400);
+    assert!(!lock(&server.storage).data.contains_key("c"));
+}
+
+#[test]
+fn the_inferred_schema_lists_the_fields_objects_share() {
+    let server = server(ServerConfig::default());
+    let get = |target: &str| send(&server, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+    let values = [
+        ("u1", serde_json::json!({ "id": 1, "name": "a" })),
+        ("u2", serde_json::json!({ "id": 2, "name": "b", "age": 30 })),
+        ("u3", serde_json::json!({ "id": 3, "age": "unknown" })),
+        ("u4", Value::from("not an object")),
+        ("other", serde_json::json!({ "elsewhere": true })),
+    ];
+
+    for (key, value) in &values {
+        let value = utf8_percent_encode(&value.to_string(), NON_ALPHANUMERIC).to_string();
+        assert_eq!(get(&format!("/set?{}={}", key, value)).status, 200);
+    }
+
+    let schema = get("/infer-schema?prefix=u").json();
+    assert_eq!(schema["sampled"], 4);
+    assert_eq!(schema["types"], serde_json::json!({ "object": 3, "string": 1 }));
+
+    let fields = &schema["fields"];
+    assert_eq!(fields["id"], serde_json::json!({ "types": { "int": 3 }, "presence": 1.0 }));
+    assert_eq!(fields["name"]["types"], serde_json::json!({ "string": 2 }));
+    assert_eq!(fields["age"]["types"], serde_json::json!({ "int": 1, "string": 1 }));
+    assert_eq!(fields["age"]["presence"], 2.0 / 3.0);
+    assert!(fields.get("elsewhere").is_none());
+
+    assert_eq!(get("/infer-schema?prefix=u&sample=2").json()["sampled"], 2);
+
+    // a sample of nothing is a malformed request
+    assert_eq!(send(&server, "GET /infer-schema?sample=0 HTTP/1.1\r\n\r\n").status, 400);
+}
+
+#[test]
+fn a_key_moves_between_namespaces_unless_nx_finds_it_taken() {
+    let server = server(ServerConfig::default());
+    let get = |target: &str| send(&server, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+    assert_eq!(get("/set?old%3Aa=1").status, 200);
+
+    assert_eq!(get("/move?key=a&from_ns=old&to_ns=new").status, 200);
+    assert_eq!(get("/get?key=new%3Aa").json(), Value::from(1));
+    assert_eq!(get("/exists?key=old%3Aa").json(), Value::from(false));
+
+    // gone from the source now, so there's nothing left to move
+    assert_eq!(get("/move?key=a&from_ns=old&to_ns=new").status, 404);
+
+    assert_eq!(get("/set?old%3Aa=2").status, 200);
+    assert_eq!(get("/move?key=a&from_ns=old&to_ns=new&nx=true").status, 409);
+    assert_eq!(get("/get?key=new%3Aa").json(), Value::from(1));
+    assert_eq!(get("/get?key=old%3Aa").json(), Value::from(2));
+
+    // without nx, the target is overwritten
+    assert_eq!(get("/move?key=a&from_ns=old&to_ns=new").status, 200);
+    assert_eq!(get("/get?key=new%3Aa").json(), Value::from(2));
+}
+
+#[test]
+fn a_set_value_may_hold_equals_signs() {
+    let server = server(ServerConfig::default());
+    let get = |target: &str| send(&server, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+
+    assert_eq!(get("/set?token=YWJj==").status, 200);
+    assert_eq!(get("/get?key=token").json(), Value::from("YWJj=="));
+
+    assert_eq!(get("/set?expr=a=b=c&ttl=60").status, 200);
+    assert_eq!(get("/get?key=expr").json(), Value::from("a=b=c"));
+
+    // a trailing & with nothing after it adds no parameter
+    assert_eq!(get("/set?trailing=1&").status, 200);
+    assert_eq!(get("/get?key=trailing").json(), Value::from(1));
+
+    // an empty key is a malformed request
+    assert_eq!(send(&server, "GET /set?=1 HTTP/1.1\r\n\r\n").status, 400);
+}
+
+#[test]
+fn the_favicon_gets_an_empty_answer_rather_than_an_error() {
+    let reply = send(&server(ServerConfig::default()), "GET /favicon.ico HTTP/1.1\r\n\r\n");
+
+    assert_eq!(reply.status, 204);
+    assert!(reply.body.is_empty());
+}
+
+/// A store holding one key, persisted to a file called `persisted.json` in
+/// `dir`.
+fn file_backed_store(dir: &Path, persist_on_drop: bool) -> Storage {
+    let backend = Arc::new(JsonFileBackend::new(dir.join("persisted.json")));
+    let mut storage = empty_store(backend, persist_on_drop);
+
+    storage.insert(String::from("a"), Record::new(Value::from(1)));
+
+    storage
+}
+
+#[test]
+fn a_store_kept_in_memory_writes_nothing_on_drop() {
+    let dir = scratch_dir("a_store_kept_in_memory_writes_nothing_on_drop");
+
+    drop(file_backed_store(&dir, false));
+
+    assert!(!dir.join("persisted.json").exists());
+}
+
+#[test]
+fn a_persisted_store_is_saved_on_drop() {
+    let dir = scratch_dir("a_persisted_store_is_saved_on_drop");
+
+    drop(file_backed_store(&dir, true));
+
+    let saved = fs::read_to_string(dir.join("persisted.json")).expect("the store was saved");
+    assert!(Storage::load(&saved).expect("the file parses").contains_key("a"));
+}
+
+#[test]
+fn a_posted_set_takes_its_value_from_the_body() {
+    let server = server(ServerConfig::default());
+    let post = |target: &str, body: &str| {
+        let head = format!("POST {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n", target, body.len());
+        send(&server, &(head + body))
+    };
+
+    assert_eq!(post("/set?key=doc", r#"{"a": [1, "b&c=d"]}"#).status, 200);
+    assert_eq!(*lock(&server.storage).data["doc"].value, serde_json::json!({ "a": [1, "b&c=d"] }));
+
+    assert_eq!(post("/set?key=n&ttl=60", "12").status, 200);
+    assert_eq!(*lock(&server.storage).data["n"].value, Value::from(12));
+    assert!(lock(&server.storage).data["n"].expires.is_some());
+}
+
+#[test]
+fn the_store_saves_through_its_backend() {
+    let backend = Arc::new(InMemory::default());
+    let storage = Arc::new(Mutex::new(empty_store(Arc::clone(&backend) as _, true)));
+
+    assert!(matches!(handle(&storage, set("a", "1")), Response::SetSuccess));
+    assert!(matches!(handle(&storage, set("b", "2")), Response::SetSuccess));
+
+    // nothing's saved until the store is flushed, here on its way out
+    assert!(backend.0.lock().unwrap().is_empty());
+    drop(storage);
+
+    let saved = backend.0.lock().unwrap();
+    assert_eq!(*saved["a"].value, Value::from(1));
+    assert_eq!(*saved["b"].value, Value::from(2));
+}
+
+#[test]
+fn racing_getorcreates_agree_on_one_value() {
+    let server = server(ServerConfig::default());
+    let get = |target: &str| send(&server, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+
+    let answers: Vec<Value> = thread::scope(|scope| {
+        let callers: Vec<_> = (0..16)
+            .map(|i| {
+                let (get, target) = (&get, format!("/getorcreate?key=id&default={}", i));
+                scope.spawn(move || get(&target).json())
+            })
+            .collect();
+
+        callers.into_iter().map(|caller| caller.join().unwrap()).collect()
+    });
+
+    let stored = get("/get?key=id").json();
+    assert!(answers.iter().all(|answer| *answer == stored), "{:?}", answers);
+    assert_eq!(server.metrics.snapshot().sets, 1);
+}
+
+#[test]
+fn rejected_requests_are_logged_with_the_client_and_reason() {
+    let mut config = ServerConfig::default();
+    config.log_rejected = true;
+    config.max_key_bytes = Some(8);
+    config.disabled_operations.insert(String::from("delete"));
+
+    let logged = server(config);
+    let get = |target: &str| send(&logged, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+    let warnings = warnings_during(|| {
+        // an admin request on a server without credentials
+        assert_eq!(send(&logged, "POST /diff?path=a HTTP/1.1\r\n\r\n").status, 403);
+        assert_eq!(get("/get?key=much_too_long").status, 400);
+        assert_eq!(get("/delete?key=a").status, 403);
+        assert_eq!(get("/get?key=a").status, 404);
+    });
+
+    assert_eq!(warnings.len(), 3, "{:?}", warnings);
+    assert!(warnings[0].starts_with("rejected client=test reason=admin_disabled: "));
+    assert!(warnings[1].starts_with("rejected client=test reason=key_too_long: "));
+    assert!(warnings[2].starts_with("rejected client=test reason=operation_disabled: "));
+
+    // without the flag, turning a request away isn't a warning
+    let quiet = server(ServerConfig::default());
+    let warnings = warnings_during(|| {
+        assert_eq!(send(&quiet, "POST /diff?path=a HTTP/1.1\r\n\r\n").status, 403);
+    });
+    assert!(warnings.is_empty(), "{:?}", warnings);
+}
+
+#[test]
+fn keys_are_counted_by_their_first_segment() {
+    let server = server(ServerConfig::default());
+    let get = |target: &str| send(&server, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+
+    for set in ["/set?user%3A1=a", "/set?user%3A2=b", "/set?order%3A1=c", "/set?loose=d"] {
+        assert_eq!(get(set).status, 200);
+    }
+
+    let counts = get("/count-by-prefix?delimiter=%3A").json();
+    assert_eq!(counts, serde_json::json!({ "user": 2, "order": 1, "loose": 1 }));
+
+    let counts = get("/count-by-prefix?delimiter=1").json();
+    assert_eq!(counts, serde_json::json!({ "user:": 1, "order:": 1, "user:2": 1, "loose": 1 }));
+
+    // an empty delimiter is a malformed request
+    assert_eq!(send(&server, "GET /count-by-prefix?delimiter= HTTP/1.1\r\n\r\n").status, 400);
+}
+
+#[test]
+fn a_malformed_request_is_answered_with_a_400() {
+    let server = server(ServerConfig::default());
+    let get = |target: &str| send(&server, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+
+    let reply = get("/garbage");
+    assert_eq!(reply.status, 400);
+    assert_eq!(reply.json()["error"], "malformed_request");
+    assert_eq!(reply.json()["message"], "No endpoint matches the request");
+
+    // a parse error's code and what it means are spelled out
+    let reply = get("/set?a=1&ttl=soon");
+    let message = reply.json()["message"].as_str().map(String::from).unwrap_or_default();
+    assert_eq!(reply.status, 400);
+    assert!(message.contains("(21): ttl must be a positive whole number"), "{}", message);
+}
+
+#[test]
+fn mget_answers_each_key_in_the_order_asked() {
+    let server = server(ServerConfig::default());
+    let get = |target: &str| send(&server, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+
+    for set in ["/set?b=1", "/set?a=2", "/set?a%2Cb=3", "/set?old=4"] {
+        assert_eq!(get(set).status, 200);
+    }
+    expire(&server, "old");
+
+    let reply = get("/mget?keys=b,missing,a%2Cb,old,a,b");
+    assert_eq!(reply.status, 200);
+    assert_eq!(
+        String::from_utf8(reply.body).unwrap(),
+        r#"{"b":1,"missing":null,"a,b":3,"old":null,"a":2}"#
+    );
+    assert_eq!(server.metrics.snapshot().misses, 2);
+
+    assert_eq!(get("/mget?keys=").json(), serde_json::json!({}));
+}
+
+#[test]
+fn persist_takes_a_keys_expiry_away() {
+    let server = server(ServerConfig::default());
+    let get = |target: &str| send(&server, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+    assert_eq!(get("/set?a=1&ttl=60").status, 200);
+    assert!(lock(&server.storage).data["a"].expires.is_some());
+
+    let reply = get("/persist?key=a");
+    assert_eq!(reply.json(), serde_json::json!({ "removed_ttl": true }));
+
+    // however far off, the key is still there
+    assert!(!lock(&server.storage).data["a"].is_expired(u64::MAX));
+    assert_eq!(get("/get?key=a").json(), Value::from(1));
+
+    let reply = get("/persist?key=a");
+    assert_eq!(reply.json(), serde_json::json!({ "removed_ttl": false }));
+    assert_eq!(get("/persist?key=missing").status, 404);
+}
+
+#[test]
+fn ready_only_once_the_store_has_loaded_and_warmed_up() {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("the listener binds");
+    let addr = listener.local_addr().expect("the listener has an address");
+    let (config, loaded) = (ServerConfig::default(), AtomicBool::new(false));
+
+    let ready = || {
+        let mut stream = TcpStream::connect(addr).expect("the client connects");
+        write!(stream, "GET /ready HTTP/1.1\r\n\r\n").expect("the request is sent");
+
+        let mut output = Vec::new();
+        stream.read_to_end(&mut output).expect("the server responds");
+        replies(&output).remove(0)
+    };
+
+    // the store is still loading for as long as the flag is down
+    thread::scope(|scope| {
+        scope.spawn(|| answer_loading(listener.incoming(), &loaded, &config));
+
+        let reply = ready();
+        assert_eq!(reply.status, 503);
+        assert_eq!(reply.json()["error"], "loading");
+
+        // the connection that wakes the responder to see the flag is the
+        // last one it answers
+        loaded.store(true, Ordering::SeqCst);
+        assert_eq!(ready().status, 503);
+    });
+
+    let ready = |server: &Server| send(server, "GET /ready HTTP/1.1\r\n\r\n");
+    let mut config = ServerConfig::default();
+    config.slow_start = Some(Duration::from_secs(60));
+    assert_eq!(ready(&server(config)).json()["error"], "warming_up");
+
+    let reply = ready(&server(ServerConfig::default()));
+    assert_eq!(reply.status, 200);
+    assert_eq!(reply.json(), serde_json::json!({ "status": "ready" }));
+}
+
+#[test]
+fn cas_swaps_only_the_value_it_expects() {
+    let server = server(ServerConfig::default());
+    let get = |target: &str| send(&server, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+    assert_eq!(get("/set?a=1").status, 200);
+
+    assert_eq!(get("/cas?key=a&expected=1&new=2").status, 200);
+    assert_eq!(get("/get?key=a").json(), Value::from(2));
+
+    assert_eq!(get("/cas?key=a&expected=1&new=3").status, 409);
+    assert_eq!(get("/get?key=a").json(), Value::from(2));
+
+    // a number kept as a string still matches its plain form
+    assert_eq!(get("/set?s=%221%22").status, 200);
+    assert_eq!(get("/cas?key=s&expected=1&new=one").status, 200);
+
+    // an empty expected value sets only if the key is absent
+    assert_eq!(get("/cas?key=b&expected=&new=first").status, 200);
+    assert_eq!(get("/cas?key=b&expected=&new=second").status, 409);
+    assert_eq!(get("/get?key=b").json(), Value::from("first"));
+    assert_eq!(get("/cas?key=c&expected=1&new=2").status, 409);
+}
+
+#[test]
+fn values_are_capped_at_a_mebibyte_by_default() {
+    let server = server(ServerConfig::default());
+    let post = |key: &str, len: usize| {
+        let head = format!("POST /set?key={} HTTP/1.1\r\nContent-Length: {}\r\n\r\n", key, len);
+        send(&server, &(head + &"a".repeat(len)))
+    };
+
+    assert_eq!(post("fits", 1024 * 1024).status, 200);
+    assert!(lock(&server.storage).data.contains_key("fits"));
+
+    assert_eq!(post("over", 1024 * 1024 + 1).status, 413);
+    assert!(!lock(&server.storage).data.contains_key("over"));
+}
+
+#[test]
+fn the_least_recently_used_key_is_evicted_first() {
+    let mut config = ServerConfig::default();
+    config.max_entries = Some(3);
+
+    let server = server(config);
+    let get = |target: &str| send(&server, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+
+    for set in ["/set?a=1", "/set?b=2", "/set?c=3"] {
+        assert_eq!(get(set).status, 200);
+    }
+
+    // reading a puts it back in use, leaving b the oldest
+    assert_eq!(get("/get?key=a").status, 200);
+    assert_eq!(get("/set?d=4").status, 200);
+
+    let live = || get("/keys").json();
+    assert_eq!(live(), serde_json::json!(["a", "c", "d"]));
+    assert_eq!(server.metrics.snapshot().evictions, 1);
+
+    // overwriting a key uses it too, and adds nothing to evict for
+    assert_eq!(get("/set?c=5").status, 200);
+    assert_eq!(get("/set?e=6").status, 200);
+    assert_eq!(live(), serde_json::json!(["c", "d", "e"]));
+    assert_eq!(server.metrics.snapshot().evictions, 2);
+}
+
+#[test]
+fn the_key_count_ceiling_refuses_new_keys_only() {
+    let mut config = ServerConfig::default();
+    config.store_limit_keys = Some(2);
+
+    let server = server(config);
+    let get = |target: &str| send(&server, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+    assert_eq!(get("/set?a=1").status, 200);
+    assert_eq!(get("/set?b=2").status, 200);
+
+    let reply = get("/set?c=3");
+    assert_eq!(reply.status, 507);
+    assert_eq!(reply.json(), serde_json::json!({ "error": "store_full", "limit": "keys" }));
+
+    // a key that's already there can still be written
+    assert_eq!(get(&format!("/set?a={}", "x".repeat(1_000))).status, 200);
+}
+
+#[test]
+fn the_byte_ceiling_refuses_writes_that_would_go_over_it() {
+    let one = footprint::entry_size("a", &Value::from("x".repeat(10)));
+
+    let mut config = ServerConfig::default();
+    config.store_limit_bytes = Some(2 * one);
+
+    let server = server(config);
+    let get = |target: &str| send(&server, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+    assert_eq!(get(&format!("/set?a={}", "x".repeat(10))).status, 200);
+    assert_eq!(get(&format!("/set?b={}", "x".repeat(10))).status, 200);
+
+    let reply = get("/set?c=1");
+    assert_eq!(reply.status, 507);
+    assert_eq!(reply.json(), serde_json::json!({ "error": "store_full", "limit": "bytes" }));
+
+    // growing a key counts too, while removing one makes room
+    assert_eq!(get(&format!("/set?a={}", "x".repeat(11))).json()["limit"], "bytes");
+    assert_eq!(get("/delete?key=a").status, 200);
+    assert_eq!(get("/set?c=1").status, 200);
+}
+
+#[test]
+fn stats_count_up_across_operations() {
+    let server = server(ServerConfig::default());
+    let get = |target: &str| send(&server, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+    let stats = || get("/stats").json();
+
+    let before = stats();
+    assert_eq!((before["keys"].as_u64(), before["bytes"].as_u64()), (Some(0), Some(0)));
+
+    assert_eq!(get("/set?a=hello").status, 200);
+    assert_eq!(get("/set?b=2").status, 200);
+    assert_eq!(get("/get?key=a").status, 200);
+    assert_eq!(get("/get?key=missing").status, 404);
+
+    let after = stats();
+    assert_eq!(after["keys"], 2);
+    assert_eq!((after["sets"].as_u64(), after["gets"].as_u64()), (Some(2), Some(2)));
+    assert_eq!(after["misses"], 1);
+    assert!(after["bytes"].as_u64().is_some_and(|bytes| bytes >= "ahellob2".len() as u64));
+
+    // an expired key drops out of the count before anything reaps it
+    expire(&server, "b");
+    assert_eq!(stats()["keys"], 1);
+}
+
+#[test]
+fn slow_requests_are_kept_for_trace_slow() {
+    let mut config = ServerConfig::default();
+    config.slow_request_ms = Some(20);
+    config.slow_request_log = Some(2);
+
+    let server = server(config);
+    let get = |target: &str| send(&server, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+    let slowly = |target: &str| {
+        // received a while ago, as if it had been waiting on the lock
+        let mut stream = Exchange::new(format!("GET {} HTTP/1.1\r\n\r\n", target));
+        let _ = server.serve(&mut stream, Instant::now() - Duration::from_millis(50));
+        assert_eq!(replies(&stream.output)[0].status, 200);
+    };
+
+    slowly("/set?a=1");
+    assert_eq!(get("/get?key=a").status, 200);
+    slowly("/set?b=2");
+    slowly("/get?key=b");
+
+    // only the two most recent slow ones are left, and never the fast one
+    let traced = get("/trace-slow").json();
+    let traced = traced.as_array().expect("a list");
+    let seen: Vec<_> =
+        traced.iter().map(|slow| (slow["operation"].as_str(), slow["key"].as_str())).collect();
+
+    assert_eq!(seen, [(Some("set"), Some("b")), (Some("get"), Some("b"))]);
+    assert!(traced.iter().all(|slow| slow["duration_ms"].as_f64().is_some_and(|ms| ms >= 50.0)));
+}
+
+#[test]
+fn an_export_imports_back_into_a_store() {
+    let mut config = ServerConfig::default();
+    config.basic_auth = Some(BasicAuth {
+        username: String::from("admin"),
+        password: Secret::new("secret"),
+    });
+
+    let admin = |server: &Server, request: &str, body: &str| {
+        let head = format!(
+            "{} HTTP/1.1\r\nAuthorization: Basic YWRtaW46c2VjcmV0\r\nContent-Length: {}\r\n\r\n",
+            request,
+            body.len()
+        );
+        send(server, &(head + body))
+    };
+
+    let source = server(config.clone());
+    assert_eq!(admin(&source, "GET /set?a=1", "").status, 200);
+    assert_eq!(admin(&source, "GET /set?b=two&ttl=60", "").status, 200);
+    let export = admin(&source, "GET /export", "");
+    assert_eq!(export.status, 200);
+    let export = String::from_utf8(export.body).unwrap();
+
+    let target = server(config);
+    assert_eq!(admin(&target, "GET /set?b=old", "").status, 200);
+    assert_eq!(admin(&target, "GET /set?c=3", "").status, 200);
+
+    // a merge overwrites what the export holds and leaves the rest
+    assert_eq!(admin(&target, "POST /import", &export).status, 200);
+    assert_eq!(lock(&target.storage).data.len(), 3);
+    assert_eq!(*lock(&target.storage).data["b"].value, Value::from("two"));
+    assert!(lock(&target.storage).data["b"].expires.is_some());
+
+    assert_eq!(admin(&target, "POST /import?replace=true", &export).status, 200);
+    assert!(!lock(&target.storage).data.contains_key("c"));
+
+    for body in ["[1, 2]", "3"] {
+        let reply = admin(&target, "POST /import", body);
+        assert_eq!(reply.status, 400);
+        assert_eq!(reply.json()["error"], "invalid_import");
+    }
+
+    // neither is open to a client without credentials
+    assert_eq!(send(&target, "GET /export HTTP/1.1\r\n\r\n").status, 401);
+}
+
+#[test]
+fn removing_an_expired_key_frees_its_bytes() {
+    let mut config = ServerConfig::default();
+    config.store_limit_bytes = Some(300);
+
+    let server = server(config);
+    let get = |target: &str| send(&server, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+    let value = "x".repeat(200);
+
+    for remove in ["/getdel?key=a", "/delete?key=a"] {
+        assert_eq!(get(&format!("/set?a={}", value)).status, 200);
+        expire(&server, "a");
+
+        // the client sees it as already gone, but its room is still given
+        // back
+        assert_eq!(get(remove).status, 404);
+        assert_eq!(get(&format!("/set?b={}", value)).status, 200);
+        assert_eq!(get("/delete?key=b").status, 200);
+    }
+}
+
+#[test]
+fn every_change_reaches_the_wal() {
+    let wal = scratch_dir("every_change_reaches_the_wal").join("wal.log");
+    let upstream = MockUpstream::start(&[("fetched", "1"), ("stale", "fresh")]);
+
+    let mut config = ServerConfig::default();
+    config.wal_path = Some(wal.clone());
+    config.upstream = Some(upstream.upstream());
+
+    let server = server(config);
+    let get = |target: &str| send(&server, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+
+    // a read-through and a background refresh both store what they fetch
+    assert_eq!(get("/get?key=fetched").status, 200);
+    assert_eq!(get("/set?stale=old").status, 200);
+    expire(&server, "stale");
+    assert_eq!(get("/get?key=stale&swr=true").status, 200);
+    assert!(eventually(|| lock(&server.storage).refreshing.is_empty()));
+
+    // and reaping an expired key removes it, with the upstream not having
+    // it either
+    assert_eq!(get("/set?reaped=1").status, 200);
+    expire(&server, "reaped");
+    assert_eq!(get("/get?key=reaped").status, 404);
+
+    let mut replayed = HashMap::new();
+    Wal::open(wal, false, &mut replayed).expect("the WAL replays");
+
+    let mut keys: Vec<&str> = replayed.keys().map(String::as_str).collect();
+    keys.sort_unstable();
+
+    assert_eq!(keys, ["fetched", "stale"]);
+    assert_eq!(*replayed["stale"].value, Value::from("fresh"));
+}
+
+#[test]
+fn keys_sent_upstream_are_escaped() {
+    let mock = MockUpstream::start(&[("a b/c\r\nX-Injected: 1", "found")]);
+    let fetched = mock.upstream().fetch("a b/c\r\nX-Injected: 1", DEFAULT_TIMEOUT, 1024);
+
+    // the whole key stays in the one path segment, and the request line
+    // can't be ended early to smuggle in a header
+    assert_eq!(fetched.expect("the fetch succeeds").as_deref(), Some("found"));
+    assert_eq!(
+        *mock.requests.lock().unwrap(),
+        ["GET /a%20b%2Fc%0D%0AX-Injected%3A%201 HTTP/1.0"]
+    );
+}
+
+#[test]
+fn fetched_values_keep_their_types() {
+    let mock = MockUpstream::start(&[("count", "3"), ("stale", "{\"a\":true}"), ("word", "hi")]);
+
+    let mut config = ServerConfig::default();
+    config.upstream = Some(mock.upstream());
+
+    let server = server(config);
+    let get = |target: &str| send(&server, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+
+    assert_eq!(get("/get?key=count").json(), Value::from(3));
+    assert_eq!(get("/get?key=word").json(), Value::from("hi"));
+
+    assert_eq!(get("/set?stale=old").status, 200);
+    expire(&server, "stale");
+    assert_eq!(get("/get?key=stale&swr=true").json(), Value::from("old"));
+    assert!(eventually(|| lock(&server.storage).refreshing.is_empty()));
+    assert_eq!(get("/get?key=stale").json(), serde_json::json!({ "a": true }));
+}
+
+#[test]
+fn a_slow_upstream_holds_up_nobody_else() {
+    // a listener that's never accepted from leaves the fetch waiting for its
+    // answer until it times out
+    let unresponsive = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", unresponsive.local_addr().unwrap());
+
+    let mut config = ServerConfig::default();
+    config.upstream = Some(url.parse().unwrap());
+    config.write_through = Some(WriteThrough::Sync);
+
+    let server = Arc::new(server(config));
+    let waiting = |request: &'static str| {
+        let server = Arc::clone(&server);
+        thread::spawn(move || send(&server, request).status)
+    };
+
+    let read = waiting("GET /get?key=a HTTP/1.1\r\nX-Deadline-Ms: 2000\r\n\r\n");
+    let write = waiting("GET /set?b=1 HTTP/1.1\r\nX-Deadline-Ms: 2000\r\n\r\n");
+
+    // give both time to be waiting on the upstream
+    thread::sleep(Duration::from_millis(200));
+
+    let started = Instant::now();
+    assert_eq!(send(&server, "GET /exists?key=c HTTP/1.1\r\n\r\n").status, 200);
+    assert!(started.elapsed() < Duration::from_secs(1));
+
+    assert_eq!(read.join().unwrap(), 404);
+    assert_eq!(write.join().unwrap(), 502);
+}
+
+/// Asks the server to explain `line`.
+fn explain(server: &Server, line: &str) -> Value {
+    let head = format!("POST /explain HTTP/1.1\r\nContent-Length: {}\r\n\r\n", line.len());
+
+    send(server, &(head + line)).json()
+}
+
+#[test]
+fn explain_knows_every_endpoint() {
+    let server = server(ServerConfig::default());
+    let explained = [
+        ("POST /set?key=a HTTP/1.1", "set"),
+        ("GET /set?a=1 HTTP/1.1", "set"),
+        ("GET /trace HTTP/1.1", "trace"),
+        ("GET /trace-slow HTTP/1.1", "trace_slow"),
+        ("POST /import HTTP/1.1", "import"),
+        ("POST /snapshot/subset?path=s.json&prefix=a HTTP/1.1", "snapshot_subset"),
+        ("POST /explain HTTP/1.1", "explain"),
+    ];
+
+    for (line, handler) in explained {
+        assert_eq!(explain(&server, line)["handler"], handler, "{}", line);
+    }
+
+    let explanation = explain(&server, "POST /set?key=a HTTP/1.1");
+    assert_eq!(explanation["key"], "a");
+    assert!(explanation.get("value").is_none());
+
+    assert!(explain(&server, "GET /nowhere HTTP/1.1")["error"].is_string());
+}
+
+#[test]
+fn import_refuses_what_a_set_would() {
+    let mut config = ServerConfig::default();
+    config.max_key_bytes = Some(8);
+    config.max_value_bytes = Some(10);
+    config.store_limit_keys = Some(2);
+    config.basic_auth = Some(BasicAuth {
+        username: String::from("admin"),
+        password: Secret::new("secret"),
+    });
+
+    let server = server(config);
+    let export = serde_json::json!({
+        "a": 1,
+        "b": "fits",
+        "c": "one more",
+        "d": "x".repeat(11),
+        "much_too_long": 1,
+    })
+    .to_string();
+
+    let head = format!(
+        "{} HTTP/1.1\r\nAuthorization: Basic YWRtaW46c2VjcmV0\r\nContent-Length: {}\r\n\r\n",
+        "POST /import",
+        export.len()
+    );
+    let reply = send(&server, &(head + &export));
+    assert_eq!(reply.status, 200);
+    assert_eq!(
+        reply.json(),
+        serde_json::json!({
+            "imported": 2,
+            "removed": 0,
+            "rejected": { "c": "store_full", "d": "too_large", "much_too_long": "key_too_long" },
+        })
+    );
+
+    let storage = lock(&server.storage);
+    assert_eq!(*storage.data["b"].value, Value::from("fits"));
+    assert!(!storage.data.contains_key("d"));
+}
+
+#[test]
+fn every_key_a_request_names_is_held_to_the_limit() {
+    let mut config = ServerConfig::default();
+    config.max_key_bytes = Some(8);
+    config.basic_auth = Some(BasicAuth {
+        username: String::from("admin"),
+        password: Secret::new("secret"),
+    });
+
+    let server = server(config);
+    let admin = |request: &str, body: &str| {
+        let head = format!(
+            "{} HTTP/1.1\r\nAuthorization: Basic YWRtaW46c2VjcmV0\r\nContent-Length: {}\r\n\r\n",
+            request,
+            body.len()
+        );
+        send(&server, &(head + body))
+    };
+
+    let too_long = [
+        admin("GET /mget?keys=a,much_too_long", ""),
+        // the key itself fits, but not once it's in the target namespace
+        admin("GET /move?key=abcd&from_ns=a&to_ns=longer", ""),
+        admin("POST /ns/much_too_long/flush", ""),
+        admin("POST /snapshot/subset?path=s.json", "[\"much_too_long\"]"),
+        admin("POST /snapshot/subset?path=s.json&prefix=much_too_long", ""),
+    ];
+
+    for reply in too_long {
+        assert_eq!(reply.status, 400);
+        assert_eq!(reply.json()["error"], "key_too_long");
+    }
+
+    assert_eq!(admin("GET /mget?keys=a,b", "").status, 200);
+}
+
+#[test]
+fn incr_counts_up_from_numeric_strings() {
+    let server = server(ServerConfig::default());
+    let get = |target: &str| send(&server, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+
+    // a quoted value is kept as a string, but it still reads as a number
+    assert_eq!(get("/set?count=%2241%22").status, 200);
+    assert_eq!(get("/get?key=count").json(), Value::from("41"));
+
+    assert_eq!(get("/incr?key=count").status, 200);
+    assert_eq!(get("/get?key=count").json(), Value::from(42));
+
+    assert_eq!(get("/set?word=hi").status, 200);
+    assert_eq!(get("/incr?key=word").json()["error"], "not_a_number");
+}
+
+#[test]
+fn rapid_increments_to_a_counter_take_the_lock_once() {
+    let mut config = ServerConfig::default();
+    config.coalesce_window = Some(Duration::from_secs(60));
+
+    let server = server(config);
+    let get = |target: &str| send(&server, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+    let coalesced = || get("/stats").json()["coalesced"].clone();
+
+    for count in 1..=100 {
+        assert_eq!(get("/incr?key=hits").json(), Value::from(count));
+    }
+
+    // a read sees every increment, having the store catch up first, and the
+    // next increment after it goes to the store again
+    assert_eq!(get("/get?key=hits").json(), Value::from(100));
+    assert_eq!(get("/incr?key=hits").json(), Value::from(101));
+    assert_eq!(get("/incr?key=hits").json(), Value::from(102));
+
+    // only the first of the burst and the one after the read took the lock
+    assert_eq!(coalesced(), Value::from(100));
+    assert_eq!(get("/get?key=hits").json(), Value::from(102));
+}
+
+#[test]
+fn stats_count_shared_values_once_when_deduplicated() {
+    let mut config = ServerConfig::default();
+    config.dedup_values = true;
+
+    let server = server(config);
+    let get = |target: &str| send(&server, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+    let value = "x".repeat(100);
+
+    assert_eq!(get(&format!("/set?a={}", value)).status, 200);
+    assert_eq!(get(&format!("/set?b={}", value)).status, 200);
+
+    let stats = get("/stats").json();
+    let one = footprint::entry_size("a", &Value::from(value));
+
+    assert_eq!(stats["bytes"], 2 * one);
+    assert_eq!(stats["deduplicated_bytes"], one + "b".len());
+}
+
+#[test]
+fn a_value_over_the_limit_is_refused_and_not_stored() {
+    let mut config = ServerConfig::default();
+    config.max_value_bytes = Some(10);
+
+    let server = server(config);
+    let get = |target: &str| send(&server, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+
+    assert_eq!(get(&format!("/set?a={}", "x".repeat(11))).status, 413);
+    assert_eq!(get("/exists?key=a").json(), Value::from(false));
+    assert_eq!(get(&format!("/set?a={}", "x".repeat(10))).status, 200);
+}
+
+#[test]
+fn requests_have_room_for_the_largest_value_allowed() {
+    let mut config = ServerConfig::default();
+    config.max_value_bytes = Some(3 * 1024 * 1024);
+
+    let server = server(config);
+    let post = |target: &str, body: &str| {
+        let head = format!("POST {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n", target, body.len());
+        send(&server, &(head + body))
+    };
+    let value = "x".repeat(3 * 1024 * 1024);
+
+    assert_eq!(post("/set?key=big", &value).status, 200);
+    assert_eq!(post("/set?key=bigger", &format!("{}x", value)).status, 413);
+}
+
+#[test]
+fn a_refresh_in_flight_doesnt_keep_the_store_alive() {
+    let unresponsive = TcpListener::bind("127.0.0.1:0").unwrap();
+    let upstream = format!("http://{}", unresponsive.local_addr().unwrap()).parse().unwrap();
+
+    let config = ServerConfig::default();
+    let storage = load_storage(&config, Arc::new(Discard), &Arc::new(Metrics::default())).unwrap();
+    let shared = Arc::new(Mutex::new(storage));
+    let held = Arc::downgrade(&shared);
+
+    revalidate(upstream, String::from("a"), Arc::downgrade(&shared), &config);
+    drop(shared);
+
+    // the fetch is still waiting on the upstream, but the store is gone
+    assert!(held.upgrade().is_none());
+}
+
+#[test]
+fn a_content_length_too_big_to_add_up_is_refused() {
+    let server = server(ServerConfig::default());
+    let reply = send(&server, "POST /set?key=a HTTP/1.1\r\nContent-Length: 18446744073709551615\r\n\r\n");
+
+    assert_eq!(reply.status, 413);
+}
+
+#[test]
+fn each_store_keeps_its_own_count_of_snapshots() {
+    let backend = Arc::new(InMemory::default());
+    let quiet = Arc::new(Mutex::new(empty_store(Arc::clone(&backend) as _, false)));
+    assert!(matches!(handle(&quiet, set("a", "1")), Response::SetSuccess));
+
+    // another store saving a snapshot taken later doesn't make this one's
+    // look out of date
+    let (snapshot, persistence, written) = detach(&quiet);
+    flush_shared(&storage()).unwrap();
+    save(&*persistence, &written, &snapshot).unwrap();
+
+    assert_eq!(*backend.0.lock().unwrap()["a"].value, Value::from(1));
+}
+
+#[test]
+fn a_body_that_isnt_utf8_is_refused() {
+    let server = server(ServerConfig::default());
+    let request = &b"POST /set?key=a HTTP/1.1\r\nContent-Length: 2\r\n\r\n\xff\xfe"[..];
+    let reply = exchange(&server, request).remove(0);
+
+    assert_eq!(reply.status, 400);
+    assert_eq!(reply.json()["error"], "invalid_body");
+    assert!(!lock(&server.storage).data.contains_key("a"));
+}
+
+#[test]
+fn a_path_that_only_starts_like_an_endpoint_matches_none() {
+    let server = server(ServerConfig::default());
+    let unmatched = [
+        "GET /exportfoo",
+        "GET /keysX",
+        "GET /scanner",
+        "GET /tracer",
+        "GET /debug/expiredish",
+        "GET /infer-schemas",
+        "GET /count-by-prefixes",
+        "POST /metrics/resetall",
+        "POST /benchmarks",
+        "POST /imports",
+        "POST /explained",
+    ];
+
+    for request in unmatched {
+        let reply = send(&server, &format!("{} HTTP/1.1\r\n\r\n", request));
+        assert_eq!(reply.status, 400, "{}", request);
+        assert_eq!(reply.json()["message"], "No endpoint matches the request", "{}", request);
+    }
+
+    // the real endpoints still match, with or without a query string
+    let get = |target: &str| send(&server, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+    assert_eq!(get("/keys").status, 200);
+    assert_eq!(get("/keys?prefix=a").status, 200);
+    assert_eq!(get("/scan?count=10").status, 200);
+}
+
+#[test]
+fn a_benchmark_runs_apart_from_the_real_store() {
+    let wal = scratch_dir("a_benchmark_runs_apart_from_the_real_store").join("wal.log");
+
+    let mut config = ServerConfig::default();
+    config.benchmark_enabled = true;
+    config.wal_path = Some(wal.clone());
+    config.max_entries = Some(1);
+
+    let server = server(config);
+    handle_on(&server, set("real", "1"));
+    assert!(matches!(
+        handle_on(&server, Request::Benchmark { ops: 200, keys: 10 }),
+        Response::Json(_)
+    ));
+
+    // none of its keys were logged, and the real key wasn't evicted to
+    // make room for them
+    let mut replayed = HashMap::new();
+    Wal::open(wal, false, &mut replayed).expect("the WAL replays");
+    assert_eq!(replayed.keys().collect::<Vec<_>>(), ["real"]);
+    assert_eq!(lock(&server.storage).data.keys().collect::<Vec<_>>(), ["real"]);
+    assert_eq!(server.metrics.snapshot().evictions, 0);
+}
+
+#[test]
+fn a_fetched_value_over_the_limit_fails_the_fetch() {
+    // past what's allowed for the value and the response's head together
+    let huge = "x".repeat(100 * 1024);
+    let mock = MockUpstream::start(&[("small", "fits"), ("huge", &huge)]);
+
+    let fetch = |key| mock.upstream().fetch(key, DEFAULT_TIMEOUT, 10);
+    assert_eq!(fetch("small").unwrap().as_deref(), Some("fits"));
+    assert!(fetch("huge").is_err());
+
+    let mut config = ServerConfig::default();
+    config.max_value_bytes = Some(10);
+    config.upstream = Some(mock.upstream());
+    config.upstream_bad_gateway = true;
+
+    let server = server(config);
+    let reply = send(&server, "GET /get?key=huge HTTP/1.1\r\n\r\n");
+    assert_eq!(reply.status, 502);
+    assert!(!lock(&server.storage).data.contains_key("huge"));
+}
@@ -0,0 +1,180 @@
+use std::io::prelude::*;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::time::Duration;
+
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+use crate::error::ServerError;
+
+const HTTP_SCHEME: &str = "http://";
+/// What's escaped when a key goes into a path: everything but the
+/// characters URLs never need escaped, so a key can't end the request line
+/// or reach into another segment.
+const PATH_SEGMENT: &AsciiSet =
+    &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+/// How much of a response may go to its status line and headers, on top of
+/// the body it's allowed.
+const RESPONSE_HEAD_BYTES: usize = 64 * 1024;
+
+/// A backend that values are fetched from when they aren't stored locally,
+/// and optionally written through to. The value for `key` lives at
+/// `<base url>/<key>`, with the key percent-encoded: a 200 response's body
+/// is taken as the value, and writes `PUT` the value as the request body.
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    host: String,
+    base_path: String,
+}
+
+impl FromStr for Upstream {
+    type Err = ServerError;
+
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        let rest = url.strip_prefix(HTTP_SCHEME).ok_or_else(|| ServerError::UpstreamError {
+            reason: format!("only {} upstream URLs are supported: {}", HTTP_SCHEME, url),
+        })?;
+
+        let (host, base_path) = match rest.find('/') {
+            Some(idx) => rest.split_at(idx),
+            None => (rest, ""),
+        };
+
+        if host.is_empty() {
+            return Err(ServerError::UpstreamError {
+                reason: format!("upstream URL has no host: {}", url),
+            });
+        }
+
+        Ok(Upstream {
+            host: String::from(host),
+            base_path: String::from(base_path.trim_end_matches('/')),
+        })
+    }
+}
+
+impl Upstream {
+    /// Fetches the value for `key`, giving up after `timeout` or once the
+    /// value turns out to be longer than `max_bytes`. Returns `Ok(None)` when
+    /// the upstream answers that it has no such key.
+    pub fn fetch(
+        &self,
+        key: &str,
+        timeout: Duration,
+        max_bytes: usize,
+    ) -> Result<Option<String>, ServerError> {
+        let (status, body) = self.request("GET", key, None, timeout, max_bytes)?;
+
+        match status {
+            200 => Ok(Some(body)),
+            404 => Ok(None),
+            _ => Err(ServerError::UpstreamError {
+                reason: format!("upstream answered with status {}", status),
+            }),
+        }
+    }
+
+    /// Writes `value` for `key` with a `PUT`, succeeding only when the
+    /// upstream answers with a 2xx status.
+    pub fn store(&self, key: &str, value: &str, timeout: Duration) -> Result<(), ServerError> {
+        // nothing is done with the response's body, so none is read
+        let (status, _) = self.request("PUT", key, Some(value), timeout, 0)?;
+
+        if (200..300).contains(&status) {
+            Ok(())
+        } else {
+            Err(ServerError::UpstreamError {
+                reason: format!("upstream answered with status {}", status),
+            })
+        }
+    }
+
+    /// Sends one HTTP/1.0 request for `key` and returns the response status
+    /// and body, failing if the body could be longer than `max_body`.
+    /// HTTP/1.0 keeps the upstream from sending chunked bodies or holding the
+    /// connection open.
+    fn request(
+        &self,
+        method: &str,
+        key: &str,
+        body: Option<&str>,
+        timeout: Duration,
+        max_body: usize,
+    ) -> Result<(u16, String), ServerError> {
+        let addr = self
+            .host
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| ServerError::UpstreamError {
+                reason: format!("could not resolve {}", self.host),
+            })?;
+
+        let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        let body = body.unwrap_or("");
+        let request = format!(
+            "{} {}/{} HTTP/1.0\r\nHost: {}\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            self.base_path,
+            utf8_percent_encode(key, PATH_SEGMENT),
+            self.host,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes())?;
+
+        // one byte past the limit is enough to tell that it's been passed
+        let limit = max_body.saturating_add(RESPONSE_HEAD_BYTES);
+        let mut response = Vec::new();
+        stream.take(limit as u64 + 1).read_to_end(&mut response)?;
+
+        if response.len() > limit {
+            return Err(ServerError::UpstreamError {
+                reason: format!("upstream response is larger than {} bytes", limit),
+            });
+        }
+
+        let response = String::from_utf8_lossy(&response);
+
+        let status = response
+            .lines()
+            .next()
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| ServerError::UpstreamError {
+                reason: String::from("upstream sent a malformed response"),
+            })?;
+
+        let body = response
+            .split_once("\r\n\r\n")
+            .map(|(_, body)| String::from(body))
+            .unwrap_or_default();
+
+        Ok((status, body))
+    }
+}
+
+/// When a set is acknowledged relative to forwarding it upstream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteThrough {
+    /// Acknowledge only once the upstream has confirmed the write.
+    Sync,
+    /// Acknowledge right away and forward the write in the background.
+    Async,
+}
+
+impl FromStr for WriteThrough {
+    type Err = ServerError;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "sync" => Ok(WriteThrough::Sync),
+            "async" => Ok(WriteThrough::Async),
+            _ => Err(ServerError::UpstreamError {
+                reason: format!("unknown write-through mode: {}", mode),
+            }),
+        }
+    }
+}
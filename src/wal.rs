@@ -0,0 +1,237 @@
+//! The write-ahead log. Every change to the store is appended to it as one
+//! line of JSON, and whatever it holds is replayed over the snapshot at
+//! startup, so a crash only loses what hadn't reached the log yet. Each line
+//! says what a key ended up as rather than how it got there, so replaying a
+//! line the snapshot already includes changes nothing.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, prelude::*};
+use std::path::PathBuf;
+use std::sync::{Mutex, PoisonError};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::Record;
+
+/// One change to the store.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub(crate) enum Op {
+    /// The key now holds this record.
+    Put { key: String, record: Record },
+    /// The key is gone.
+    Remove { key: String },
+}
+
+impl Op {
+    fn apply(self, data: &mut HashMap<String, Record>) {
+        match self {
+            Op::Put { key, record } => {
+                data.insert(key, record);
+            }
+            Op::Remove { key } => {
+                data.remove(&key);
+            }
+        }
+    }
+}
+
+pub(crate) struct Wal {
+    path: PathBuf,
+    /// Sync every line to disk before the change it records is answered.
+    fsync: bool,
+    file: Mutex<Log>,
+}
+
+struct Log {
+    file: File,
+    /// How many bytes have been cut off the front of the log since it was
+    /// opened, so that positions stay meaningful across truncations.
+    dropped: u64,
+    /// The position just past the last line.
+    end: u64,
+}
+
+impl Wal {
+    /// Opens the log at `path`, replaying what it holds over `data`. An
+    /// unfinished last line, as a crash partway through an append leaves, is
+    /// skipped and then cut off, so the next append starts on a line of its
+    /// own. Any other line that can't be parsed is skipped too.
+    pub(crate) fn open(
+        path: PathBuf,
+        fsync: bool,
+        data: &mut HashMap<String, Record>,
+    ) -> io::Result<Wal> {
+        let logged = match fs::read(&path) {
+            Ok(logged) => logged,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+
+        let complete = logged.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+        let mut replayed = 0;
+
+        if complete < logged.len() {
+            warn!("Skipping unfinished last line of {}", path.display());
+        }
+
+        for (i, line) in logged[..complete].split(|&b| b == b'\n').enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+
+            match serde_json::from_slice::<Op>(line) {
+                Ok(op) => {
+                    op.apply(data);
+                    replayed += 1;
+                }
+                Err(err) => warn!("Skipping unreadable line {} of {}: {}", i + 1, path.display(), err),
+            }
+        }
+
+        if replayed > 0 {
+            info!("Replayed {} changes from {}", replayed, path.display());
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        file.set_len(complete as u64)?;
+
+        Ok(Wal {
+            path,
+            fsync,
+            file: Mutex::new(Log {
+                file,
+                dropped: 0,
+                end: complete as u64,
+            }),
+        })
+    }
+
+    /// Adds a change to the end of the log.
+    pub(crate) fn append(&self, op: &Op) -> io::Result<()> {
+        let mut line = serde_json::to_vec(op)?;
+        line.push(b'\n');
+
+        let mut log = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        log.file.write_all(&line)?;
+
+        if self.fsync {
+            log.file.sync_data()?;
+        }
+
+        log.end += line.len() as u64;
+        Ok(())
+    }
+
+    /// The position just past the last change logged so far.
+    pub(crate) fn position(&self) -> u64 {
+        let log = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        log.dropped + log.end
+    }
+
+    /// Drops every change before `position`, once a snapshot holding them
+    /// is safely saved. Changes logged since are kept.
+    pub(crate) fn truncate(&self, position: u64) -> io::Result<()> {
+        let mut log = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if position <= log.dropped {
+            return Ok(());
+        }
+
+        let cut = (position - log.dropped) as usize;
+        let mut logged = fs::read(&self.path)?;
+
+        // something other than this log has cut the file short, so what's
+        // left can't be told apart from what the snapshot already holds
+        if logged.len() < cut {
+            let message = format!("{} is shorter than expected", self.path.display());
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, message));
+        }
+
+        let rest = logged.split_off(cut);
+
+        // the kept changes are written out to the side and renamed over the
+        // log, so a crash here leaves one log or the other intact
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut file = File::create(&tmp)?;
+
+        file.write_all(&rest)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+
+        log.file = OpenOptions::new().append(true).open(&self.path)?;
+        log.dropped = position;
+        log.end = rest.len() as u64;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::tests::scratch_dir;
+
+    fn put(key: &str, value: i64) -> Op {
+        Op::Put { key: String::from(key), record: Record::new(Value::from(value)) }
+    }
+
+    #[test]
+    fn replaying_the_log_twice_changes_nothing_more() {
+        let path = scratch_dir("replaying_the_log_twice_changes_nothing_more").join("wal.log");
+        let wal = Wal::open(path.clone(), false, &mut HashMap::new()).unwrap();
+
+        wal.append(&put("a", 1)).unwrap();
+        wal.append(&put("b", 2)).unwrap();
+        wal.append(&Op::Remove { key: String::from("a") }).unwrap();
+        wal.append(&put("b", 3)).unwrap();
+        drop(wal);
+
+        let mut data = HashMap::new();
+        Wal::open(path.clone(), false, &mut data).unwrap();
+        Wal::open(path, false, &mut data).unwrap();
+
+        assert_eq!(data.len(), 1);
+        assert_eq!(*data["b"].value, Value::from(3));
+    }
+
+    #[test]
+    fn an_unfinished_last_line_is_skipped_and_cut_off() {
+        let path = scratch_dir("an_unfinished_last_line_is_skipped_and_cut_off").join("wal.log");
+        let wal = Wal::open(path.clone(), false, &mut HashMap::new()).unwrap();
+
+        wal.append(&put("a", 1)).unwrap();
+        drop(wal);
+
+        let complete = fs::read(&path).unwrap();
+        fs::write(&path, [&complete[..], &br#"{"op":"put","key":"b""#[..]].concat()).unwrap();
+
+        let mut data = HashMap::new();
+        let wal = Wal::open(path.clone(), false, &mut data).unwrap();
+        assert_eq!(data.keys().collect::<Vec<_>>(), ["a"]);
+        assert_eq!(fs::read(&path).unwrap(), complete);
+
+        // the next change starts on a line of its own
+        wal.append(&put("c", 3)).unwrap();
+        let mut data = HashMap::new();
+        Wal::open(path, false, &mut data).unwrap();
+        assert!(data.contains_key("a") && data.contains_key("c"));
+    }
+
+    #[test]
+    fn a_log_cut_short_elsewhere_fails_to_truncate() {
+        let path = scratch_dir("a_log_cut_short_elsewhere_fails_to_truncate").join("wal.log");
+        let wal = Wal::open(path.clone(), false, &mut HashMap::new()).unwrap();
+
+        wal.append(&put("a", 1)).unwrap();
+        fs::write(&path, "").unwrap();
+
+        let err = wal.truncate(wal.position()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}